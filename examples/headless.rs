@@ -0,0 +1,30 @@
+/* Runs a rom without a display and prints what it writes to the serial
+ * port, the way test roms report their results. Nothing here touches SDL.
+ *
+ *   cargo run --example headless -- <game rom> [seconds]
+ */
+use gameboy_emulator_v2::Gameboy;
+use gameboy_emulator_v2::cartridge::Cartridge;
+use gameboy_emulator_v2::rom::BootRom;
+
+/* The DMG cpu clock */
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+
+    let path = match args.next() {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: headless <game rom> [seconds]");
+            std::process::exit(2);
+        },
+    };
+    let seconds: u64 = args.next().map(|s| s.parse().unwrap()).unwrap_or(60);
+
+    let cartridge = Cartridge::read(&path)?;
+    let mut gameboy = Gameboy::from_roms(BootRom::zero(), cartridge, true);
+
+    print!("{}", gameboy.run_headless(seconds * CYCLES_PER_SECOND));
+    Ok(())
+}
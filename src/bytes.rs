@@ -28,20 +28,18 @@ pub fn set_bit(input: u8, n: u8, b: bool) -> u8 {
     }
 }
 
+/* Adds a signed byte to a 16 bit value, as used by JR, ADD SP,n and
+ * LD HL,SP+n. The carry and half carry flags come from adding the byte,
+ * unsigned, to the low byte of a, regardless of the sign.
+ */
 pub fn add_unsigned_signed(a: u16, b: u8) -> (u16, bool, bool) {
     let al = (a & 0x00FF) as u8;
 
-    let bi: i8 = b as i8;
+    let v = a.wrapping_add(b as i8 as u16);
+    let (_, carry) = al.overflowing_add(b);
+    let hc = check_half_carry8(al, b);
 
-    if bi >= 0 {
-        let (v, overflow) = a.overflowing_add(bi as u16);
-        let hc = check_half_carry8(al, b);
-        (v, overflow, hc)
-    } else {
-        let (v, overflow) = a.overflowing_sub((-bi) as u16);
-        let hc = check_half_carry_sub8(al, b);
-        (v, overflow, hc)
-    }
+    (v, carry, hc)
 }
 
 pub fn check_half_carry16(a:u16, b:u16) -> bool {
@@ -96,13 +94,13 @@ mod tests {
         // positive addition
         assert_eq!(add_unsigned_signed(0x0032, 0x0D), (0x003F, false, false));
         assert_eq!(add_unsigned_signed(0xFFF8, 0x13), (0x000B, true, false));
-        assert_eq!(add_unsigned_signed(0x01FF, 0x13), (0x0212, false, true));
+        assert_eq!(add_unsigned_signed(0x01FF, 0x13), (0x0212, true, true));
 
-        // negative addition
-        // assert_eq!(add_unsigned_signed(0x0032, 0xFD), (0x0023, false, true));
-        // assert_eq!(add_unsigned_signed(0x0002, 0xFD), (0xFFF3, true, true));
+        // negative addition, flags come from the unsigned low byte add
+        assert_eq!(add_unsigned_signed(0x0032, 0xFD), (0x002F, true, false));
+        assert_eq!(add_unsigned_signed(0x0002, 0xFD), (0xFFFF, false, false));
 
-        assert_eq!(add_unsigned_signed(0x000C, 0xFB), (0x0007, false, false));
+        assert_eq!(add_unsigned_signed(0x000C, 0xFB), (0x0007, true, true));
     }
 
     #[test]
@@ -1,12 +1,14 @@
 use std::io::Error;
 use std::io::Read;
-use std::io::BufReader;
 
+use crate::bytes;
 use crate::device::Device;
-use crate::device::ram::Ram8k;
+use crate::mbc::{Mbc, MBC2_RAM_SIZE};
+use crate::state::{SaveState, StateReader, StateWriter};
 
 use std::path::Path;
 use std::io::ErrorKind;
+use std::fs;
 use std::fs::File;
 
 use std::io::Seek;
@@ -14,8 +16,9 @@ use std::io::SeekFrom;
 
 pub struct Cartridge {
     storage: Vec<u8>,
-    pub ram: Ram8k,
+    pub ram: Vec<u8>,
     header: Header,
+    mbc: Mbc,
 }
 
 impl Cartridge {
@@ -31,55 +34,110 @@ impl Cartridge {
 
         file.seek(SeekFrom::Start(0x0000))?;
 
-        let mut reader = BufReader::with_capacity(header.capacity(), file);
-        let mut bytes = Vec::new();
-
-        reader.read_to_end(&mut bytes)?;
+        let mut bytes = Vec::with_capacity(header.capacity());
+        file.read_to_end(&mut bytes)?;
         Ok(Cartridge::new(bytes, header))
     }
 
     pub fn new(bytes: Vec<u8>, header: Header) -> Cartridge {
         Cartridge {
             storage: bytes,
-            ram: Ram8k::new(),
-            header: header
+            ram: vec![0; header.ram_capacity()],
+            mbc: Mbc::new(header.cart_type),
+            header,
+        }
+    }
+
+    /* Whether the cart runs with the CGB registers, see Header::cgb */
+    pub fn cgb(&self) -> bool {
+        self.header.cgb()
+    }
+
+    /* Applies a Game Genie style patch to the rom. Addresses in the
+     * switchable bank region are patched in every bank, the compare value
+     * is what lets a code single out the bank it's meant for.
+     */
+    pub fn patch(&mut self, address: u16, value: u8, compare: Option<u8>) {
+        let offsets: Vec<usize> = if address < 0x4000 {
+            vec![address as usize]
+        } else {
+            (address as usize..self.storage.len()).step_by(0x4000).collect()
+        };
+
+        for offset in offsets {
+            if let Some(byte) = self.storage.get_mut(offset) {
+                if compare.is_none() || compare == Some(*byte) {
+                    *byte = value;
+                }
+            }
         }
     }
 
     pub fn zero() -> Cartridge {
         Cartridge {
             storage: Vec::new(),
-            ram: Ram8k::new(),
+            ram: Vec::new(),
             header: Header::zero(),
+            mbc: Mbc::None,
+        }
+    }
+
+    /* Battery backed carts keep their external ram between sessions. Saves
+     * are a raw dump of the ram, a file of a different size (from another
+     * emulator, or an older version of this one) loads as much as fits.
+     * Both are no-ops for carts without a battery.
+     */
+    pub fn save_ram(&self, path: &Path) -> Result<(), Error> {
+        if !self.header.cart_type.has_battery() {
+            return Ok(());
+        }
+
+        fs::write(path, &self.ram)
+    }
+
+    pub fn load_ram(&mut self, path: &Path) -> Result<(), Error> {
+        if !self.header.cart_type.has_battery() || !path.exists() {
+            return Ok(());
+        }
+
+        let bytes = fs::read(path)?;
+        let len = bytes.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&bytes[..len]);
+        Ok(())
+    }
+
+    /* External ram, address is relative to 0xA000. Reads while the ram is
+     * disabled, or past the end of the ram the header declares, return
+     * 0xFF and writes are dropped.
+     */
+    pub fn get_ram(&self, address: u16) -> u8 {
+        self.mbc.ram_offset(address)
+            .and_then(|offset| self.ram.get(offset))
+            .map(|byte| byte | !self.mbc.ram_mask())
+            .unwrap_or(0xFF)
+    }
+
+    pub fn set_ram(&mut self, address: u16, value: u8) {
+        let mask = self.mbc.ram_mask();
+        if let Some(byte) = self.mbc.ram_offset(address).and_then(|offset| self.ram.get_mut(offset)) {
+            *byte = value & mask;
         }
     }
 }
 
 impl Device for Cartridge {
     fn get(&self, address: u16) -> u8 {
-        self.storage[address as usize]
+        /* Banks past the end of the rom wrap around like the unconnected
+         * address lines on real carts. A rom cut short of a whole bank
+         * reads what an unmapped bus would past its end.
+         */
+        let banks = self.storage.len().div_ceil(0x4000);
+        let offset = self.mbc.rom_offset(address) % (banks.max(1) * 0x4000);
+        self.storage.get(offset).copied().unwrap_or(0xFF)
     }
 
     fn set(&mut self, address: u16, value: u8) {
-        match address {
-            0x0000..=0x1FFF => {
-                if (value & 0x0F) == 0x0A {
-                    println!("Enable RAM!");
-                } else {
-                    println!("Disable RAM!");
-                }
-            },
-            0x2000..=0x3FFF => {
-                println!("RAM Bank Number");
-            }
-            0x4000..=0x5FFF => {
-                println!("ROM/RAM Select");
-            }
-            0x6000..=0x7FFF => {
-                println!("ROM/RAM Select");
-            }
-            _ => panic!("invalid ram write to cartridge: {:X} {:X}", address, value),
-        }
+        self.mbc.write(address, value);
     }
 }
 
@@ -91,6 +149,7 @@ pub struct Header {
     storage: [u8; 0x4F],
     cart_type: CartridgeType,
     rom_size: RomSize,
+    ram_size: RamSize,
     name: String,
 }
 
@@ -98,18 +157,35 @@ impl Header {
     pub fn from_file(file: &mut File) -> Result<Header, Error> {
         let mut bytes = [0;0x4F];
         file.seek(SeekFrom::Start(0x100))?;
-        file.read(&mut bytes)?;
+        file.read_exact(&mut bytes)?;
 
         Ok(Header::new(bytes))
     }
 
+    /* The number of 16KB rom banks the header declares */
+    pub fn banks(&self) -> usize {
+        self.rom_size as usize
+    }
+
+    /* The rom size in bytes the header declares */
     pub fn capacity(&self) -> usize {
-        /* Early tests suggest rom_size isn't reliabl */
-        /* (self.rom_size as usize) * 16000 */
+        self.banks() * 0x4000
+    }
+
+    /* 0x0143, 0x80 for carts that use CGB features and 0xC0 for CGB only
+     * carts. Titles are ASCII so bit 7 is never set otherwise.
+     */
+    pub fn cgb(&self) -> bool {
+        bytes::check_bit(self.storage[0x43], 7)
+    }
+
+    /* The external ram size in bytes. MBC2 carts declare none, they have
+     * 512 half bytes built into the MBC itself.
+     */
+    pub fn ram_capacity(&self) -> usize {
         match self.cart_type {
-            CartridgeType::MCB0 => 32000,
-            CartridgeType::MCB1 => 64000,
-            _ => panic!("invalid cart type: {:?}", self.cart_type),
+            CartridgeType::MCB2 | CartridgeType::MCB2Battery => MBC2_RAM_SIZE,
+            _ => self.ram_size.bytes(),
         }
     }
 
@@ -120,6 +196,7 @@ impl Header {
             storage: bytes,
             cart_type: CartridgeType::from(bytes[0x47]),
             rom_size: RomSize::from(bytes[0x48]),
+            ram_size: RamSize::from(bytes[0x49]),
             name: name,
         }
     }
@@ -165,6 +242,41 @@ impl RomSize {
     }
 }
 
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RamSize {
+    None,
+    S2,
+    S8,
+    S32,
+    S64,
+    S128,
+}
+
+impl RamSize {
+    /* An unknown size is treated as no ram rather than refusing the rom */
+    pub fn from(byte: u8) -> RamSize {
+        match byte {
+            0x01 => RamSize::S2,
+            0x02 => RamSize::S8,
+            0x03 => RamSize::S32,
+            0x04 => RamSize::S128,
+            0x05 => RamSize::S64,
+            _ => RamSize::None,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        match self {
+            RamSize::None => 0,
+            RamSize::S2 => 0x800,
+            RamSize::S8 => 0x2000,
+            RamSize::S32 => 0x8000,
+            RamSize::S64 => 0x10000,
+            RamSize::S128 => 0x20000,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum CartridgeType {
     MCB0                       = 0x00,
@@ -197,6 +309,24 @@ pub enum CartridgeType {
     HuC1RAMBattery             = 0xFF,
 }
 
+impl CartridgeType {
+    pub fn has_battery(&self) -> bool {
+        matches!(self,
+            CartridgeType::MCB1RAMBattery |
+            CartridgeType::MCB2Battery |
+            CartridgeType::ROMRAMBattery |
+            CartridgeType::MMM01RAMBattery |
+            CartridgeType::MCB3TimerBattery |
+            CartridgeType::MCB3TimerRamBattery |
+            CartridgeType::MCB3RAMBattery |
+            CartridgeType::MCB5RAMBattery |
+            CartridgeType::MCB5RumbleRAMBattery |
+            CartridgeType::MCB7SensorRumbleRAMBattery |
+            CartridgeType::HuC1RAMBattery
+        )
+    }
+}
+
 impl std::convert::From<u8> for CartridgeType {
     fn from(byte: u8) -> Self {
         match byte {
@@ -232,3 +362,330 @@ impl std::convert::From<u8> for CartridgeType {
         }
     }
 }
+
+impl SaveState for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.ram);
+        self.mbc.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        r.bytes_into(&mut self.ram)?;
+        self.mbc.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* 128KB MBC1 rom where every byte holds its bank number */
+    fn mbc1_cartridge() -> Cartridge {
+        mbc1_cartridge_with_type(0x01)
+    }
+
+    fn mbc1_cartridge_with_type(cart_type: u8) -> Cartridge {
+        let mut bytes = vec![0; 8 * 0x4000];
+        for (bank, chunk) in bytes.chunks_mut(0x4000).enumerate() {
+            for b in chunk.iter_mut() {
+                *b = bank as u8;
+            }
+        }
+
+        /* 4 banks of 8KB ram */
+        let mut header = [0; 0x4F];
+        header[0x47] = cart_type;
+        header[0x49] = 0x03;
+        Cartridge::new(bytes, Header::new(header))
+    }
+
+    #[test]
+    fn test_mbc1_rom_banks() {
+        let mut cartridge = mbc1_cartridge();
+
+        assert_eq!(cartridge.get(0x0000), 0);
+        assert_eq!(cartridge.get(0x4000), 1);
+
+        cartridge.set(0x2000, 0x05);
+        assert_eq!(cartridge.get(0x4000), 5);
+        assert_eq!(cartridge.get(0x7FFF), 5);
+        assert_eq!(cartridge.get(0x3FFF), 0);
+
+        /* Bank 0 maps to bank 1 */
+        cartridge.set(0x2000, 0x00);
+        assert_eq!(cartridge.get(0x4000), 1);
+
+        /* Banks past the end of the rom wrap */
+        cartridge.set(0x2000, 0x0A);
+        assert_eq!(cartridge.get(0x4000), 2);
+    }
+
+    #[test]
+    fn test_mbc1_ram() {
+        let mut cartridge = mbc1_cartridge();
+
+        /* Disabled by default */
+        cartridge.set_ram(0x0000, 0x42);
+        assert_eq!(cartridge.get_ram(0x0000), 0xFF);
+
+        cartridge.set(0x0000, 0x0A);
+        cartridge.set_ram(0x0000, 0x42);
+        assert_eq!(cartridge.get_ram(0x0000), 0x42);
+
+        /* Ram banking only applies in mode 1 */
+        cartridge.set(0x4000, 0x01);
+        assert_eq!(cartridge.get_ram(0x0000), 0x42);
+
+        cartridge.set(0x6000, 0x01);
+        assert_eq!(cartridge.get_ram(0x0000), 0x00);
+        cartridge.set_ram(0x0000, 0x24);
+
+        cartridge.set(0x4000, 0x00);
+        assert_eq!(cartridge.get_ram(0x0000), 0x42);
+
+        cartridge.set(0x0000, 0x00);
+        assert_eq!(cartridge.get_ram(0x0000), 0xFF);
+    }
+
+    #[test]
+    fn test_mbc2() {
+        let mut bytes = vec![0; 16 * 0x4000];
+        for (bank, chunk) in bytes.chunks_mut(0x4000).enumerate() {
+            chunk[0] = bank as u8;
+        }
+
+        let mut header = [0; 0x4F];
+        header[0x47] = 0x06;
+        let mut cartridge = Cartridge::new(bytes, Header::new(header));
+
+        /* Address bit 8 picks between the rom bank and ram enable */
+        cartridge.set(0x2100, 0x05);
+        assert_eq!(cartridge.get(0x4000), 5);
+        cartridge.set(0x0100, 0x00);
+        assert_eq!(cartridge.get(0x4000), 1);
+
+        cartridge.set_ram(0x0000, 0x42);
+        assert_eq!(cartridge.get_ram(0x0000), 0xFF);
+        cartridge.set(0x0000, 0x0A);
+        assert_eq!(cartridge.get(0x4000), 1);
+
+        /* Only the low nibble is stored, and the 512 bytes repeat */
+        cartridge.set_ram(0x0000, 0x42);
+        assert_eq!(cartridge.get_ram(0x0000), 0xF2);
+        assert_eq!(cartridge.get_ram(0x0200), 0xF2);
+        assert_eq!(cartridge.get_ram(0x1E00), 0xF2);
+
+        cartridge.set_ram(0x1FFF, 0x0C);
+        assert_eq!(cartridge.get_ram(0x01FF), 0xFC);
+    }
+
+    /* 512 banks, the largest MBC5 rom, with each bank starting with its
+     * number little endian
+     */
+    fn mbc5_cartridge() -> Cartridge {
+        let mut bytes = vec![0; 512 * 0x4000];
+        for (bank, chunk) in bytes.chunks_mut(0x4000).enumerate() {
+            chunk[0] = bank as u8;
+            chunk[1] = (bank >> 8) as u8;
+        }
+
+        let mut header = [0; 0x4F];
+        header[0x47] = 0x1B;
+        header[0x49] = 0x03;
+        Cartridge::new(bytes, Header::new(header))
+    }
+
+    fn bank_at(cartridge: &Cartridge, address: u16) -> u16 {
+        cartridge.get(address) as u16 | (cartridge.get(address + 1) as u16) << 8
+    }
+
+    #[test]
+    fn test_mbc5_rom_banks() {
+        let mut cartridge = mbc5_cartridge();
+
+        assert_eq!(bank_at(&cartridge, 0x0000), 0);
+        assert_eq!(bank_at(&cartridge, 0x4000), 1);
+
+        /* No remap, bank 0 can be selected in the upper region */
+        cartridge.set(0x2000, 0x00);
+        assert_eq!(bank_at(&cartridge, 0x4000), 0);
+
+        cartridge.set(0x2000, 0xFF);
+        assert_eq!(bank_at(&cartridge, 0x4000), 255);
+
+        cartridge.set(0x2000, 0x00);
+        cartridge.set(0x3000, 0x01);
+        assert_eq!(bank_at(&cartridge, 0x4000), 256);
+
+        /* The lower region stays on bank 0 */
+        assert_eq!(bank_at(&cartridge, 0x0000), 0);
+    }
+
+    #[test]
+    fn test_mbc5_ram_banks() {
+        let mut cartridge = mbc5_cartridge();
+
+        cartridge.set(0x0000, 0x0A);
+        cartridge.set_ram(0x0000, 0x11);
+
+        cartridge.set(0x4000, 0x02);
+        assert_eq!(cartridge.get_ram(0x0000), 0x00);
+        cartridge.set_ram(0x0000, 0x22);
+
+        cartridge.set(0x4000, 0x00);
+        assert_eq!(cartridge.get_ram(0x0000), 0x11);
+    }
+
+    #[test]
+    fn test_no_mbc() {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x4000] = 0x99;
+
+        /* ROM+RAM with 8KB */
+        let mut header = [0; 0x4F];
+        header[0x47] = 0x08;
+        header[0x49] = 0x02;
+        let mut cartridge = Cartridge::new(bytes.clone(), Header::new(header));
+
+        cartridge.set(0x2000, 0x05);
+        assert_eq!(cartridge.get(0x4000), 0x99);
+
+        cartridge.set_ram(0x0010, 0x42);
+        assert_eq!(cartridge.get_ram(0x0010), 0x42);
+
+        /* Without ram there's nothing to write to */
+        let mut cartridge = Cartridge::new(bytes, Header::zero());
+        cartridge.set_ram(0x0010, 0x42);
+        assert_eq!(cartridge.get_ram(0x0010), 0xFF);
+    }
+
+    #[test]
+    fn test_ram_size() {
+        let sizes = [(0x00, 0), (0x01, 0x800), (0x02, 0x2000), (0x03, 0x8000), (0x04, 0x20000), (0x05, 0x10000), (0x42, 0)];
+
+        for (byte, size) in sizes.iter() {
+            let mut bytes = [0; 0x4F];
+            bytes[0x47] = 0x1B;
+            bytes[0x49] = *byte;
+            let header = Header::new(bytes);
+
+            assert_eq!(header.ram_capacity(), *size, "ram size {:02X}", byte);
+            assert_eq!(Cartridge::new(Vec::new(), header).ram.len(), *size);
+        }
+
+        /* MBC2 has its ram built in whatever the header says */
+        let mut bytes = [0; 0x4F];
+        bytes[0x47] = 0x06;
+        assert_eq!(Header::new(bytes).ram_capacity(), 0x200);
+    }
+
+    #[test]
+    fn test_ram_out_of_range() {
+        /* MBC5 with a single 8KB bank */
+        let mut header = [0; 0x4F];
+        header[0x47] = 0x1B;
+        header[0x49] = 0x02;
+        let mut cartridge = Cartridge::new(vec![0; 0x8000], Header::new(header));
+        cartridge.set(0x0000, 0x0A);
+
+        cartridge.set_ram(0x1FFF, 0x42);
+        assert_eq!(cartridge.get_ram(0x1FFF), 0x42);
+
+        cartridge.set(0x4000, 0x01);
+        cartridge.set_ram(0x0000, 0x24);
+        assert_eq!(cartridge.get_ram(0x0000), 0xFF);
+
+        cartridge.set(0x4000, 0x00);
+        assert_eq!(cartridge.get_ram(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_short_rom() {
+        let cartridge = Cartridge::new(vec![0x42; 0x200], Header::zero());
+
+        assert_eq!(cartridge.get(0x01FF), 0x42);
+        assert_eq!(cartridge.get(0x0200), 0xFF);
+        assert_eq!(cartridge.get(0x7FFF), 0xFF);
+
+        assert_eq!(Cartridge::zero().get(0x0100), 0xFF);
+    }
+
+    #[test]
+    fn test_read_truncated_header() {
+        let path = std::env::temp_dir().join("gbe_test_read_truncated_header.gb");
+        fs::write(&path, vec![0; 0x120]).unwrap();
+
+        let error = Cartridge::read(path.to_str().unwrap()).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_load_ram() {
+        let path = std::env::temp_dir().join("gbe_test_save_load_ram.sav");
+        let _ = fs::remove_file(&path);
+
+        let mut cartridge = mbc1_cartridge_with_type(0x03);
+        cartridge.set(0x0000, 0x0A);
+        cartridge.set_ram(0x0000, 0x12);
+        cartridge.set_ram(0x1FFF, 0x34);
+        cartridge.save_ram(&path).unwrap();
+
+        let mut fresh = mbc1_cartridge_with_type(0x03);
+        fresh.load_ram(&path).unwrap();
+        assert_eq!(fresh.ram, cartridge.ram);
+
+        /* A short save only fills the start of ram */
+        fs::write(&path, [0x56]).unwrap();
+        let mut short = mbc1_cartridge_with_type(0x03);
+        short.load_ram(&path).unwrap();
+        assert_eq!(short.ram[0], 0x56);
+        assert_eq!(short.ram[0x1FFF], 0x00);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_ram_without_battery() {
+        let path = std::env::temp_dir().join("gbe_test_save_ram_without_battery.sav");
+        let _ = fs::remove_file(&path);
+
+        let cartridge = mbc1_cartridge_with_type(0x02);
+        cartridge.save_ram(&path).unwrap();
+        assert!(!path.exists());
+
+        /* Missing saves are fine */
+        let mut battery = mbc1_cartridge_with_type(0x03);
+        battery.load_ram(&path).unwrap();
+    }
+
+    #[test]
+    fn test_header_capacity() {
+        let mut bytes = [0; 0x4F];
+        bytes[0x48] = 0x05;
+        let header = Header::new(bytes);
+
+        assert_eq!(header.banks(), 64);
+        assert_eq!(header.capacity(), 64 * 16384);
+        assert_eq!(Header::zero().capacity(), 0x8000);
+    }
+
+    #[test]
+    fn test_read_whole_rom() {
+        let path = std::env::temp_dir().join("gbe_test_read_whole_rom.gb");
+
+        /* A 1MB MBC5 rom, larger than any fixed buffer */
+        let mut bytes = vec![0; 64 * 0x4000];
+        bytes[0x147] = 0x19;
+        bytes[0x148] = 0x05;
+        bytes[64 * 0x4000 - 1] = 0x42;
+        fs::write(&path, &bytes).unwrap();
+
+        let cartridge = Cartridge::read(path.to_str().unwrap()).unwrap();
+        assert_eq!(cartridge.storage.len(), cartridge.header.capacity());
+        assert_eq!(cartridge.storage[64 * 0x4000 - 1], 0x42);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
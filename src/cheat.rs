@@ -0,0 +1,126 @@
+use std::io::Error;
+use std::io::ErrorKind;
+
+/* Cheat codes
+ *
+ * Game Genie codes patch the cartridge rom, they're written as
+ * ABC-DEF or ABC-DEF-GHI where
+ *
+ *   AB - the new value
+ *   FCDE - the address, with F inverted
+ *   GI - the compare value, rotated right by 2 and xored with 0xBA. The
+ *        patch only applies where the rom holds this value which lets codes
+ *        target a single bank. H is a checksum and ignored.
+ *
+ * Game Shark codes write to ram, they're written as TTVVLLHH where
+ *
+ *   TT - the ram bank (usually 01)
+ *   VV - the value
+ *   HHLL - the address, little endian
+ *
+ * Game Shark writes are reapplied every frame.
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Cheat {
+    GameGenie {
+        address: u16,
+        value: u8,
+        compare: Option<u8>,
+    },
+    GameShark {
+        bank: u8,
+        address: u16,
+        value: u8,
+    },
+}
+
+impl Cheat {
+    pub fn parse(code: &str) -> Result<Cheat, Error> {
+        if code.contains('-') {
+            parse_game_genie(code)
+        } else {
+            parse_game_shark(code)
+        }
+    }
+}
+
+fn invalid(code: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("invalid cheat code: {}", code))
+}
+
+fn hex_digits(code: &str) -> Result<Vec<u8>, Error> {
+    code.chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8).ok_or_else(|| invalid(code)))
+        .collect()
+}
+
+fn parse_game_genie(code: &str) -> Result<Cheat, Error> {
+    let d = hex_digits(code)?;
+
+    if d.len() != 6 && d.len() != 9 {
+        return Err(invalid(code));
+    }
+
+    let value = (d[0] << 4) | d[1];
+    let address = ((d[5] as u16 ^ 0xF) << 12)
+        | ((d[2] as u16) << 8)
+        | ((d[3] as u16) << 4)
+        | d[4] as u16;
+
+    let compare = if d.len() == 9 {
+        Some(((d[6] << 4) | d[8]).rotate_right(2) ^ 0xBA)
+    } else {
+        None
+    };
+
+    Ok(Cheat::GameGenie { address, value, compare })
+}
+
+fn parse_game_shark(code: &str) -> Result<Cheat, Error> {
+    let d = hex_digits(code)?;
+
+    if d.len() != 8 {
+        return Err(invalid(code));
+    }
+
+    let byte = |i: usize| (d[i] << 4) | d[i + 1];
+
+    Ok(Cheat::GameShark {
+        bank: byte(0),
+        value: byte(2),
+        address: ((byte(6) as u16) << 8) | byte(4) as u16,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_game_genie() {
+        assert_eq!(
+            Cheat::parse("00A-17B-C49").unwrap(),
+            Cheat::GameGenie { address: 0x4A17, value: 0x00, compare: Some(0xC8) },
+        );
+
+        assert_eq!(
+            Cheat::parse("3E5-A9F").unwrap(),
+            Cheat::GameGenie { address: 0x05A9, value: 0x3E, compare: None },
+        );
+    }
+
+    #[test]
+    fn test_parse_game_shark() {
+        assert_eq!(
+            Cheat::parse("01FF00C1").unwrap(),
+            Cheat::GameShark { bank: 0x01, address: 0xC100, value: 0xFF },
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Cheat::parse("01FF00").is_err());
+        assert!(Cheat::parse("ZZZ-ZZZ").is_err());
+    }
+}
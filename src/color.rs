@@ -0,0 +1,84 @@
+/* CGB colors
+ *
+ * The CGB stores colors as 15 bit RGB, 5 bits per channel with red in the
+ * low bits. Scaling those straight up to 8 bits per channel looks much more
+ * saturated than the real screen, so we optionally run them through the
+ * usual approximation of the CGB LCD's response where each output channel
+ * bleeds in a little from the others.
+ */
+
+use crate::shade::Shade;
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum ColorCorrection {
+    // Scale each channel independently
+    None,
+
+    // Approximate the colors of the CGB screen
+    Cgb,
+}
+
+pub fn rgb555_to_rgb888(color: u16, correction: ColorCorrection) -> (u8, u8, u8) {
+    let r = (color & 0x1F) as u32;
+    let g = ((color >> 5) & 0x1F) as u32;
+    let b = ((color >> 10) & 0x1F) as u32;
+
+    match correction {
+        ColorCorrection::None => (scale(r), scale(g), scale(b)),
+        ColorCorrection::Cgb => {
+            let cr = r * 26 + g * 4 + b * 2;
+            let cg = g * 24 + b * 8;
+            let cb = r * 6 + g * 4 + b * 22;
+
+            (clamp(cr), clamp(cg), clamp(cb))
+        }
+    }
+}
+
+/* A DMG shade as an RGB555 grey, for anything drawn without a CGB palette */
+pub fn shade_rgb555(shade: Shade) -> u16 {
+    let c = match shade {
+        Shade::White => 0x1F,
+        Shade::LightGrey => 0x15,
+        Shade::DarkGrey => 0x0A,
+        Shade::Black => 0x00,
+    };
+    c | (c << 5) | (c << 10)
+}
+
+/* 5 bits to 8 bits, repeating the top bits so 0x1F maps to 0xFF */
+fn scale(c: u32) -> u8 {
+    ((c << 3) | (c >> 2)) as u8
+}
+
+/* The corrected channels sum to at most 32 * 31, scaled back into 0-240 */
+fn clamp(c: u32) -> u8 {
+    (c.min(960) >> 2) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uncorrected() {
+        assert_eq!(rgb555_to_rgb888(0x001F, ColorCorrection::None), (0xFF, 0x00, 0x00));
+        assert_eq!(rgb555_to_rgb888(0x7FFF, ColorCorrection::None), (0xFF, 0xFF, 0xFF));
+        assert_eq!(rgb555_to_rgb888(0x0000, ColorCorrection::None), (0x00, 0x00, 0x00));
+    }
+
+    #[test]
+    fn test_corrected() {
+        /* Pure red picks up some blue */
+        assert_eq!(rgb555_to_rgb888(0x001F, ColorCorrection::Cgb), (201, 0, 46));
+        assert_eq!(rgb555_to_rgb888(0x7FFF, ColorCorrection::Cgb), (240, 240, 240));
+        assert_eq!(rgb555_to_rgb888(0x0000, ColorCorrection::Cgb), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_shade_greys() {
+        assert_eq!(shade_rgb555(Shade::White), 0x7FFF);
+        assert_eq!(rgb555_to_rgb888(shade_rgb555(Shade::LightGrey), ColorCorrection::None), (0xAD, 0xAD, 0xAD));
+        assert_eq!(shade_rgb555(Shade::Black), 0x0000);
+    }
+}
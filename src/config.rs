@@ -0,0 +1,167 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use crate::shade::Shade;
+use crate::color::ColorCorrection;
+
+/* Configuration file
+ *
+ * One setting per line as `key = value`, blank lines and lines starting
+ * with # are ignored. The display colors for the four shades are given as
+ * hex RGB, for the classic green DMG look
+ *
+ *   white = #E0F8D0
+ *   light_grey = #88C070
+ *   dark_grey = #346856
+ *   black = #081820
+ *
+ * Any shade left out keeps its greyscale color. CGB carts are drawn in
+ * their own colors, `color_correction = on` runs those through the CGB
+ * screen's color curve, it's off by default.
+ */
+pub struct Config {
+    pub colors: ShadeColors,
+    pub color_correction: ColorCorrection,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            colors: ShadeColors::greyscale(),
+            color_correction: ColorCorrection::None,
+        }
+    }
+
+    pub fn read(path: &str) -> Result<Config, Error> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(input: &str) -> Result<Config, Error> {
+        let mut config = Config::new();
+
+        for (n, line) in input.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+                None => return Err(invalid(n, "expected key = value")),
+            };
+
+            if key == "color_correction" {
+                config.color_correction = match value {
+                    "on" => ColorCorrection::Cgb,
+                    "off" => ColorCorrection::None,
+                    _ => return Err(invalid(n, &format!("expected on or off, got {}", value))),
+                };
+                continue;
+            }
+
+            let shade = match key {
+                "white" => Shade::White,
+                "light_grey" => Shade::LightGrey,
+                "dark_grey" => Shade::DarkGrey,
+                "black" => Shade::Black,
+                _ => return Err(invalid(n, &format!("unknown setting {}", key))),
+            };
+
+            let color = parse_color(value).ok_or_else(|| invalid(n, &format!("invalid color {}", value)))?;
+            config.colors.set(shade, color);
+        }
+
+        Ok(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
+
+fn invalid(line: usize, message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("config line {}: {}", line + 1, message))
+}
+
+/* RRGGBB with an optional leading # */
+fn parse_color(value: &str) -> Option<[u8; 4]> {
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 {
+        return None
+    }
+
+    let rgb = u32::from_str_radix(hex, 16).ok()?;
+    Some([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255])
+}
+
+/* The RGBA color the display draws for each shade */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ShadeColors {
+    colors: [[u8; 4]; 4],
+}
+
+impl ShadeColors {
+    pub fn greyscale() -> ShadeColors {
+        ShadeColors {
+            colors: [
+                [255, 255, 255, 255],
+                [211, 211, 211, 255],
+                [169, 169, 169, 255],
+                [0, 0, 0, 255],
+            ],
+        }
+    }
+
+    pub fn set(&mut self, shade: Shade, rgba: [u8; 4]) {
+        self.colors[shade as usize] = rgba;
+    }
+
+    pub fn rgba(&self, shade: Shade) -> [u8; 4] {
+        self.colors[shade as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_colors() {
+        let config = Config::parse("
+            # The classic green
+            white = #E0F8D0
+            light_grey = #88C070
+            dark_grey = 346856
+            black = #081820
+        ").unwrap();
+
+        assert_eq!(config.colors.rgba(Shade::Black), [0x08, 0x18, 0x20, 255]);
+        assert_eq!(config.colors.rgba(Shade::DarkGrey), [0x34, 0x68, 0x56, 255]);
+        assert_eq!(config.colors.rgba(Shade::White), [0xE0, 0xF8, 0xD0, 255]);
+    }
+
+    #[test]
+    fn test_missing_shades_stay_greyscale() {
+        let config = Config::parse("black = #081820").unwrap();
+
+        assert_eq!(config.colors.rgba(Shade::Black), [0x08, 0x18, 0x20, 255]);
+        assert_eq!(config.colors.rgba(Shade::White), ShadeColors::greyscale().rgba(Shade::White));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Config::parse("black = #0818").is_err());
+        assert!(Config::parse("purple = #081820").is_err());
+        assert!(Config::parse("black").is_err());
+        assert!(Config::parse("color_correction = yes").is_err());
+    }
+
+    #[test]
+    fn test_parse_color_correction() {
+        assert_eq!(Config::parse("").unwrap().color_correction, ColorCorrection::None);
+        assert_eq!(Config::parse("color_correction = on").unwrap().color_correction, ColorCorrection::Cgb);
+        assert_eq!(Config::parse("color_correction = off").unwrap().color_correction, ColorCorrection::None);
+    }
+}
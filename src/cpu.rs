@@ -1,20 +1,64 @@
+use std::cell::RefCell;
+use std::io::Error;
+use std::path::Path;
+
 use crate::shade::Shade;
-use crate::msg::{Frame, TileMap};
-use crate::register::{Registers, Registers16, IME, HaltedState};
+use crate::msg::{Frame, TileMap, TileMapView, Button, Snapshot};
+use crate::register::{Registers, Registers8, Registers16, IME, HaltedState};
 use crate::mmu::MMU;
+use crate::cartridge::Cartridge;
+use crate::rom::BootRomVariant;
 use crate::bytes;
-use crate::device::lcd::Mode;
-use crate::device::interrupt::Interrupt;
+use crate::device::lcd::{Mode, LineRegisters};
+use crate::device::interrupt::{Interrupt, PRIORITY};
+use crate::device::oam::SpriteLimit;
 use crate::framebuffer;
+use crate::color;
 use crate::tile::Tile;
 use crate::palette::Palette;
+use crate::pixel::Pixel;
+use crate::trace::{self, TraceHash};
+use crate::dump;
+use crate::mooneye;
+use crate::watcher::RegisterCondition;
+use crate::state::{SaveState, StateReader, StateWriter};
 
-use crate::instruction::{opcode, Instruction, OpResult};
+use crate::instruction::{opcode, disassemble, Instruction, OpResult};
 use crate::instruction::helper::call;
 
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum State {
+    Running,
+
+    // Execution is suspended, entered when a watch point is hit
+    Debug,
+}
+
+/* 154 lines of 456 cycles */
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/* The cycles spent dispatching an interrupt, see CPU::handle_interrupts */
+pub const INTERRUPT_CYCLES: u8 = 20;
+
+/* About a second, a call that hasn't returned by then probably won't */
+pub const STEP_OVER_LIMIT: u64 = CYCLES_PER_FRAME * 60;
+
 pub struct CPUManager {
     instructions: opcode::Fetcher,
     cpu: CPU,
+    trace_hash: Option<TraceHash>,
+    instruction_count: u64,
+
+    /* One Gameboy Doctor line per executed instruction while logging, see
+     * set_logging and trace::format_state
+     */
+    trace: Option<Vec<String>>,
+
+    /* Set when leaving the debug state so the instruction at a break point
+     * can run instead of stopping on it again.
+     */
+    skip_break: bool,
+    tile_map_view: TileMapView,
 }
 
 impl CPUManager {
@@ -22,21 +66,292 @@ impl CPUManager {
         CPUManager {
             instructions: opcode::Fetcher::new(),
             cpu: CPU::new(rs, mmu),
+            trace_hash: None,
+            instruction_count: 0,
+            trace: None,
+            skip_break: false,
+            tile_map_view: TileMapView::new(),
+        }
+    }
+
+    /* Starts as the boot rom for the variant would leave things when it
+     * hands off to the cartridge at 0x0100
+     */
+    pub fn skip_boot(variant: BootRomVariant, cartridge: Cartridge) -> CPUManager {
+        CPUManager::new(Registers::skip_boot(variant), MMU::skip_boot(cartridge, variant))
+    }
+
+    /* Start accumulating a hash of every executed instruction */
+    pub fn enable_trace_hash(&mut self) {
+        self.trace_hash = Some(TraceHash::new());
+    }
+
+    pub fn trace_digest(&self) -> Option<u64> {
+        self.trace_hash.as_ref().map(|t| t.digest())
+    }
+
+    /* Collect log output from the devices and a trace of every executed
+     * instruction, drained with take_log
+     */
+    pub fn set_logging(&mut self, enabled: bool) {
+        self.cpu.mmu.lcd.log = if enabled { Some(Vec::new()) } else { None };
+        self.trace = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /* Collect bytes sent over the serial port, drained with take_serial_output */
+    pub fn capture_serial(&mut self) {
+        self.cpu.mmu.serial.output = Some(Vec::new());
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        match self.cpu.mmu.serial.output.as_mut() {
+            Some(output) => std::mem::take(output),
+            None => Vec::new(),
+        }
+    }
+
+    /* Log VRAM and OAM accesses the PPU would block */
+    pub fn set_conflict_logging(&mut self, enabled: bool) {
+        self.cpu.mmu.conflicts = if enabled { Some(RefCell::new(Vec::new())) } else { None };
+    }
+
+    pub fn take_log(&mut self) -> Vec<String> {
+        let mut log = match self.trace.as_mut() {
+            Some(trace) => std::mem::take(trace),
+            None => Vec::new(),
+        };
+
+        if let Some(lcd_log) = self.cpu.mmu.lcd.log.as_mut() {
+            log.append(lcd_log);
+        }
+
+        if let Some(conflicts) = self.cpu.mmu.conflicts.as_ref() {
+            log.append(&mut conflicts.borrow_mut());
         }
+
+        log
+    }
+
+    pub fn set_sprite_limit(&mut self, limit: SpriteLimit) {
+        self.cpu.sprite_limit = limit;
+    }
+
+    pub fn watch_read(&mut self, address: u16) {
+        self.cpu.mmu.watcher.watch_read(address);
+    }
+
+    pub fn set_break_point(&mut self, pc: u16) {
+        self.cpu.mmu.watcher.set_break_point(pc);
+    }
+
+    pub fn clear_break_point(&mut self, pc: u16) {
+        self.cpu.mmu.watcher.clear_break_point(pc);
+    }
+
+    pub fn set_register_break(&mut self, condition: RegisterCondition) {
+        self.cpu.mmu.watcher.set_register_break(condition);
+    }
+
+    pub fn clear_register_break(&mut self, condition: RegisterCondition) {
+        self.cpu.mmu.watcher.clear_register_break(condition);
+    }
+
+    pub fn pause(&mut self) {
+        self.cpu.state = State::Debug;
+    }
+
+    pub fn resume(&mut self) {
+        self.cpu.state = State::Running;
+        self.skip_break = true;
+    }
+
+    /* Executes a single instruction from the debug state and stays there */
+    pub fn step_debug(&mut self) -> Option<(Mode, Mode)> {
+        self.resume();
+        let transition = self.step();
+        self.cpu.state = State::Debug;
+        transition
+    }
+
+    /* Steps over calls, a CALL or RST runs until it returns to the
+     * instruction after it. A break point inside the call stops it early, as
+     * does a call that hasn't returned after STEP_OVER_LIMIT cycles.
+     */
+    pub fn step_over(&mut self) {
+        let pc = self.pc();
+        let sp = self.cpu.registers.get16(Registers16::SP);
+
+        let return_pc = match self.peek(pc) {
+            0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Some(pc.wrapping_add(3)),
+            opcode if opcode & 0xC7 == 0xC7 => Some(pc.wrapping_add(1)),
+            _ => None,
+        };
+
+        self.step_debug();
+
+        let return_pc = match return_pc {
+            Some(return_pc) => return_pc,
+            None => return,
+        };
+
+        /* Unlike resume the first instruction can be a break point */
+        self.cpu.state = State::Running;
+        let start = self.cpu.cycles;
+
+        /* Recursive calls pass through the return address on a deeper stack */
+        while !(self.pc() == return_pc && self.cpu.registers.get16(Registers16::SP) >= sp) {
+            if self.cpu.state == State::Debug || self.cpu.cycles - start >= STEP_OVER_LIMIT {
+                break
+            }
+            self.step();
+        }
+
+        self.cpu.state = State::Debug;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let r = &self.cpu.registers;
+
+        Snapshot {
+            a: r.get8(Registers8::A),
+            b: r.get8(Registers8::B),
+            c: r.get8(Registers8::C),
+            d: r.get8(Registers8::D),
+            e: r.get8(Registers8::E),
+            f: r.get8(Registers8::F),
+            h: r.get8(Registers8::H),
+            l: r.get8(Registers8::L),
+            sp: r.get16(Registers16::SP),
+            pc: r.get16(Registers16::PC),
+            ime: r.ime.enabled(),
+        }
+    }
+
+    /* Direct memory access for tools outside the core, routed through the
+     * mmu like any other write. Reads don't trip read watches or log
+     * conflicts, see MMU::peek.
+     */
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.mmu.peek(address)
+    }
+
+    /* Whether a mooneye test rom finished and passed, see mooneye.rs */
+    pub fn mooneye_result(&self) -> Option<bool> {
+        mooneye::check_mooneye_result(&self.cpu)
+    }
+
+    /* The instruction at pc as assembly and its length in bytes */
+    pub fn disassemble(&self, pc: u16) -> (String, u16) {
+        disassemble::disassemble(&self.cpu.mmu, pc)
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.cpu.mmu.set(address, value);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.cpu.mmu.joypad.set_button(button, pressed) {
+            self.cpu.mmu.interrupt_flag.set(Interrupt::Joypad, true);
+        }
+    }
+
+    /* Audio samples generated since the last call */
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.mmu.apu.take_samples()
+    }
+
+    pub fn patch_rom(&mut self, address: u16, value: u8, compare: Option<u8>) {
+        self.cpu.mmu.cartridge.patch(address, value, compare);
+    }
+
+    pub fn save_ram(&self, path: &Path) -> Result<(), Error> {
+        self.cpu.mmu.cartridge.save_ram(path)
+    }
+
+    pub fn load_ram(&mut self, path: &Path) -> Result<(), Error> {
+        self.cpu.mmu.cartridge.load_ram(path)
+    }
+
+    pub fn dump_state(&self) -> Vec<u8> {
+        self.cpu.mmu.dump_state()
+    }
+
+    pub fn debug_dump_json(&self) -> String {
+        dump::ppu_json(&self.cpu.mmu)
+    }
+
+    /* The full machine state, see state.rs for the format */
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.cpu.save_state(&mut w);
+        w.u64(self.instruction_count);
+        w.finish()
+    }
+
+    /* The devices load one after another, so a state that turns out to be
+     * bad part way through would leave the machine half replaced. Put back
+     * what was running before if it fails.
+     */
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let previous = self.save_state();
+
+        let result = self.read_state(bytes);
+        if result.is_err() {
+            self.read_state(&previous).expect("restoring the previous state");
+        }
+        result
+    }
+
+    fn read_state(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let mut r = StateReader::new(bytes)?;
+        self.cpu.load_state(&mut r)?;
+        self.instruction_count = r.u64()?;
+        r.finish()
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles
+    }
+
+    /* True once a watch point has stopped the cpu */
+    pub fn debugging(&self) -> bool {
+        self.cpu.state == State::Debug
+    }
+
+    /* The number of instructions executed so far */
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.registers.get16(Registers16::PC)
+    }
+
+    pub fn frame_buffer(&self) -> &framebuffer::Buffer {
+        &self.cpu.buffer
     }
 
     pub fn frame_info(&self) -> Box<Frame> {
         Box::new(Frame {
             main: self.cpu.buffer,
+            colors: if self.cpu.mmu.cartridge.cgb() { Some(self.cpu.colors) } else { None },
             tiles: self.draw_tiles(),
             tile_map: self.draw_tile_map(),
+            speed: None,
         })
     }
 
+    pub fn set_tile_map_view(&mut self, view: TileMapView) {
+        self.tile_map_view = view;
+    }
+
     pub fn draw_tile_map(&self) -> TileMap {
+        let view = self.tile_map_view;
         TileMap {
             palette: self.cpu.mmu.lcd.bg_palette,
-            pixels: self.cpu.mmu.gpu.buffer,
+            view,
+            background: view.map == self.cpu.mmu.lcd.control.display_select,
+            pixels: self.cpu.mmu.gpu.render_map(view.map, view.unsigned_tiles),
             scroll_x: self.cpu.mmu.lcd.scroll_x,
             scroll_y: self.cpu.mmu.lcd.scroll_y,
         }
@@ -46,10 +361,10 @@ impl CPUManager {
         let mut buffer = [[Shade::White;256];96];
 
         // 12 rows of tiles
-        for iy in 0..12 {
+        for iy in 0..TILE_ROWS {
             // read across for 32 tiles per row (256 pixels)
-            for ix in 0..32 {
-                let tile_index = (iy * 32) + ix;
+            for ix in 0..TILE_COLUMNS {
+                let tile_index = (iy * TILE_COLUMNS) + ix;
                 let tile = self.cpu.mmu.gpu.vram.tile_set[tile_index];
                 draw_tile(
                     &mut buffer,
@@ -64,61 +379,187 @@ impl CPUManager {
     }
 
     pub fn next_frame(&mut self) {
+        let start = self.cpu.cycles;
+
         loop {
-            match self.next_instruction() {
-                Some((Mode::VBlank, Mode::OAM)) => {
-                    self.cpu.mmu.interrupt_flag.vblank = true;
-                    break;
-                },
-                Some((Mode::VRAM, Mode::HBlank)) => {
-                    self.cpu.render_line();
-                },
-                Some((Mode::HBlank, Mode::VBlank)) => {
-                    self.cpu.mmu.gpu.update_buffer();
-                }
-                _ => {},
+            if self.cpu.state == State::Debug {
+                break;
+            }
+
+            if let Some((Mode::VBlank, Mode::OAM)) = self.step() {
+                break;
+            }
+
+            /* There are no vblanks while the display is off, the frame (a
+             * blank one) ends after the time it would have taken.
+             */
+            let frame_cycles = CYCLES_PER_FRAME * self.cpu.mmu.speed.multiplier() as u64;
+            if !self.cpu.mmu.lcd.control.display_enabled && self.cpu.cycles - start >= frame_cycles {
+                self.cpu.buffer = framebuffer::new();
+                break;
             }
         }
     }
 
-    pub fn next_instruction(&mut self) -> Option<(Mode, Mode)> {
-        if self.cpu.registers.halted == HaltedState::Halted {
-            if self.cpu.registers.ime.flagged_on() {
-                self.cpu.handle_interrupts();
+    /* Executes the next instruction and handles any resulting lcd mode transition */
+    pub fn step(&mut self) -> Option<(Mode, Mode)> {
+        let transition = self.next_instruction();
+
+        match transition {
+            Some((Mode::VRAM, Mode::HBlank)) => {
+                self.cpu.render_line();
+            },
+            /* The interrupt is requested on entering vblank at line 144,
+             * the frame is handed over when it ends (see next_frame)
+             */
+            Some((Mode::HBlank, Mode::VBlank)) => {
+                self.cpu.mmu.interrupt_flag.set(Interrupt::VBlank, true);
+                let control = self.cpu.mmu.lcd.control;
+                self.cpu.mmu.gpu.update_buffer(control.tile_data, control.display_select);
             }
-            self.cpu.advance_cycles(4);
+            _ => {},
+        }
+
+        transition
+    }
+
+    pub fn next_instruction(&mut self) -> Option<(Mode, Mode)> {
+        if self.cpu.state == State::Debug {
             return None
         }
 
+        /* The LCD keeps running while the cpu is halted, it's usually what
+         * delivers the interrupt that wakes us back up
+         */
+        if self.cpu.registers.halted == HaltedState::Halted {
+            let dispatch = if self.cpu.registers.ime.flagged_on() {
+                self.cpu.handle_interrupts()
+            } else {
+                0
+            };
+            return self.cpu.advance_cycles(4 + dispatch)
+        }
+
         if self.cpu.registers.halted == HaltedState::HaltedNoJump {
             if self.cpu.interrupt_available().is_some() {
                 self.cpu.registers.halted = HaltedState::None;
             }
 
-            self.cpu.advance_cycles(4);
-            return None
+            return self.cpu.advance_cycles(4)
         }
 
-        if self.cpu.registers.ime.enabled () {
-            self.cpu.handle_interrupts();
-        }
+        /* Servicing an interrupt is charged along with the first
+         * instruction of its handler
+         */
+        let dispatch = if self.cpu.registers.ime.enabled() {
+            self.cpu.handle_interrupts()
+        } else {
+            0
+        };
 
+        /* EI only queues IME, interrupts were checked above so the
+         * instruction after EI always runs before one can be serviced, and a
+         * DI there cancels it.
+         */
         if self.cpu.registers.ime.queued() {
             self.cpu.registers.ime = IME::Enabled;
         }
 
+        let pc = self.cpu.registers.get16(Registers16::PC);
+
+        if !std::mem::take(&mut self.skip_break) && self.cpu.mmu.watcher.is_break_point(pc) {
+            println!("Break point hit: {:X}", pc);
+            self.cpu.state = State::Debug;
+            return self.cpu.advance_cycles(dispatch)
+        }
+
+        /* Trace lines show the state before the instruction runs */
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(trace::format_state(&self.cpu));
+        }
+
         let opcode = self.cpu.get_opcode();
+
+        /* The HALT bug, the pc fails to increment past the opcode following
+         * HALT so that byte is read again as the next instruction.
+         */
+        if self.cpu.registers.halted == HaltedState::HaltBug {
+            let next = self.cpu.registers.get16(Registers16::PC).wrapping_sub(1);
+            self.cpu.registers.set16(Registers16::PC, next);
+            self.cpu.registers.halted = HaltedState::None;
+        }
+
+        if let Some(trace_hash) = self.trace_hash.as_mut() {
+            trace_hash.record(pc, opcode);
+        }
+
         let instruction = self.instructions.fetch(opcode).unwrap();
         let result = self.cpu.execute(instruction);
-        self.cpu.advance_cycles(result.cycles)
+        self.instruction_count += 1;
+
+        if let Some(address) = self.cpu.mmu.watcher.take_hit() {
+            println!("Read watch point hit: {:X} by {}", address, result.name);
+            self.cpu.state = State::Debug;
+        }
+
+        let hits = self.cpu.mmu.watcher.check_registers(&self.cpu.registers);
+        for condition in hits.iter() {
+            println!("Register break point hit: {:?} by {}", condition, result.name);
+        }
+        if !hits.is_empty() {
+            self.cpu.state = State::Debug;
+        }
+
+        self.cpu.advance_cycles(result.cycles + dispatch)
     }
 }
 
 pub struct CPU {
     pub mmu: MMU,
     pub registers: Registers,
+    pub state: State,
 
     pub buffer: framebuffer::Buffer,
+
+    /* The screen as CGB colors, only drawn for CGB carts */
+    pub colors: framebuffer::ColorBuffer,
+    pub line_registers: [LineRegisters; 144],
+    pub sprite_limit: SpriteLimit,
+
+    /* Total cycles run since power on */
+    pub cycles: u64,
+}
+
+/* The per line registers are recorded as each line renders so they don't
+ * need saving, the frame buffer does since a state can be loaded mid frame.
+ */
+impl SaveState for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.registers.save_state(w);
+        self.mmu.save_state(w);
+        w.u64(self.cycles);
+
+        let shades: Vec<u8> = self.buffer.iter().flat_map(|row| row.iter().map(|s| *s as u8)).collect();
+        w.bytes(&shades);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.registers.load_state(r)?;
+        self.mmu.load_state(r)?;
+        self.cycles = r.u64()?;
+
+        let mut shades = [0; 144 * 160];
+        r.bytes_into(&mut shades)?;
+        for (i, shade) in shades.iter().enumerate() {
+            self.buffer[i / 160][i % 160] = match shade {
+                1 => Shade::LightGrey,
+                2 => Shade::DarkGrey,
+                3 => Shade::Black,
+                _ => Shade::White,
+            };
+        }
+        Ok(())
+    }
 }
 
 impl CPU {
@@ -130,11 +571,16 @@ impl CPU {
         CPU {
             mmu: mmu,
             registers: registers,
+            state: State::Running,
             buffer: framebuffer::new(),
+            colors: framebuffer::new_colors(),
+            line_registers: [LineRegisters::new(); 144],
+            sprite_limit: SpriteLimit::hardware(),
+            cycles: 0,
         }
     }
 
-    pub fn execute(&mut self, instruction: &Instruction) -> OpResult {
+    pub fn execute<'a>(&mut self, instruction: &'a Instruction) -> OpResult<'a> {
         let args = self.get_arguments(instruction);
         instruction.call(self, args)
     }
@@ -152,16 +598,135 @@ impl CPU {
         /* Where are we in the lcd screen */
         let y = self.mmu.lcd.lines as usize;
 
-        /* y offset tells us which row in the background buffer we're on.*/
-        let bg_y = y + self.mmu.lcd.scroll_y as usize;
+        if !self.mmu.lcd.control.display_enabled {
+            self.buffer[y] = [Shade::White; 160];
+            self.colors[y] = [color::shade_rgb555(Shade::White); 160];
+            return
+        }
+
+        /* Capture the registers as they are at the moment this line is drawn */
+        let registers = self.mmu.lcd.line_registers();
+        self.line_registers[y] = registers;
+
+        /* y offset tells us which row in the background buffer we're on,
+         * the map wraps around at 256 in both directions
+         */
+        let bg_y = (y + registers.scroll_y as usize) & 0xFF;
 
         /* scroll x tells us which column in the background buffer we're on */
-        let bg_x = self.mmu.lcd.scroll_x as usize;
+        let bg_x = registers.scroll_x as usize;
+
+        /* Sprite priority depends on the background color number, not the
+         * final shade, so hang on to those for the line.
+         */
+        let mut bg_line = [Pixel::P0; 160];
+
+        for (x, p) in bg_line.iter_mut().enumerate() {
+            *p = self.mmu.gpu.buffer[bg_y][(bg_x + x) & 0xFF];
+            self.buffer[y][x] = registers.bg_palette.map(*p);
+        }
+
+        let sprite_line = if self.mmu.lcd.control.sprite_enabled {
+            self.render_sprites(y, registers, &bg_line)
+        } else {
+            [false; 160]
+        };
+
+        if self.mmu.cartridge.cgb() {
+            self.render_colors(y, &bg_line, &sprite_line);
+        }
+    }
+
+    /* CGB carts draw the background through the color palettes. Without the
+     * tile attributes in VRAM bank 1 every background tile uses palette 0,
+     * and without the object palettes sprites keep their DMG shade drawn as
+     * a grey.
+     */
+    fn render_colors(&mut self, y: usize, bg_line: &[Pixel; 160], sprite_line: &[bool; 160]) {
+        for (x, c) in self.colors[y].iter_mut().enumerate() {
+            *c = if sprite_line[x] {
+                color::shade_rgb555(self.buffer[y][x])
+            } else {
+                self.mmu.background_palettes.color(0, bg_line[x])
+            };
+        }
+    }
+
+    /* Returns which pixels on the line were drawn in a sprite's colors */
+    fn render_sprites(&mut self, y: usize, registers: LineRegisters, bg_line: &[Pixel; 160]) -> [bool; 160] {
+        let mut sprite_line = [false; 160];
+
+        /* In 8x16 mode sprites are two consecutive tiles */
+        let height = if self.mmu.lcd.control.sprite_size { 16 } else { 8 };
+
+        let mut sprites = self.mmu.oam.line_sprites(y as u8, height as u8, self.sprite_limit);
+
+        /* On the DMG the sprite with the smaller x wins where sprites
+         * overlap, ties go to the sprite earlier in OAM. line_sprites is
+         * already in OAM order so a stable sort by x gives that priority.
+         */
+        sprites.sort_by_key(|i| self.mmu.oam.sprite(*i).x);
+
+        /* Draw in reverse so that the highest priority sprite ends up on top */
+        for i in sprites.iter().rev() {
+            let sprite = self.mmu.oam.sprite(*i);
+
+            let palette = if sprite.palette {
+                registers.object_palette_1
+            } else {
+                registers.object_palette_0
+            };
+
+            let mut row = y + 16 - sprite.y as usize;
+
+            /* Flip the row across the whole sprite so tall sprites swap
+             * their top and bottom tiles too.
+             */
+            if sprite.y_flip {
+                row = height - 1 - row;
+            }
+
+            /* The low bit of the tile index is ignored for tall sprites */
+            let tile_index = if height == 16 {
+                (sprite.tile & 0xFE) as usize + row / 8
+            } else {
+                sprite.tile as usize
+            };
 
-        for x in 0..160 as usize {
-            let p = self.mmu.gpu.buffer[bg_y][bg_x + x as usize];
-            self.buffer[y][x] = self.mmu.lcd.bg_palette.map(p);
+            let tile = self.mmu.gpu.vram.tile_set[tile_index]
+                .flipped(sprite.x_flip, false);
+
+            let row = row % 8;
+
+            for tx in 0..8 {
+                /* the sprite_x is offset by 8 so that sprites can scroll off the left */
+                let x = sprite.x as usize + tx;
+                if !(8..168).contains(&x) {
+                    continue;
+                }
+
+                let p = tile.data[row][tx];
+
+                /* Color 0 is transparent for sprites */
+                if p == Pixel::P0 {
+                    continue;
+                }
+
+                /* A sprite behind the background still wins over lower
+                 * priority sprites, so the background is drawn back over
+                 * anything already on the pixel rather than skipping it.
+                 */
+                let behind = sprite.priority && bg_line[x - 8] != Pixel::P0;
+                sprite_line[x - 8] = !behind;
+                self.buffer[y][x - 8] = if behind {
+                    registers.bg_palette.map(bg_line[x - 8])
+                } else {
+                    palette.map(p)
+                };
+            }
         }
+
+        sprite_line
     }
 
     pub fn get_opcode(&mut self) -> u16 {
@@ -187,86 +752,76 @@ impl CPU {
         let _if = self.mmu.interrupt_flag;
         let _ie = self.mmu.interrupt_enable;
 
-        if _if.vblank && _ie.vblank {
-            Some(Interrupt::VBlank)
-        } else if _if.lcd_stat && _ie.lcd_stat {
-            Some(Interrupt::LCDStat)
-        } else if _if.timer && _ie.timer {
-            Some(Interrupt::Timer)
-        } else if _if.serial && _ie.serial {
-            Some(Interrupt::Serial)
-        } else if _if.joypad && _ie.joypad {
-            Some(Interrupt::Joypad)
-        } else {
-            None
-        }
+        PRIORITY.iter()
+            .find(|i| _if.get(**i) && _ie.get(**i))
+            .copied()
     }
 
-    fn handle_interrupts(&mut self) {
+    /* Dispatching an interrupt takes five machine cycles, two waiting, two
+     * pushing the pc high byte first and one jumping to the handler. Returns
+     * the cycles spent, 0 when nothing was pending.
+     */
+    fn handle_interrupts(&mut self) -> u8 {
         match self.interrupt_available() {
-            Some(Interrupt::VBlank) => {
-                self.mmu.interrupt_flag.vblank = false;
-                self.registers.halted = HaltedState::None;
-                self.registers.ime = IME::Disabled;
-                call(self, 0x40);
-            }
-            Some(Interrupt::LCDStat) => {
-                self.mmu.interrupt_flag.lcd_stat = false;
+            Some(interrupt) => {
+                self.mmu.interrupt_flag.set(interrupt, false);
                 self.registers.halted = HaltedState::None;
                 self.registers.ime = IME::Disabled;
-                call(self, 0x48);
-            }
-            Some(Interrupt::Timer) => {
-                self.mmu.interrupt_flag.timer = false;
-                self.registers.halted = HaltedState::None;
-                self.registers.ime = IME::Disabled;
-                call(self, 0x50);
-            }
-            Some(Interrupt::Serial) => {
-                self.mmu.interrupt_flag.serial = false;
-                self.registers.halted = HaltedState::None;
-                self.registers.ime = IME::Disabled;
-                call(self, 0x58);
-            }
-            Some(Interrupt::Joypad) => {
-                self.mmu.interrupt_flag.joypad = false;
-                self.registers.halted = HaltedState::None;
-                self.registers.ime = IME::Disabled;
-                call(self, 0x60);
-            }
-            None => {}
+                call(self, interrupt.address());
+                INTERRUPT_CYCLES
+            },
+            None => 0,
         }
     }
 
     pub fn advance_cycles(&mut self, cycles: u8) -> Option<(Mode, Mode)> {
+        self.cycles += cycles as u64;
+
+        /* At double speed the LCD and audio see half the cpu's cycles */
+        let lcd_cycles = cycles / self.mmu.speed.multiplier();
+
         if self.mmu.timer.advance_cycles(cycles) {
-            self.mmu.interrupt_flag.timer = true;
+            self.mmu.interrupt_flag.set(Interrupt::Timer, true);
         }
 
-        if self.registers.halted == HaltedState::None {
-            self.mmu.lcd.advance_cycles(cycles)
-        } else {
-            None
+        if self.mmu.serial.advance_cycles(cycles) {
+            self.mmu.interrupt_flag.set(Interrupt::Serial, true);
+        }
+
+        self.mmu.advance_dma(cycles);
+        self.mmu.apu.advance_cycles(lcd_cycles);
+
+        let transition = self.mmu.lcd.advance_cycles(lcd_cycles);
+
+        if self.mmu.lcd.take_stat_interrupt() {
+            self.mmu.interrupt_flag.set(Interrupt::LCDStat, true);
         }
 
+        transition
     }
 
+    /* STOP after preparing a speed switch changes speed and carries on */
     pub fn stop(&mut self) {
+        if self.mmu.speed.switch() {
+            return
+        }
         self.registers.stopped = true;
     }
 
+    /* With IME set the cpu sleeps until an interrupt is serviced, this
+     * includes HALT directly after EI since IME is set before HALT latches.
+     *
+     * With IME clear the cpu wakes without jumping once IE & IF != 0, unless
+     * one is already pending in which case HALT exits immediately and
+     * triggers the HALT bug.
+     */
     pub fn halt(&mut self) {
-        if self.registers.ime.enabled() {
+        if self.registers.ime.flagged_on() {
             self.registers.halted = HaltedState::Halted;
+        } else if self.interrupt_available().is_none() {
+            self.registers.halted = HaltedState::HaltedNoJump;
         } else {
-            let _if = u8::from(self.mmu.interrupt_flag);
-            let _ie = u8::from(self.mmu.interrupt_enable);
-            if (_if & _ie & 0x1F) == 0 {
-                self.registers.halted = HaltedState::HaltedNoJump;
-            } else {
-                self.registers.halted = HaltedState::HaltBug;
-            }
-
+            self.registers.halted = HaltedState::HaltBug;
         }
     }
 
@@ -287,6 +842,12 @@ impl CPU {
     }
 }
 
+/* The tile viewer lays the 384 tiles in vram out in a grid that has to
+ * exactly fill the [[Shade;256];96] buffer in Frame::tiles
+ */
+const TILE_ROWS: usize = 12;
+const TILE_COLUMNS: usize = 32;
+
 fn draw_tile(buffer: &mut [[Shade;256];96], origin_x: usize, origin_y: usize, tile: Tile, palette: Palette) {
     for y in 0..8 as usize {
         for x in 0..8 as usize {
@@ -296,3 +857,801 @@ fn draw_tile(buffer: &mut [[Shade;256];96], origin_x: usize, origin_y: usize, ti
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::BootRom;
+    use crate::device::interrupt::InterruptFlag;
+    use crate::cartridge::Header;
+
+    fn test_manager() -> CPUManager {
+        let mut registers = Registers::new();
+        registers.set16(Registers16::PC, 0xC000);
+        CPUManager::new(registers, MMU::new(BootRom::zero(), Cartridge::zero()))
+    }
+
+    #[test]
+    fn test_read_watch_point() {
+        let mut manager = test_manager();
+
+        /* LD A, (HL) */
+        manager.cpu.mmu.set(0xC000, 0x7E);
+        manager.cpu.registers.set16(Registers16::HL, 0xC100);
+        manager.watch_read(0xC100);
+
+        manager.next_instruction();
+
+        assert_eq!(manager.cpu.state, State::Debug);
+    }
+
+    #[test]
+    fn test_read_watch_point_unwatched() {
+        let mut manager = test_manager();
+
+        manager.cpu.mmu.set(0xC000, 0x7E);
+        manager.cpu.registers.set16(Registers16::HL, 0xC200);
+        manager.watch_read(0xC100);
+
+        manager.next_instruction();
+
+        assert_eq!(manager.cpu.state, State::Running);
+    }
+
+    #[test]
+    fn test_peek_skips_read_watch() {
+        let mut manager = test_manager();
+
+        /* NOP */
+        manager.cpu.mmu.set(0xC000, 0x00);
+        manager.watch_read(0xC100);
+
+        /* Disassembling doesn't trip the watch either */
+        manager.peek(0xC100);
+        manager.disassemble(0xC100);
+        manager.next_instruction();
+
+        assert_eq!(manager.cpu.state, State::Running);
+    }
+
+    #[test]
+    fn test_break_point() {
+        let mut manager = test_manager();
+
+        /* NOP; NOP */
+        manager.cpu.mmu.set(0xC000, 0x00);
+        manager.cpu.mmu.set(0xC001, 0x00);
+        manager.set_break_point(0xC001);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.state, State::Running);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.state, State::Debug);
+        assert_eq!(manager.pc(), 0xC001);
+
+        /* Resuming runs the instruction at the break point */
+        manager.resume();
+        manager.next_instruction();
+        assert_eq!(manager.cpu.state, State::Running);
+        assert_eq!(manager.pc(), 0xC002);
+    }
+
+    #[test]
+    fn test_step_debug() {
+        let mut manager = test_manager();
+
+        /* INC A */
+        manager.cpu.mmu.set(0xC000, 0x3C);
+        manager.cpu.registers.set8(Registers8::A, 0x01);
+        manager.pause();
+
+        manager.step_debug();
+
+        assert_eq!(manager.cpu.state, State::Debug);
+        assert_eq!(manager.snapshot().a, 0x02);
+        assert_eq!(manager.snapshot().pc, 0xC001);
+    }
+
+    fn halt_manager(ime: IME, interrupt_enable: u8, interrupt_flag: u8) -> CPUManager {
+        let mut manager = test_manager();
+
+        /* HALT; NOP */
+        manager.cpu.mmu.set(0xC000, 0x76);
+        manager.cpu.mmu.set(0xC001, 0x00);
+        manager.cpu.registers.set16(Registers16::SP, 0xDFFE);
+        manager.cpu.registers.ime = ime;
+        manager.cpu.mmu.interrupt_enable = InterruptFlag::from(interrupt_enable);
+        manager.cpu.mmu.interrupt_flag = InterruptFlag::from(interrupt_flag);
+        manager
+    }
+
+    #[test]
+    fn test_halt_ime_on_pending_services() {
+        let mut manager = halt_manager(IME::Enabled, 0x01, 0x01);
+
+        /* The interrupt is taken before HALT is ever fetched, and the first
+         * instruction of the handler (a NOP in empty memory) runs in the
+         * same step.
+         */
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::None);
+        assert_eq!(manager.cpu.registers.get16(Registers16::PC), 0x0041);
+        assert_eq!(manager.cpu.mmu.get(0xDFFD), 0xC0);
+        assert_eq!(manager.cpu.mmu.get(0xDFFC), 0x00);
+        assert_eq!(manager.cpu.registers.ime, IME::Disabled);
+        assert!(!manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+    }
+
+    #[test]
+    fn test_halt_ime_on_wakes_and_services() {
+        let mut manager = halt_manager(IME::Enabled, 0x01, 0x00);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::Halted);
+
+        manager.cpu.mmu.interrupt_flag.set(Interrupt::VBlank, true);
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::None);
+        assert_eq!(manager.cpu.registers.get16(Registers16::PC), 0x0040);
+    }
+
+    #[test]
+    fn test_halt_after_ei_services() {
+        let mut manager = halt_manager(IME::Queued, 0x04, 0x04);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::Halted);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.get16(Registers16::PC), 0x0050);
+    }
+
+    #[test]
+    fn test_halt_ime_off_pending_halt_bug() {
+        let mut manager = halt_manager(IME::Disabled, 0x01, 0x01);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::HaltBug);
+    }
+
+    #[test]
+    fn test_halt_bug_repeats_next_opcode() {
+        let mut manager = halt_manager(IME::Disabled, 0x01, 0x01);
+
+        /* HALT; INC A */
+        manager.cpu.mmu.set(0xC001, 0x3C);
+        let a = manager.cpu.registers.get8(Registers8::A);
+
+        manager.next_instruction();
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::None);
+        assert_eq!(manager.cpu.registers.get16(Registers16::PC), 0xC001);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.get16(Registers16::PC), 0xC002);
+        assert_eq!(manager.cpu.registers.get8(Registers8::A), a.wrapping_add(2));
+    }
+
+    #[test]
+    fn test_halt_ime_off_none_pending_wakes_without_jump() {
+        /* An interrupt requested but not enabled doesn't count */
+        let mut manager = halt_manager(IME::Disabled, 0x01, 0x02);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::HaltedNoJump);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::HaltedNoJump);
+
+        manager.cpu.mmu.interrupt_flag.set(Interrupt::VBlank, true);
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.halted, HaltedState::None);
+        assert_eq!(manager.cpu.registers.get16(Registers16::PC), 0xC001);
+        assert!(manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+    }
+
+    #[test]
+    fn test_tile_grid_dimensions() {
+        let manager = test_manager();
+
+        assert_eq!(TILE_ROWS * TILE_COLUMNS, manager.cpu.mmu.gpu.vram.tile_set.len());
+        assert_eq!(TILE_COLUMNS * 8, 256);
+        assert_eq!(TILE_ROWS * 8, 96);
+    }
+
+    #[test]
+    fn test_draw_tiles_last_tile() {
+        let mut manager = test_manager();
+        manager.cpu.mmu.lcd.bg_palette = Palette::from(0xE4);
+
+        let last = manager.cpu.mmu.gpu.vram.tile_set.len() - 1;
+        for row in 0..8 {
+            manager.cpu.mmu.gpu.vram.tile_set[last].set_row(row, 0xFF, 0xFF);
+        }
+
+        /* The bottom right pixel is the furthest draw_tile ever writes */
+        let tiles = manager.draw_tiles();
+        assert_eq!(tiles[95][255], Shade::Black);
+        assert_eq!(tiles[95][247], Shade::White);
+        assert_eq!(tiles[87][255], Shade::White);
+    }
+
+    #[test]
+    fn test_double_speed_switch() {
+        /* LD A, 0x01; LDH (0x4D), A; STOP; JR -2 on a CGB cart */
+        let mut header = [0; 0x4F];
+        header[0x43] = 0x80;
+        let mut manager = rom_manager_with_header(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE], Header::new(header));
+        for _ in 0..3 {
+            manager.step();
+        }
+
+        assert!(!manager.cpu.registers.stopped);
+        assert_eq!(manager.peek(0xFF4D), 0xFE);
+
+        /* Over 10 lines the timer sees twice the cycles the LCD does */
+        manager.poke(0xFF04, 0);
+        while manager.cpu.mmu.lcd.lines < 10 {
+            manager.step();
+        }
+        assert_eq!(manager.peek(0xFF04), (2 * 456 * 10 / 256) as u8);
+    }
+
+    #[test]
+    fn test_no_speed_switch_on_dmg() {
+        /* The same program on a DMG cart stops */
+        let mut manager = rom_manager(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE]);
+        for _ in 0..3 {
+            manager.step();
+        }
+
+        assert!(manager.cpu.registers.stopped);
+        assert_eq!(manager.peek(0xFF4D), 0xFF);
+        assert_eq!(manager.cpu.mmu.speed.multiplier(), 1);
+    }
+
+    #[test]
+    fn test_serial_transfer_interrupt() {
+        let mut manager = test_manager();
+        manager.cpu.mmu.set(0xFF01, 0x41);
+        manager.cpu.mmu.set(0xFF02, 0x81);
+
+        /* The whole transfer takes 4096 cycles, memory is all NOPs */
+        for _ in 0..1023 {
+            manager.next_instruction();
+        }
+        assert!(!manager.cpu.mmu.interrupt_flag.get(Interrupt::Serial));
+
+        manager.next_instruction();
+        assert!(manager.cpu.mmu.interrupt_flag.get(Interrupt::Serial));
+        assert_eq!(manager.cpu.mmu.get(0xFF01), 0xFF);
+    }
+
+    fn pending_vblank_manager(program: &[u8]) -> CPUManager {
+        let mut manager = test_manager();
+        manager.cpu.registers.set16(Registers16::SP, 0xFFFE);
+        manager.cpu.mmu.set(0xFFFF, 0x01);
+        manager.cpu.mmu.interrupt_flag.set(Interrupt::VBlank, true);
+
+        for (i, b) in program.iter().enumerate() {
+            manager.cpu.mmu.set(0xC000 + i as u16, *b);
+        }
+        manager
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_cycles() {
+        let mut manager = pending_vblank_manager(&[]);
+        manager.cpu.registers.ime = IME::Enabled;
+
+        /* The dispatch plus the NOP at the handler */
+        manager.next_instruction();
+        assert_eq!(manager.cpu.cycles, INTERRUPT_CYCLES as u64 + 4);
+        assert_eq!(manager.pc(), 0x0041);
+        assert_eq!(manager.cpu.registers.ime, IME::Disabled);
+        assert!(!manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+
+        /* The return address, high byte first at the higher address */
+        assert_eq!(manager.cpu.registers.get16(Registers16::SP), 0xFFFC);
+        assert_eq!(manager.cpu.mmu.get(0xFFFD), 0xC0);
+        assert_eq!(manager.cpu.mmu.get(0xFFFC), 0x00);
+
+        /* Without anything pending nothing extra is charged */
+        manager.next_instruction();
+        assert_eq!(manager.cpu.cycles, INTERRUPT_CYCLES as u64 + 8);
+    }
+
+    #[test]
+    fn test_ei_delay() {
+        /* EI; NOP; NOP, the interrupt waits for the instruction after EI */
+        let mut manager = pending_vblank_manager(&[0xFB, 0x00, 0x00]);
+
+        manager.next_instruction();
+        assert_eq!(manager.cpu.registers.ime, IME::Queued);
+
+        manager.next_instruction();
+        assert_eq!(manager.pc(), 0xC002);
+        assert_eq!(manager.cpu.registers.ime, IME::Enabled);
+
+        manager.next_instruction();
+        assert_eq!(manager.pc(), 0x0041);
+        assert_eq!(manager.cpu.mmu.get16(0xFFFC), 0xC002);
+    }
+
+    #[test]
+    fn test_ei_di() {
+        /* EI; DI; NOP, DI runs before IME takes effect so nothing is serviced */
+        let mut manager = pending_vblank_manager(&[0xFB, 0xF3, 0x00]);
+
+        for _ in 0..3 {
+            manager.next_instruction();
+        }
+
+        assert_eq!(manager.pc(), 0xC003);
+        assert_eq!(manager.cpu.registers.ime, IME::Disabled);
+        assert!(manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+    }
+
+    #[test]
+    fn test_ly_write_raises_stat() {
+        let mut manager = test_manager();
+        manager.cpu.mmu.lcd.lines = 10;
+        manager.cpu.mmu.set(0xFF41, 0x40);
+
+        /* LD (0xFF44), A */
+        manager.cpu.mmu.set(0xC000, 0xEA);
+        manager.cpu.mmu.set(0xC001, 0x44);
+        manager.cpu.mmu.set(0xC002, 0xFF);
+
+        manager.next_instruction();
+
+        assert_eq!(manager.cpu.mmu.get(0xFF44), 0);
+        assert!(manager.cpu.mmu.interrupt_flag.get(Interrupt::LCDStat));
+    }
+
+    #[test]
+    fn test_vblank_requested_at_line_144() {
+        let mut manager = rom_manager(&[0x18, 0xFE]);
+        manager.cpu.mmu.interrupt_flag.set(Interrupt::VBlank, false);
+
+        while manager.cpu.mmu.lcd.lines < 143 {
+            manager.step();
+            assert!(!manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+        }
+
+        while manager.step() != Some((Mode::HBlank, Mode::VBlank)) {
+            assert!(!manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+        }
+
+        assert_eq!(manager.cpu.mmu.lcd.lines, 144);
+        assert!(manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+
+        /* Not requested again as vblank ends */
+        manager.cpu.mmu.interrupt_flag.set(Interrupt::VBlank, false);
+        while manager.step() != Some((Mode::VBlank, Mode::OAM)) {}
+        assert!(!manager.cpu.mmu.interrupt_flag.get(Interrupt::VBlank));
+    }
+
+    #[test]
+    fn test_skip_boot_state() {
+        let manager = CPUManager::skip_boot(BootRomVariant::DMG, Cartridge::zero());
+        let snapshot = manager.snapshot();
+
+        /* The DMG post boot state from the pan docs power up sequence */
+        assert_eq!((snapshot.a, snapshot.f), (0x01, 0xB0));
+        assert_eq!((snapshot.b, snapshot.c), (0x00, 0x13));
+        assert_eq!((snapshot.d, snapshot.e), (0x00, 0xD8));
+        assert_eq!((snapshot.h, snapshot.l), (0x01, 0x4D));
+        assert_eq!((snapshot.sp, snapshot.pc), (0xFFFE, 0x0100));
+
+        let io = [
+            (0xFF04, 0xAB), (0xFF05, 0x00), (0xFF06, 0x00), (0xFF0F, 0xE1),
+            (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF41, 0x85), (0xFF47, 0xFC),
+            (0xFFFF, 0x00),
+        ];
+        for (address, value) in io.iter() {
+            assert_eq!(manager.cpu.mmu.get(*address), *value, "{:04X}", address);
+        }
+
+        assert_eq!(manager.cpu.mmu.lcd.lines, 153);
+        assert_eq!(manager.cpu.mmu.lcd.status.mode, Mode::VBlank);
+
+        let dmg0 = CPUManager::skip_boot(BootRomVariant::DMG0, Cartridge::zero());
+        assert_eq!(dmg0.cpu.mmu.get(0xFF04), 0x18);
+        assert_eq!(dmg0.snapshot().b, 0xFF);
+    }
+
+    fn rom_manager(program: &[u8]) -> CPUManager {
+        rom_manager_with_header(program, Header::zero())
+    }
+
+    fn rom_manager_with_header(program: &[u8], header: Header) -> CPUManager {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0100..0x0100 + program.len()].copy_from_slice(program);
+
+        let cartridge = Cartridge::new(bytes, header);
+        let mut manager = CPUManager::skip_boot(BootRomVariant::DMG, cartridge);
+
+        /* skip_boot hands off at the end of vblank, start from the top of the first frame */
+        while manager.step() != Some((Mode::VBlank, Mode::OAM)) {}
+
+        manager
+    }
+
+    fn run_trace(program: &[u8], n: usize) -> u64 {
+        let mut manager = rom_manager(program);
+        manager.enable_trace_hash();
+        for _ in 0..n {
+            manager.next_instruction();
+        }
+        manager.trace_digest().unwrap()
+    }
+
+    #[test]
+    fn test_trace_hash_deterministic() {
+        /* INC A; SWAP A; JR -4 */
+        let program = [0x3C, 0xCB, 0x37, 0x18, 0xFB];
+
+        assert_eq!(run_trace(&program, 1000), run_trace(&program, 1000));
+        assert_ne!(run_trace(&program, 1000), run_trace(&program, 999));
+    }
+
+    #[test]
+    fn test_instruction_trace() {
+        /* LD A, 0x42; INC A; NOP */
+        let mut manager = rom_manager(&[0x3E, 0x42, 0x3C, 0x00]);
+        manager.cpu.registers.set16(Registers16::PC, 0x0100);
+        manager.cpu.registers.set16(Registers16::AF, 0x01B0);
+        manager.set_logging(true);
+
+        for _ in 0..3 {
+            manager.next_instruction();
+        }
+
+        assert_eq!(manager.take_log(), vec![
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:3E,42,3C,00",
+            "A:42 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:3C,00,00,00",
+            "A:43 F:10 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:00,00,00,00",
+        ]);
+        assert!(manager.take_log().is_empty());
+    }
+
+    #[test]
+    fn test_line_registers_scroll_y() {
+        let mut manager = rom_manager(&[]);
+
+        while manager.cpu.mmu.lcd.lines < 80 {
+            manager.step();
+        }
+
+        manager.cpu.mmu.set(0xFF42, 0x10);
+
+        while manager.step() != Some((Mode::VBlank, Mode::OAM)) {}
+
+        for y in 0..80 {
+            assert_eq!(manager.cpu.line_registers[y].scroll_y, 0x00);
+        }
+
+        for y in 80..144 {
+            assert_eq!(manager.cpu.line_registers[y].scroll_y, 0x10);
+        }
+    }
+
+    /* Places 11 solid sprites side by side on the first line */
+    fn sprite_line_manager() -> CPUManager {
+        let mut manager = rom_manager(&[]);
+
+        /* Enable the display, background, and sprites */
+        manager.cpu.mmu.set(0xFF40, 0x93);
+        manager.cpu.mmu.set(0xFF48, 0xE4);
+
+        /* Tile 1 is solid color 3 */
+        for i in 0..16 {
+            manager.cpu.mmu.set(0x8010 + i, 0xFF);
+        }
+
+        for i in 0..11 {
+            let address = 0xFE00 + (i * 4);
+            manager.cpu.mmu.set(address, 16);
+            manager.cpu.mmu.set(address + 1, 8 + (i as u8 * 8));
+            manager.cpu.mmu.set(address + 2, 1);
+            manager.cpu.mmu.set(address + 3, 0);
+        }
+
+        manager
+    }
+
+    fn drawn_sprites(manager: &CPUManager) -> usize {
+        (0..11)
+            .filter(|i| manager.cpu.buffer[0][i * 8] == Shade::Black)
+            .count()
+    }
+
+    #[test]
+    fn test_sprite_limit_hardware() {
+        let mut manager = sprite_line_manager();
+        manager.cpu.render_line();
+
+        assert_eq!(drawn_sprites(&manager), 10);
+        assert_eq!(manager.cpu.buffer[0][80], Shade::White);
+    }
+
+    #[test]
+    fn test_sprite_limit_selects_by_oam_order() {
+        let mut manager = sprite_line_manager();
+
+        /* 12 sprites laid out right to left, so the two lowest x positions
+         * are the last two in OAM
+         */
+        for i in 0..12u16 {
+            let address = 0xFE00 + (i * 4);
+            manager.cpu.mmu.set(address, 16);
+            manager.cpu.mmu.set(address + 1, 8 + ((11 - i) as u8 * 8));
+            manager.cpu.mmu.set(address + 2, 1);
+            manager.cpu.mmu.set(address + 3, 0);
+        }
+        manager.cpu.render_line();
+
+        /* Selection is by OAM index, x only orders the 10 chosen */
+        for column in 0..12 {
+            let expected = if column < 2 { Shade::White } else { Shade::Black };
+            assert_eq!(manager.cpu.buffer[0][column * 8], expected, "column {}", column);
+        }
+
+        /* Nothing on the line below */
+        manager.cpu.mmu.lcd.lines = 8;
+        manager.cpu.render_line();
+        assert_eq!(manager.cpu.buffer[8][16], Shade::White);
+    }
+
+    /* Tiles 1-3 are solid colors 1-3 */
+    fn sprite_priority_manager() -> CPUManager {
+        let mut manager = rom_manager(&[]);
+        manager.cpu.mmu.set(0xFF40, 0x93);
+        manager.cpu.mmu.set(0xFF48, 0xE4);
+
+        for tile in 1..4u16 {
+            let (top, bottom) = match tile {
+                1 => (0xFF, 0x00),
+                2 => (0x00, 0xFF),
+                _ => (0xFF, 0xFF),
+            };
+            for row in 0..8 {
+                manager.cpu.mmu.set(0x8000 + tile * 16 + row * 2, top);
+                manager.cpu.mmu.set(0x8000 + tile * 16 + row * 2 + 1, bottom);
+            }
+        }
+
+        manager
+    }
+
+    fn set_sprite(manager: &mut CPUManager, i: u16, x: u8, tile: u8) {
+        let address = 0xFE00 + i * 4;
+        manager.cpu.mmu.set(address, 16);
+        manager.cpu.mmu.set(address + 1, x);
+        manager.cpu.mmu.set(address + 2, tile);
+        manager.cpu.mmu.set(address + 3, 0);
+    }
+
+    #[test]
+    fn test_render_sprite() {
+        let mut manager = sprite_priority_manager();
+        manager.cpu.mmu.set(0xFF49, 0x40);
+
+        /* Tile 4 has a single color 3 pixel in the top left */
+        manager.cpu.mmu.set(0x8040, 0x80);
+        manager.cpu.mmu.set(0x8041, 0x80);
+
+        /* Palette 1 and flipped horizontally, at screen x 20 */
+        manager.cpu.mmu.set(0xFE00, 16);
+        manager.cpu.mmu.set(0xFE01, 28);
+        manager.cpu.mmu.set(0xFE02, 4);
+        manager.cpu.mmu.set(0xFE03, 0x30);
+        manager.cpu.render_line();
+
+        /* 0x40 maps color 3 to light grey, color 0 is transparent */
+        assert_eq!(manager.cpu.buffer[0][27], Shade::LightGrey);
+        assert_eq!(manager.cpu.buffer[0][20], manager.cpu.buffer[0][0]);
+        assert_eq!(manager.cpu.buffer[0][26], manager.cpu.buffer[0][0]);
+    }
+
+    /* The column of shades sprite 0 draws at screen x 0 */
+    fn tall_sprite_column(manager: &mut CPUManager) -> Vec<Shade> {
+        (0..17).map(|line| {
+            manager.cpu.mmu.lcd.lines = line;
+            manager.cpu.render_line();
+            manager.cpu.buffer[line as usize][0]
+        }).collect()
+    }
+
+    #[test]
+    fn test_background_scroll_wraps() {
+        let mut manager = sprite_priority_manager();
+        manager.cpu.mmu.set(0xFF47, 0xE4);
+        manager.cpu.mmu.set(0xFF42, 250);
+        manager.cpu.mmu.set(0xFF43, 250);
+
+        /* Line 10 column 10 scrolled by 250 is row 4 column 4 of the map */
+        manager.cpu.mmu.gpu.buffer[4][4] = Pixel::P3;
+        manager.cpu.mmu.gpu.buffer[4][5] = Pixel::P2;
+        manager.cpu.mmu.lcd.lines = 10;
+        manager.cpu.render_line();
+
+        assert_eq!(manager.cpu.buffer[10][10], Shade::Black);
+        assert_eq!(manager.cpu.buffer[10][11], Shade::DarkGrey);
+
+        /* The last column reads past 255 with scroll x at 255 */
+        manager.cpu.mmu.set(0xFF43, 255);
+        manager.cpu.mmu.gpu.buffer[4][158] = Pixel::P1;
+        manager.cpu.render_line();
+        assert_eq!(manager.cpu.buffer[10][159], Shade::LightGrey);
+    }
+
+    #[test]
+    fn test_render_cgb_colors() {
+        let mut header = [0; 0x4F];
+        header[0x43] = 0x80;
+        let mut manager = rom_manager_with_header(&[], Header::new(header));
+        manager.cpu.mmu.set(0xFF40, 0x93);
+        manager.cpu.mmu.set(0xFF48, 0xE4);
+
+        /* Tile 1 is solid color 3 */
+        for row in 0..16 {
+            manager.cpu.mmu.set(0x8010 + row, 0xFF);
+        }
+        set_sprite(&mut manager, 0, 28, 1);
+
+        /* Background palette 0 color 1 is red */
+        manager.cpu.mmu.set(0xFF68, 0x82);
+        manager.cpu.mmu.set(0xFF69, 0x1F);
+        manager.cpu.mmu.set(0xFF69, 0x00);
+
+        manager.cpu.mmu.gpu.buffer[0][5] = Pixel::P1;
+        manager.cpu.mmu.lcd.lines = 0;
+        manager.cpu.render_line();
+
+        assert_eq!(manager.cpu.colors[0][5], 0x001F);
+
+        /* Sprites are drawn in their DMG shade */
+        assert_eq!(manager.cpu.colors[0][20], color::shade_rgb555(Shade::Black));
+
+        let colors = manager.frame_info().colors.unwrap();
+        assert_eq!(colors[0][5], 0x001F);
+
+        assert!(rom_manager(&[]).frame_info().colors.is_none());
+    }
+
+    #[test]
+    fn test_render_tall_sprite() {
+        let mut manager = sprite_priority_manager();
+
+        /* 8x16 sprites on */
+        manager.cpu.mmu.set(0xFF40, 0x97);
+
+        /* Tile 2 is color 2 with its first row color 1, tile 3 is color 3 */
+        manager.cpu.mmu.set(0x8020, 0xFF);
+        manager.cpu.mmu.set(0x8021, 0x00);
+
+        /* Index 3 draws tiles 2 and 3, the low bit is ignored */
+        set_sprite(&mut manager, 0, 8, 3);
+
+        manager.cpu.render_line();
+        let background = manager.cpu.buffer[0][100];
+
+        let mut expected = vec![Shade::LightGrey];
+        expected.extend([Shade::DarkGrey; 7].iter());
+        expected.extend([Shade::Black; 8].iter());
+        expected.push(background);
+        assert_eq!(tall_sprite_column(&mut manager), expected);
+
+        /* Flipped vertically the bottom tile is drawn on top and each tile
+         * is upside down
+         */
+        manager.cpu.mmu.set(0xFE03, 0x40);
+
+        let mut expected = vec![Shade::Black; 8];
+        expected.extend([Shade::DarkGrey; 7].iter());
+        expected.push(Shade::LightGrey);
+        expected.push(background);
+        assert_eq!(tall_sprite_column(&mut manager), expected);
+    }
+
+    #[test]
+    fn test_sprite_priority_same_x() {
+        let mut manager = sprite_priority_manager();
+        set_sprite(&mut manager, 0, 8, 2);
+        set_sprite(&mut manager, 1, 8, 3);
+        set_sprite(&mut manager, 2, 8, 1);
+        manager.cpu.render_line();
+
+        /* The first sprite in OAM wins */
+        assert_eq!(manager.cpu.buffer[0][0], Shade::DarkGrey);
+        assert_eq!(manager.cpu.buffer[0][7], Shade::DarkGrey);
+    }
+
+    #[test]
+    fn test_sprite_priority_lower_x() {
+        let mut manager = sprite_priority_manager();
+        set_sprite(&mut manager, 0, 12, 2);
+        set_sprite(&mut manager, 1, 8, 3);
+        manager.cpu.render_line();
+
+        /* The second sprite is further left so it's on top where they overlap */
+        assert_eq!(manager.cpu.buffer[0][4], Shade::Black);
+        assert_eq!(manager.cpu.buffer[0][7], Shade::Black);
+        assert_eq!(manager.cpu.buffer[0][8], Shade::DarkGrey);
+    }
+
+    #[test]
+    fn test_sprite_priority_sorted_by_x() {
+        let mut manager = sprite_priority_manager();
+
+        /* Each sprite overlaps the next, OAM order is the reverse of x order */
+        set_sprite(&mut manager, 0, 16, 1);
+        set_sprite(&mut manager, 1, 12, 2);
+        set_sprite(&mut manager, 2, 8, 3);
+        manager.cpu.render_line();
+
+        assert_eq!(manager.cpu.buffer[0][0], Shade::Black);
+        assert_eq!(manager.cpu.buffer[0][7], Shade::Black);
+        assert_eq!(manager.cpu.buffer[0][8], Shade::DarkGrey);
+        assert_eq!(manager.cpu.buffer[0][11], Shade::DarkGrey);
+        assert_eq!(manager.cpu.buffer[0][12], Shade::LightGrey);
+        assert_eq!(manager.cpu.buffer[0][15], Shade::LightGrey);
+    }
+
+    #[test]
+    fn test_sprite_behind_background() {
+        let mut manager = sprite_priority_manager();
+
+        /* Background tile 0 is color 1 on the left half and color 0 on the right */
+        for row in 0..8 {
+            manager.cpu.mmu.set(0x8000 + row * 2, 0xF0);
+        }
+        manager.cpu.mmu.gpu.update_buffer(true, false);
+        manager.cpu.mmu.set(0xFF47, 0xE4);
+
+        set_sprite(&mut manager, 0, 8, 3);
+        manager.cpu.mmu.set(0xFE03, 0x80);
+        manager.cpu.render_line();
+
+        assert_eq!(manager.cpu.buffer[0][0], Shade::LightGrey);
+        assert_eq!(manager.cpu.buffer[0][3], Shade::LightGrey);
+        assert_eq!(manager.cpu.buffer[0][4], Shade::Black);
+        assert_eq!(manager.cpu.buffer[0][7], Shade::Black);
+    }
+
+    #[test]
+    fn test_sprite_behind_background_hides_lower_priority() {
+        let mut manager = sprite_priority_manager();
+        for row in 0..8 {
+            manager.cpu.mmu.set(0x8000 + row * 2, 0xF0);
+        }
+        manager.cpu.mmu.gpu.update_buffer(true, false);
+        manager.cpu.mmu.set(0xFF47, 0xE4);
+
+        /* Same x so the behind sprite wins by being first in OAM */
+        set_sprite(&mut manager, 0, 8, 2);
+        set_sprite(&mut manager, 1, 8, 3);
+        manager.cpu.mmu.set(0xFE03, 0x80);
+        manager.cpu.render_line();
+
+        /* Over color 1 the background hides both sprites */
+        assert_eq!(manager.cpu.buffer[0][0], Shade::LightGrey);
+        assert_eq!(manager.cpu.buffer[0][3], Shade::LightGrey);
+
+        /* Over color 0 the behind sprite is drawn */
+        assert_eq!(manager.cpu.buffer[0][4], Shade::DarkGrey);
+        assert_eq!(manager.cpu.buffer[0][7], Shade::DarkGrey);
+    }
+
+    #[test]
+    fn test_sprite_limit_raised() {
+        let mut manager = sprite_line_manager();
+        manager.set_sprite_limit(SpriteLimit::Limited(40));
+        manager.cpu.render_line();
+
+        assert_eq!(drawn_sprites(&manager), 11);
+    }
+}
@@ -2,6 +2,13 @@ pub mod tile_map;
 pub mod ram;
 pub mod lcd;
 pub mod interrupt;
+pub mod oam;
+pub mod timer;
+pub mod serial;
+pub mod joypad;
+pub mod apu;
+pub mod speed;
+pub mod cgb_palette;
 
 pub trait Device {
     fn get(&self, a: u16) -> u8;
@@ -0,0 +1,855 @@
+use std::collections::VecDeque;
+
+use crate::bytes;
+use crate::device::Device;
+
+// 0xFF10 = NR10 channel 1 sweep
+// 0xFF11 = NR11 channel 1 duty and length
+// 0xFF12 = NR12 channel 1 envelope
+// 0xFF13 = NR13 channel 1 frequency low
+// 0xFF14 = NR14 channel 1 trigger, length enable and frequency high
+// 0xFF16 - 0xFF19 = NR21 - NR24 channel 2, as channel 1 without the sweep
+// 0xFF1A = NR30 channel 3 dac enable
+// 0xFF1B = NR31 channel 3 length
+// 0xFF1C = NR32 channel 3 volume
+// 0xFF1D = NR33 channel 3 frequency low
+// 0xFF1E = NR34 channel 3 trigger, length enable and frequency high
+// 0xFF20 = NR41 channel 4 length
+// 0xFF21 = NR42 channel 4 envelope
+// 0xFF22 = NR43 channel 4 clock shift, width and divisor
+// 0xFF23 = NR44 channel 4 trigger and length enable
+// 0xFF24 = NR50 master volume
+// 0xFF25 = NR51 panning
+// 0xFF26 = NR52 power and channel status
+// 0xFF30 - 0xFF3F = wave ram
+
+pub const CPU_HZ: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/* The frame sequencer clocks length, sweep and envelope at 512Hz */
+const FRAME_SEQUENCER_CYCLES: u32 = CPU_HZ / 512;
+
+/* Bits that read back as 1 for each register from 0xFF10 to 0xFF2F,
+ * write only and unused bits.
+ */
+const READ_MASK: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+const DUTY: [u8; 4] = [
+    0b0000_0001,
+    0b1000_0001,
+    0b1000_0111,
+    0b0111_1110,
+];
+
+/* Converts a 4 bit channel output into -1.0 .. 1.0, or silence if the dac
+ * is off.
+ */
+fn dac(enabled: bool, value: u8) -> f32 {
+    if enabled {
+        value as f32 / 7.5 - 1.0
+    } else {
+        0.0
+    }
+}
+
+struct Length {
+    enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    fn load(&mut self, value: u16) {
+        self.counter = self.max - value;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /* Returns false once the length runs out and the channel turns off */
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+}
+
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = bytes::check_bit(value, 3);
+        self.period = value & 0x07;
+    }
+
+    /* The dac is on while any of the top 5 bits are set */
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            enabled: false,
+            shadow: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.period = (value >> 4) & 0x07;
+        self.negate = bytes::check_bit(value, 3);
+        self.shift = value & 0x07;
+    }
+
+    fn reload(&mut self) {
+        /* A period of 0 is treated as 8 by the timer */
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /* The next frequency, None if it overflows and turns the channel off */
+    fn next_frequency(&self) -> Option<u16> {
+        let delta = self.shadow >> self.shift;
+        let frequency = if self.negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        };
+
+        if frequency > 2047 {
+            None
+        } else {
+            Some(frequency)
+        }
+    }
+}
+
+/* Channels 1 and 2, channel 2 has no sweep */
+pub struct Square {
+    pub enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    duty_position: u8,
+    length: Length,
+    envelope: Envelope,
+    frequency: u16,
+    timer: u32,
+}
+
+impl Square {
+    fn new(sweep: bool) -> Square {
+        Square {
+            enabled: false,
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+            duty: 0,
+            duty_position: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    /* Cycles between steps through the duty pattern */
+    pub fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    /* Writes to the channel's registers, 0 to 4 for NRx0 to NRx4 */
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.write(value);
+                }
+            },
+            1 => {
+                self.duty = value >> 6;
+                self.length.load((value & 0x3F) as u16);
+            },
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((value & 0x07) as u16) << 8);
+                self.length.enabled = bytes::check_bit(value, 6);
+
+                if bytes::check_bit(value, 7) {
+                    self.trigger();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = self.period();
+
+        let frequency = self.frequency;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = frequency;
+            sweep.reload();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+
+            if sweep.shift != 0 && sweep.next_frequency().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn advance_cycles(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match self.sweep.as_mut() {
+            Some(sweep) => sweep,
+            None => return,
+        };
+
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload();
+
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+
+        match sweep.next_frequency() {
+            Some(frequency) if sweep.shift != 0 => {
+                sweep.shadow = frequency;
+                self.frequency = frequency;
+
+                /* The overflow check runs again with the new frequency */
+                if sweep.next_frequency().is_none() {
+                    self.enabled = false;
+                }
+            },
+            Some(_) => {},
+            None => self.enabled = false,
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let high = (DUTY[self.duty as usize] >> (7 - self.duty_position)) & 0x01;
+        let value = if self.enabled { high * self.envelope.volume } else { 0 };
+        dac(self.envelope.dac_enabled(), value)
+    }
+}
+
+/* Channel 3 plays back the 32 4 bit samples in wave ram */
+pub struct Wave {
+    pub enabled: bool,
+    dac_enabled: bool,
+    length: Length,
+    volume: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac_enabled: false,
+            length: Length::new(256),
+            volume: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            ram: [0; 16],
+        }
+    }
+
+    pub fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = bytes::check_bit(value, 7);
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.load(value as u16),
+            2 => self.volume = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | (((value & 0x07) as u16) << 8);
+                self.length.enabled = bytes::check_bit(value, 6);
+
+                if bytes::check_bit(value, 7) {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn advance_cycles(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> f32 {
+        /* High nibble first */
+        let byte = self.ram[self.position as usize / 2];
+        let sample = if self.position & 0x01 == 0 { byte >> 4 } else { byte & 0x0F };
+
+        /* Volume codes 0-3 are mute, 100%, 50% and 25% */
+        let value = match (self.enabled, self.volume) {
+            (false, _) | (_, 0) => 0,
+            (true, v) => sample >> (v - 1),
+        };
+
+        dac(self.dac_enabled, value)
+    }
+}
+
+/* Channel 4, pseudo random noise from a linear feedback shift register */
+pub struct Noise {
+    pub enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    shift: u8,
+    narrow: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            narrow: false,
+            divisor: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub fn period(&self) -> u32 {
+        let divisor = if self.divisor == 0 { 8 } else { self.divisor as u32 * 16 };
+        divisor << self.shift
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load((value & 0x3F) as u16),
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.shift = value >> 4;
+                self.narrow = bytes::check_bit(value, 3);
+                self.divisor = value & 0x07;
+            },
+            4 => {
+                self.length.enabled = bytes::check_bit(value, 6);
+
+                if bytes::check_bit(value, 7) {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn advance_cycles(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let bit = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+
+            /* Narrow mode also feeds bit 6 for a 7 bit register */
+            if self.narrow {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+
+        self.timer -= cycles;
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let high = (!self.lfsr & 0x01) as u8;
+        let value = if self.enabled { high * self.envelope.volume } else { 0 };
+        dac(self.envelope.dac_enabled(), value)
+    }
+}
+
+/* Audio Processing Unit
+ *
+ * Samples are generated at sample_rate as interleaved left / right pairs
+ * into a ring buffer for the audio device to drain with take_samples. If
+ * nothing drains it the oldest samples are dropped.
+ */
+pub struct Apu {
+    pub channel1: Square,
+    pub channel2: Square,
+    pub channel3: Wave,
+    pub channel4: Noise,
+
+    powered: bool,
+    registers: [u8; 0x20],
+
+    frame_sequencer: u32,
+    frame_step: u8,
+
+    sample_rate: u32,
+    sample_clock: u32,
+    samples: VecDeque<f32>,
+}
+
+impl Apu {
+    pub fn new(sample_rate: u32) -> Apu {
+        Apu {
+            channel1: Square::new(true),
+            channel2: Square::new(false),
+            channel3: Wave::new(),
+            channel4: Noise::new(),
+
+            powered: false,
+            registers: [0; 0x20],
+
+            frame_sequencer: 0,
+            frame_step: 0,
+
+            sample_rate,
+            sample_clock: 0,
+            samples: VecDeque::with_capacity(Apu::capacity(sample_rate)),
+        }
+    }
+
+    /* A quarter second of stereo samples */
+    fn capacity(sample_rate: u32) -> usize {
+        (sample_rate as usize / 4) * 2
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+
+    pub fn advance_cycles(&mut self, n: u8) {
+        let cycles = n as u32;
+
+        if self.powered {
+            self.channel1.advance_cycles(cycles);
+            self.channel2.advance_cycles(cycles);
+            self.channel3.advance_cycles(cycles);
+            self.channel4.advance_cycles(cycles);
+
+            self.frame_sequencer += cycles;
+            if self.frame_sequencer >= FRAME_SEQUENCER_CYCLES {
+                self.frame_sequencer -= FRAME_SEQUENCER_CYCLES;
+                self.step_frame_sequencer();
+            }
+        }
+
+        self.sample_clock += cycles * self.sample_rate;
+        while self.sample_clock >= CPU_HZ {
+            self.sample_clock -= CPU_HZ;
+            self.push_sample();
+        }
+    }
+
+    /* Length on even steps, sweep on 2 and 6, envelope on 7 */
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 0x01 == 0 {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.channel1.clock_sweep();
+        }
+
+        if self.frame_step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /* Mixes the channels using the NR51 panning and NR50 master volume */
+    fn mix(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+
+        let outputs = [
+            self.channel1.output(),
+            self.channel2.output(),
+            self.channel3.output(),
+            self.channel4.output(),
+        ];
+
+        let panning = self.registers[0x15];
+        let volume = self.registers[0x14];
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if bytes::check_bit(panning, i as u8 + 4) {
+                left += output;
+            }
+            if bytes::check_bit(panning, i as u8) {
+                right += output;
+            }
+        }
+
+        let left_volume = ((volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (volume & 0x07) as f32 + 1.0;
+
+        (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+    }
+
+    fn push_sample(&mut self) {
+        if self.samples.len() + 2 > Apu::capacity(self.sample_rate) {
+            self.samples.pop_front();
+            self.samples.pop_front();
+        }
+
+        let (left, right) = self.mix();
+        self.samples.push_back(left);
+        self.samples.push_back(right);
+    }
+
+    fn power_off(&mut self) {
+        let wave_ram = self.channel3.ram;
+
+        self.channel1 = Square::new(true);
+        self.channel2 = Square::new(false);
+        self.channel3 = Wave::new();
+        self.channel4 = Noise::new();
+        self.channel3.ram = wave_ram;
+
+        self.registers = [0; 0x20];
+        self.powered = false;
+    }
+}
+
+impl Device for Apu {
+    fn get(&self, address: u16) -> u8 {
+        match address {
+            0x0026 => {
+                let mut status = 0x70;
+                status = bytes::set_bit(status, 7, self.powered);
+                status = bytes::set_bit(status, 3, self.channel4.enabled);
+                status = bytes::set_bit(status, 2, self.channel3.enabled);
+                status = bytes::set_bit(status, 1, self.channel2.enabled);
+                status = bytes::set_bit(status, 0, self.channel1.enabled);
+                status
+            },
+            0x0010..=0x002F => {
+                let i = (address - 0x0010) as usize;
+                self.registers[i] | READ_MASK[i]
+            },
+            0x0030..=0x003F => self.channel3.ram[(address - 0x0030) as usize],
+            _ => panic!("invalid apu address: {:X}", address),
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            0x0026 => {
+                let powered = bytes::check_bit(value, 7);
+                if !powered {
+                    self.power_off();
+                } else if !self.powered {
+                    self.powered = true;
+                    self.frame_step = 0;
+                }
+            },
+            /* Powered off the registers ignore writes, wave ram doesn't */
+            0x0010..=0x002F if !self.powered => {},
+            0x0010..=0x002F => {
+                self.registers[(address - 0x0010) as usize] = value;
+
+                match address {
+                    0x0010..=0x0014 => self.channel1.write(address - 0x0010, value),
+                    0x0015..=0x0019 => self.channel2.write(address - 0x0015, value),
+                    0x001A..=0x001E => self.channel3.write(address - 0x001A, value),
+                    0x001F..=0x0023 => self.channel4.write(address - 0x001F, value),
+                    _ => {},
+                }
+            },
+            0x0030..=0x003F => self.channel3.ram[(address - 0x0030) as usize] = value,
+            _ => panic!("invalid apu address: {:X}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.set(0x0026, 0x80);
+        apu
+    }
+
+    #[test]
+    fn test_square_period() {
+        let mut apu = powered_apu();
+
+        /* frequency 0x700 */
+        apu.set(0x0013, 0x00);
+        apu.set(0x0014, 0x07);
+        assert_eq!(apu.channel1.period(), (2048 - 0x700) * 4);
+
+        apu.set(0x0013, 0xFF);
+        /* The highest frequency gives the minimum period */
+        assert_eq!(apu.channel1.period(), 4);
+
+        /* Writing NR14 again keeps the low bits from NR13 */
+        apu.set(0x0014, 0x00);
+        assert_eq!(apu.channel1.period(), (2048 - 0x0FF) * 4);
+    }
+
+    #[test]
+    fn test_square_duty_steps() {
+        let mut apu = powered_apu();
+        apu.set(0x0011, 0x80);
+        apu.set(0x0012, 0xF0);
+        apu.set(0x0013, 0x00);
+        apu.set(0x0014, 0x87);
+        assert!(apu.channel1.enabled);
+
+        /* One step through the duty pattern every 1024 cycles */
+        for _ in 0..(1024 / 4) {
+            apu.advance_cycles(4);
+        }
+        assert_eq!(apu.channel1.duty_position, 1);
+    }
+
+    #[test]
+    fn test_trigger_needs_dac() {
+        let mut apu = powered_apu();
+        apu.set(0x0012, 0x00);
+        apu.set(0x0014, 0x80);
+        assert!(!apu.channel1.enabled);
+        assert_eq!(apu.get(0x0026), 0xF0);
+
+        apu.set(0x0012, 0xF0);
+        apu.set(0x0014, 0x80);
+        assert_eq!(apu.get(0x0026), 0xF1);
+    }
+
+    #[test]
+    fn test_length_expires() {
+        let mut apu = powered_apu();
+        apu.set(0x0011, 0x3E);
+        apu.set(0x0012, 0xF0);
+        apu.set(0x0014, 0xC0);
+        assert!(apu.channel1.enabled);
+
+        /* A length of 2 runs out after two 256Hz clocks */
+        for _ in 0..FRAME_SEQUENCER_CYCLES {
+            apu.advance_cycles(4);
+        }
+        assert!(!apu.channel1.enabled);
+    }
+
+    #[test]
+    fn test_envelope_decreases() {
+        let mut apu = powered_apu();
+        apu.set(0x0012, 0xF1);
+        apu.set(0x0014, 0x80);
+        assert_eq!(apu.channel1.envelope.volume, 15);
+
+        /* The envelope is clocked at 64Hz */
+        for _ in 0..(FRAME_SEQUENCER_CYCLES * 8 / 4) {
+            apu.advance_cycles(4);
+        }
+        assert_eq!(apu.channel1.envelope.volume, 14);
+    }
+
+    #[test]
+    fn test_register_readback() {
+        let mut apu = powered_apu();
+        apu.set(0x0011, 0x80);
+        assert_eq!(apu.get(0x0011), 0xBF);
+        apu.set(0x0013, 0x12);
+        assert_eq!(apu.get(0x0013), 0xFF);
+        apu.set(0x0030, 0x12);
+        assert_eq!(apu.get(0x0030), 0x12);
+
+        apu.set(0x0026, 0x00);
+        assert_eq!(apu.get(0x0011), 0x3F);
+        assert_eq!(apu.get(0x0026), 0x70);
+        assert_eq!(apu.get(0x0030), 0x12);
+    }
+
+    #[test]
+    fn test_writes_while_powered_off() {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+
+        apu.set(0x0024, 0x77);
+        apu.set(0x0012, 0xF0);
+        apu.set(0x0014, 0x80);
+        assert_eq!(apu.get(0x0024), 0x00);
+        assert!(!apu.channel1.enabled);
+
+        apu.set(0x0030, 0x12);
+        assert_eq!(apu.get(0x0030), 0x12);
+
+        apu.set(0x0026, 0x80);
+        apu.set(0x0024, 0x77);
+        assert_eq!(apu.get(0x0024), 0x77);
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut apu = powered_apu();
+
+        /* A second of cycles */
+        for _ in 0..(CPU_HZ / 4) {
+            apu.advance_cycles(4);
+        }
+
+        /* Only a quarter second fits in the buffer */
+        assert_eq!(apu.take_samples().len(), Apu::capacity(DEFAULT_SAMPLE_RATE));
+        assert!(apu.take_samples().is_empty());
+    }
+}
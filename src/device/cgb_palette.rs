@@ -0,0 +1,142 @@
+use std::io::Error;
+
+use crate::bytes;
+use crate::color::{rgb555_to_rgb888, ColorCorrection};
+use crate::device::Device;
+use crate::pixel::Pixel;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/* CGB background palettes
+ *
+ * 0xFF68 = BCPS, palette index
+ * 0xFF69 = BCPD, palette data
+ *
+ * Palette memory holds eight palettes of four 15 bit colors, two bytes
+ * each little endian, 64 bytes in all. BCPS bits 0-5 pick the byte BCPD
+ * reads and writes, with bit 7 set the index moves on after every write to
+ * BCPD so a whole palette can be written in one go.
+ */
+pub const PALETTE_BYTES: usize = 64;
+
+pub struct CgbPalettes {
+    index: u8,
+    auto_increment: bool,
+    data: [u8; PALETTE_BYTES],
+}
+
+impl CgbPalettes {
+    pub fn new() -> CgbPalettes {
+        CgbPalettes {
+            index: 0,
+            auto_increment: false,
+            data: [0; PALETTE_BYTES],
+        }
+    }
+
+    /* The 15 bit color for a tile color number in one of the eight palettes */
+    pub fn color(&self, palette: u8, p: Pixel) -> u16 {
+        let offset = (palette as usize & 0x07) * 8 + p as usize * 2;
+        ((self.data[offset + 1] as u16) << 8) | self.data[offset] as u16
+    }
+
+    pub fn rgb(&self, palette: u8, p: Pixel, correction: ColorCorrection) -> (u8, u8, u8) {
+        rgb555_to_rgb888(self.color(palette, p), correction)
+    }
+}
+
+impl Default for CgbPalettes {
+    fn default() -> CgbPalettes {
+        CgbPalettes::new()
+    }
+}
+
+impl Device for CgbPalettes {
+    fn get(&self, address: u16) -> u8 {
+        match address {
+            /* Bit 6 is unused and reads as 1 */
+            0x0000 => bytes::set_bit(0x40 | self.index, 7, self.auto_increment),
+            0x0001 => self.data[self.index as usize],
+            _ => panic!("invalid cgb palette address: {:X}", address),
+        }
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 => {
+                self.index = value & 0x3F;
+                self.auto_increment = bytes::check_bit(value, 7);
+            },
+            0x0001 => {
+                self.data[self.index as usize] = value;
+                if self.auto_increment {
+                    self.index = (self.index + 1) & 0x3F;
+                }
+            },
+            _ => panic!("invalid cgb palette address: {:X}", address),
+        }
+    }
+}
+
+impl SaveState for CgbPalettes {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.index);
+        w.bool(self.auto_increment);
+        w.bytes(&self.data);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.index = r.u8()?;
+        self.auto_increment = r.bool()?;
+        r.bytes_into(&mut self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auto_increment() {
+        let mut palettes = CgbPalettes::new();
+
+        /* Start at palette 1 color 0 */
+        palettes.set(0, 0x88);
+        assert_eq!(palettes.get(0), 0xC8);
+
+        palettes.set(1, 0x1F);
+        palettes.set(1, 0x00);
+        assert_eq!(palettes.get(0), 0xCA);
+
+        /* Without auto increment writes stay put */
+        palettes.set(0, 0x0A);
+        palettes.set(1, 0xE0);
+        palettes.set(1, 0xE0);
+        assert_eq!(palettes.get(0), 0x4A);
+        assert_eq!(palettes.get(1), 0xE0);
+    }
+
+    #[test]
+    fn test_increment_wraps() {
+        let mut palettes = CgbPalettes::new();
+        palettes.set(0, 0xBF);
+        palettes.set(1, 0x12);
+
+        assert_eq!(palettes.get(0), 0xC0);
+        palettes.set(0, 0x3F);
+        assert_eq!(palettes.get(1), 0x12);
+    }
+
+    #[test]
+    fn test_color_unpacking() {
+        let mut palettes = CgbPalettes::new();
+
+        /* Palette 2 color 3 is pure blue, 0x7C00 */
+        palettes.set(0, 0x80 | (2 * 8 + 3 * 2));
+        palettes.set(1, 0x00);
+        palettes.set(1, 0x7C);
+
+        assert_eq!(palettes.color(2, Pixel::P3), 0x7C00);
+        assert_eq!(palettes.rgb(2, Pixel::P3, ColorCorrection::None), (0x00, 0x00, 0xFF));
+        assert_eq!(palettes.color(2, Pixel::P2), 0x0000);
+    }
+}
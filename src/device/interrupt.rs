@@ -1,5 +1,6 @@
 use crate::bytes;
 
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Interrupt {
     VBlank,
     LCDStat,
@@ -8,6 +9,30 @@ pub enum Interrupt {
     Joypad,
 }
 
+/* Interrupts in priority order, when more than one is pending the
+ * earliest in this list is serviced first.
+ */
+pub const PRIORITY: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LCDStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    /* Location of the interrupt handler jumped to when servicing */
+    pub fn address(&self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::LCDStat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct InterruptFlag {
     pub vblank: bool,
@@ -27,6 +52,32 @@ impl InterruptFlag {
             joypad: false,
         }
     }
+
+    pub fn get(&self, i: Interrupt) -> bool {
+        match i {
+            Interrupt::VBlank => self.vblank,
+            Interrupt::LCDStat => self.lcd_stat,
+            Interrupt::Timer => self.timer,
+            Interrupt::Serial => self.serial,
+            Interrupt::Joypad => self.joypad,
+        }
+    }
+
+    pub fn set(&mut self, i: Interrupt, v: bool) {
+        match i {
+            Interrupt::VBlank => self.vblank = v,
+            Interrupt::LCDStat => self.lcd_stat = v,
+            Interrupt::Timer => self.timer = v,
+            Interrupt::Serial => self.serial = v,
+            Interrupt::Joypad => self.joypad = v,
+        }
+    }
+}
+
+impl Default for InterruptFlag {
+    fn default() -> InterruptFlag {
+        InterruptFlag::new()
+    }
 }
 
 impl std::convert::From<u8> for InterruptFlag {
@@ -41,9 +92,10 @@ impl std::convert::From<u8> for InterruptFlag {
     }
 }
 
+/* Only bits 0-4 hold interrupts, as in IF the top 3 bits read as 1 */
 impl std::convert::From<InterruptFlag> for u8 {
     fn from(p: InterruptFlag) -> Self {
-        let mut u:u8 = 0x00;
+        let mut u:u8 = 0xE0;
 
         u = bytes::set_bit(u, 0, p.vblank);
         u = bytes::set_bit(u, 1, p.lcd_stat);
@@ -54,3 +106,42 @@ impl std::convert::From<InterruptFlag> for u8 {
         u
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_set() {
+        for i in PRIORITY.iter() {
+            let mut flag = InterruptFlag::new();
+
+            flag.set(*i, true);
+            assert!(flag.get(*i));
+
+            for other in PRIORITY.iter().filter(|o| *o != i) {
+                assert!(!flag.get(*other));
+            }
+
+            flag.set(*i, false);
+            assert!(!flag.get(*i));
+        }
+    }
+
+    #[test]
+    fn test_u8_round_trip() {
+        let mut flag = InterruptFlag::new();
+        flag.set(Interrupt::VBlank, true);
+        flag.set(Interrupt::Serial, true);
+
+        let byte = u8::from(flag);
+        assert_eq!(byte, 0b1110_1001);
+
+        let flag = InterruptFlag::from(byte);
+        assert!(flag.get(Interrupt::VBlank));
+        assert!(!flag.get(Interrupt::LCDStat));
+        assert!(!flag.get(Interrupt::Timer));
+        assert!(flag.get(Interrupt::Serial));
+        assert!(!flag.get(Interrupt::Joypad));
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::Error;
+
+use crate::bytes;
+use crate::device::Device;
+use crate::msg::Button;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/* P1 / JOYP, 0xFF00
+ *
+ * The buttons are wired as a 2x4 matrix, writing 0 to bit 4 selects the
+ * directions and writing 0 to bit 5 selects the actions. The low nibble then
+ * reads the selected buttons, active low.
+ *
+ *   bit 3: down / start
+ *   bit 2: up / select
+ *   bit 1: left / b
+ *   bit 0: right / a
+ */
+pub struct Joypad {
+    select: u8,
+    directions: u8,
+    actions: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: 0x30,
+            directions: 0,
+            actions: 0,
+        }
+    }
+
+    /* Updates a button, returns true if one of the selected lines went
+     * from high to low which raises the joypad interrupt.
+     */
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.lines();
+
+        let (group, bit) = match button {
+            Button::Right => (&mut self.directions, 0),
+            Button::Left => (&mut self.directions, 1),
+            Button::Up => (&mut self.directions, 2),
+            Button::Down => (&mut self.directions, 3),
+            Button::A => (&mut self.actions, 0),
+            Button::B => (&mut self.actions, 1),
+            Button::Select => (&mut self.actions, 2),
+            Button::Start => (&mut self.actions, 3),
+        };
+        *group = bytes::set_bit(*group, bit, pressed);
+
+        before & !self.lines() != 0
+    }
+
+    /* The low nibble as read, 1 for released */
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+
+        if !bytes::check_bit(self.select, 4) {
+            pressed |= self.directions;
+        }
+
+        if !bytes::check_bit(self.select, 5) {
+            pressed |= self.actions;
+        }
+
+        !pressed & 0x0F
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
+impl Device for Joypad {
+    fn get(&self, _address: u16) -> u8 {
+        /* The top two bits are unused and read as 1 */
+        0xC0 | self.select | self.lines()
+    }
+
+    fn set(&mut self, _address: u16, value: u8) {
+        self.select = value & 0x30;
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.u8(self.directions);
+        w.u8(self.actions);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.select = r.u8()?;
+        self.directions = r.u8()?;
+        self.actions = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_directions() {
+        let mut joypad = Joypad::new();
+        joypad.set(0, 0x20);
+        assert_eq!(joypad.get(0), 0xEF);
+
+        assert!(joypad.set_button(Button::Right, true));
+        assert_eq!(joypad.get(0) & 0x01, 0x00);
+
+        /* Actions aren't selected */
+        assert!(!joypad.set_button(Button::Start, true));
+        assert_eq!(joypad.get(0), 0xEE);
+
+        joypad.set(0, 0x10);
+        assert_eq!(joypad.get(0), 0xD7);
+
+        joypad.set_button(Button::Right, false);
+        joypad.set(0, 0x20);
+        assert_eq!(joypad.get(0), 0xEF);
+    }
+}
@@ -1,6 +1,10 @@
+use std::io::Error;
+
 use crate::device::Device;
+use crate::palette;
 use crate::palette::{Palette};
 use crate::bytes;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 // 0xFF40 = control register
 // 0xFF41 = status register
@@ -68,13 +72,19 @@ impl StatusRegister {
     }
 }
 
+impl Default for StatusRegister {
+    fn default() -> StatusRegister {
+        StatusRegister::new()
+    }
+}
+
 impl std::convert::From<u8> for StatusRegister {
     fn from(byte: u8) -> Self {
         let mode = match (bytes::check_bit(byte, 1), bytes::check_bit(byte, 0)) {
             (false, false) => Mode::HBlank,
-            (false, true) => Mode::VRAM,
+            (false, true) => Mode::VBlank,
             (true, false) => Mode::OAM,
-            (true, true) => Mode::VBlank,
+            (true, true) => Mode::VRAM,
         };
 
         StatusRegister {
@@ -93,9 +103,9 @@ impl std::convert::From<StatusRegister> for u8 {
 
         let mut u = match r.mode {
             Mode::HBlank => 0b00,
-            Mode::VRAM => 0b01,
+            Mode::VBlank => 0b01,
             Mode::OAM => 0b10,
-            Mode::VBlank => 0b11,
+            Mode::VRAM => 0b11,
         };
 
         u = bytes::set_bit(u, 6, r.ly_coincidence_interrupt);
@@ -147,6 +157,12 @@ impl ControlRegister {
     }
 }
 
+impl Default for ControlRegister {
+    fn default() -> ControlRegister {
+        ControlRegister::new()
+    }
+}
+
 impl std::convert::From<u8> for ControlRegister {
     fn from(byte: u8) -> Self {
         ControlRegister {
@@ -179,6 +195,41 @@ impl std::convert::From<ControlRegister> for u8 {
     }
 }
 
+/* The registers that affect how a single scanline is drawn. Games change
+ * these mid frame for raster effects so they're captured as each line is
+ * rendered rather than read once per frame.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct LineRegisters {
+    pub scroll_y: u8,
+    pub scroll_x: u8,
+    pub window_y: u8,
+    pub window_x: u8,
+    pub bg_palette: Palette,
+    pub object_palette_0: Palette,
+    pub object_palette_1: Palette,
+}
+
+impl LineRegisters {
+    pub fn new() -> LineRegisters {
+        LineRegisters {
+            scroll_y: 0,
+            scroll_x: 0,
+            window_y: 0,
+            window_x: 0,
+            bg_palette: Palette::new(),
+            object_palette_0: Palette::new(),
+            object_palette_1: Palette::new(),
+        }
+    }
+}
+
+impl Default for LineRegisters {
+    fn default() -> LineRegisters {
+        LineRegisters::new()
+    }
+}
+
 pub struct LCD {
     pub cycles: u32,
     pub lines: u8,
@@ -189,7 +240,7 @@ pub struct LCD {
 
     pub scroll_y: u8,
     pub scroll_x: u8,
-    //pub lcd_y: u8,
+    // 0xFF44 reads lines directly
     pub ly_compare: u8,
     pub dma: u8,
     pub bg_palette: Palette,
@@ -197,6 +248,12 @@ pub struct LCD {
     pub object_palette_1: Palette,
     pub window_y: u8,
     pub window_x: u8,
+
+    // Set when an LCD STAT interrupt should be raised, drained by the cpu
+    pub stat_interrupt: bool,
+
+    // Decoded palette writes, only collected when logging is enabled
+    pub log: Option<Vec<String>>,
 }
 
 impl LCD {
@@ -210,7 +267,6 @@ impl LCD {
             status: StatusRegister::new(),
             scroll_y: 0,
             scroll_x: 0,
-            //lcd_y: 0,
             ly_compare: 0,
             dma: 0,
             bg_palette: Palette::new(),
@@ -218,11 +274,79 @@ impl LCD {
             object_palette_1: Palette::new(),
             window_y: 0,
             window_x: 0,
+
+            stat_interrupt: false,
+            log: None,
+        }
+    }
+
+    fn log_palette(&mut self, name: &str, v: u8) {
+        if let Some(log) = self.log.as_mut() {
+            log.push(palette::describe(name, v));
+        }
+    }
+
+
+    /* Updates the LY=LYC coincidence flag, requesting a STAT interrupt if
+     * the two match and the coincidence interrupt is enabled.
+     */
+    fn check_coincidence(&mut self) {
+        self.status.coincidence = self.lines == self.ly_compare;
+
+        if self.status.coincidence && self.status.ly_coincidence_interrupt {
+            self.stat_interrupt = true;
+        }
+    }
+
+    pub fn take_stat_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.stat_interrupt)
+    }
+
+    pub fn line_registers(&self) -> LineRegisters {
+        LineRegisters {
+            scroll_y: self.scroll_y,
+            scroll_x: self.scroll_x,
+            window_y: self.window_y,
+            window_x: self.window_x,
+            bg_palette: self.bg_palette,
+            object_palette_0: self.object_palette_0,
+            object_palette_1: self.object_palette_1,
+        }
+    }
+
+    /* Whether entering mode requests a STAT interrupt */
+    fn mode_interrupt_enabled(&self, mode: Mode) -> bool {
+        match mode {
+            Mode::HBlank => self.status.hblank_interrupt,
+            Mode::VBlank => self.status.vblank_interrupt,
+            Mode::OAM => self.status.oam_interrupt,
+            Mode::VRAM => false,
         }
     }
-    
 
     pub fn advance_cycles(&mut self, n: u8) -> Option<(Mode, Mode)> {
+        /* With the display off LY holds at 0 and there are no modes */
+        if !self.control.display_enabled {
+            return None
+        }
+
+        let line = self.lines;
+        let transition = self.advance_mode(n);
+
+        if self.lines != line {
+            self.check_coincidence();
+        }
+
+        if let Some((_, mode)) = transition {
+            if self.mode_interrupt_enabled(mode) {
+                self.stat_interrupt = true;
+            }
+        }
+
+        transition
+    }
+
+    fn advance_mode(&mut self, n: u8) -> Option<(Mode, Mode)> {
         self.mode_clock += n as u32;
         self.cycles += n as u32;
 
@@ -281,11 +405,18 @@ impl LCD {
     }
 }
 
+impl Default for LCD {
+    fn default() -> LCD {
+        LCD::new()
+    }
+}
+
 impl Device for LCD {
     fn get(&self, address: u16) -> u8 {
         match address {
             0x0040 => u8::from(self.control),
-            0x0041 => u8::from(self.status),
+            // Bit 7 is unused and reads as 1
+            0x0041 => u8::from(self.status) | 0x80,
             0x0042 => self.scroll_y,
             0x0043 => self.scroll_x,
             0x0044 => self.lines,
@@ -302,22 +433,342 @@ impl Device for LCD {
 
     fn set(&mut self, address: u16, v: u8) {
         match address {
-            0x0040 => self.control = ControlRegister::from(v),
-            0x0041 => self.status = StatusRegister::from(v),
+            0x0040 => {
+                let was_enabled = self.control.display_enabled;
+                self.control = ControlRegister::from(v);
+
+                match (was_enabled, self.control.display_enabled) {
+                    (true, false) => {
+                        self.lines = 0;
+                        self.mode_clock = 0;
+                        self.status.mode = Mode::HBlank;
+                    },
+                    /* Turning the display back on starts a fresh frame */
+                    (false, true) => {
+                        self.mode_clock = 0;
+                        self.status.mode = Mode::OAM;
+                        self.check_coincidence();
+                    },
+                    _ => {},
+                }
+            },
+            /* Only the interrupt enables (bits 3-6) are writable, the mode
+             * and coincidence flag belong to the lcd
+             */
+            0x0041 => {
+                let written = StatusRegister::from(v);
+                self.status.ly_coincidence_interrupt = written.ly_coincidence_interrupt;
+                self.status.oam_interrupt = written.oam_interrupt;
+                self.status.vblank_interrupt = written.vblank_interrupt;
+                self.status.hblank_interrupt = written.hblank_interrupt;
+            },
             0x0042 => {
                 // println!("Scrolling y: {:X}", v);
                 self.scroll_y = v;
             },
             0x0043 => self.scroll_x = v,
-            0x0044 => self.lines = v,
-            0x0045 => self.ly_compare = v,
+            /* Any write restarts the frame from line 0, which with the
+             * display on begins in OAM like any other line
+             */
+            0x0044 => {
+                self.lines = 0;
+                self.mode_clock = 0;
+                self.cycles = 0;
+
+                if self.control.display_enabled {
+                    if self.status.mode != Mode::OAM && self.mode_interrupt_enabled(Mode::OAM) {
+                        self.stat_interrupt = true;
+                    }
+                    self.status.mode = Mode::OAM;
+                }
+
+                self.check_coincidence();
+            },
+            0x0045 => {
+                self.ly_compare = v;
+                self.check_coincidence();
+            },
             0x0046 => self.dma = v,
-            0x0047 => self.bg_palette = Palette::from(v),
-            0x0048 => self.object_palette_0 = Palette::from(v),
-            0x0049 => self.object_palette_1 = Palette::from(v),
+            0x0047 => {
+                self.log_palette("BGP", v);
+                self.bg_palette = Palette::from(v);
+            },
+            0x0048 => {
+                self.log_palette("OBP0", v);
+                self.object_palette_0 = Palette::from(v);
+            },
+            0x0049 => {
+                self.log_palette("OBP1", v);
+                self.object_palette_1 = Palette::from(v);
+            },
             0x004A => self.window_y = v,
             0x004B => self.window_x = v,
             _ => panic!("invalid lcd address: {:X}", address),
         }
     }
 }
+
+impl SaveState for LCD {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u32(self.cycles);
+        w.u8(self.lines);
+        w.u32(self.mode_clock);
+        w.u8(u8::from(self.control));
+        w.u8(u8::from(self.status));
+        w.u8(self.scroll_y);
+        w.u8(self.scroll_x);
+        w.u8(self.ly_compare);
+        w.u8(self.dma);
+        w.u8(u8::from(self.bg_palette));
+        w.u8(u8::from(self.object_palette_0));
+        w.u8(u8::from(self.object_palette_1));
+        w.u8(self.window_y);
+        w.u8(self.window_x);
+        w.bool(self.stat_interrupt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.cycles = r.u32()?;
+        self.lines = r.u8()?;
+        self.mode_clock = r.u32()?;
+        self.control = ControlRegister::from(r.u8()?);
+        self.status = StatusRegister::from(r.u8()?);
+        self.scroll_y = r.u8()?;
+        self.scroll_x = r.u8()?;
+        self.ly_compare = r.u8()?;
+        self.dma = r.u8()?;
+        self.bg_palette = Palette::from(r.u8()?);
+        self.object_palette_0 = Palette::from(r.u8()?);
+        self.object_palette_1 = Palette::from(r.u8()?);
+        self.window_y = r.u8()?;
+        self.window_x = r.u8()?;
+        self.stat_interrupt = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_mode_bits() {
+        for (bits, mode) in [(0, Mode::HBlank), (1, Mode::VBlank), (2, Mode::OAM), (3, Mode::VRAM)].iter() {
+            assert_eq!(StatusRegister::from(*bits).mode, *mode);
+            assert_eq!(u8::from(StatusRegister::from(*bits)), *bits);
+        }
+    }
+
+    #[test]
+    fn test_ly_write_resets() {
+        let mut lcd = LCD::new();
+        lcd.lines = 42;
+        lcd.mode_clock = 100;
+
+        lcd.set(0x0044, 0x99);
+
+        assert_eq!(lcd.get(0x0044), 0);
+        assert_eq!(lcd.mode_clock, 0);
+        assert!(lcd.status.coincidence);
+        assert!(!lcd.take_stat_interrupt());
+    }
+
+    #[test]
+    fn test_stat_write_read_only_bits() {
+        let mut lcd = LCD::new();
+        lcd.status.mode = Mode::VRAM;
+        lcd.status.coincidence = true;
+
+        /* Try to clear the mode and coincidence bits while enabling interrupts */
+        lcd.set(0x0041, 0x78);
+        assert_eq!(lcd.get(0x0041), 0xFF);
+        assert_eq!(lcd.status.mode, Mode::VRAM);
+
+        lcd.status.coincidence = false;
+        lcd.status.mode = Mode::HBlank;
+
+        /* and to set them while disabling */
+        lcd.set(0x0041, 0x07);
+        assert_eq!(lcd.get(0x0041), 0x80);
+        assert!(!lcd.status.hblank_interrupt);
+
+        lcd.set(0x0041, 0x48);
+        assert!(lcd.status.ly_coincidence_interrupt);
+        assert!(lcd.status.hblank_interrupt);
+        assert!(!lcd.status.oam_interrupt);
+        assert_eq!(lcd.get(0x0041), 0xC8);
+    }
+
+    #[test]
+    fn test_ly_write_read_only() {
+        let mut lcd = enabled_lcd();
+        lcd.lines = 90;
+
+        lcd.set(0x0044, 0x42);
+        assert_eq!(lcd.get(0x0044), 0);
+        assert_eq!(lcd.status.mode, Mode::OAM);
+    }
+
+    #[test]
+    fn test_ly_write_coincidence_interrupt() {
+        let mut lcd = LCD::new();
+        lcd.lines = 42;
+        lcd.status.ly_coincidence_interrupt = true;
+
+        lcd.set(0x0044, 0x00);
+
+        assert!(lcd.status.coincidence);
+        assert!(lcd.take_stat_interrupt());
+        assert!(!lcd.take_stat_interrupt());
+    }
+
+    #[test]
+    fn test_ly_write_during_vblank_restarts_frame() {
+        let mut lcd = enabled_lcd();
+        lcd.status.oam_interrupt = true;
+
+        while lcd.status.mode != Mode::VBlank {
+            lcd.advance_cycles(4);
+        }
+        lcd.advance_cycles(100);
+        lcd.take_stat_interrupt();
+
+        lcd.set(0x0044, 0x00);
+        assert_eq!(lcd.status.mode, Mode::OAM);
+        assert!(lcd.take_stat_interrupt());
+
+        /* The next frame runs the usual 80 cycles of OAM, reaches VRAM and
+         * then gets to vblank a whole frame's worth of lines later
+         */
+        let mut cycles = 0;
+        while lcd.status.mode == Mode::OAM {
+            lcd.advance_cycles(4);
+            cycles += 4;
+        }
+        assert_eq!(cycles, 80);
+        assert_eq!(lcd.status.mode, Mode::VRAM);
+
+        while lcd.status.mode != Mode::VBlank {
+            lcd.advance_cycles(4);
+            cycles += 4;
+        }
+        assert_eq!(cycles, 144 * 456);
+    }
+
+    /* An lcd with the display turned on, as the boot rom leaves it */
+    fn enabled_lcd() -> LCD {
+        let mut lcd = LCD::new();
+        lcd.set(0x0040, 0x80);
+        lcd
+    }
+
+    /* Runs the lcd until the next mode transition */
+    fn next_transition(lcd: &mut LCD) -> (Mode, Mode) {
+        loop {
+            if let Some(transition) = lcd.advance_cycles(4) {
+                return transition;
+            }
+        }
+    }
+
+    #[test]
+    fn test_hblank_stat_interrupt() {
+        let mut lcd = enabled_lcd();
+        lcd.status.hblank_interrupt = true;
+
+        assert_eq!(next_transition(&mut lcd), (Mode::OAM, Mode::VRAM));
+        assert!(!lcd.take_stat_interrupt());
+
+        assert_eq!(next_transition(&mut lcd), (Mode::VRAM, Mode::HBlank));
+        assert!(lcd.take_stat_interrupt());
+
+        /* OAM interrupts aren't enabled */
+        assert_eq!(next_transition(&mut lcd), (Mode::HBlank, Mode::OAM));
+        assert!(!lcd.take_stat_interrupt());
+    }
+
+    #[test]
+    fn test_coincidence_stat_interrupt() {
+        let mut lcd = enabled_lcd();
+        lcd.set(0x0045, 2);
+        lcd.status.ly_coincidence_interrupt = true;
+
+        while lcd.lines < 2 {
+            assert!(!lcd.status.coincidence);
+            assert!(!lcd.take_stat_interrupt());
+            lcd.advance_cycles(4);
+        }
+
+        assert!(lcd.status.coincidence);
+        assert!(lcd.take_stat_interrupt());
+
+        while lcd.lines < 3 {
+            lcd.advance_cycles(4);
+        }
+        assert!(!lcd.status.coincidence);
+    }
+
+    #[test]
+    fn test_ly_tracks_lines() {
+        let mut lcd = enabled_lcd();
+
+        for line in 0..154u32 {
+            assert_eq!(lcd.get(0x0044), line as u8);
+            for _ in 0..(456 / 4) {
+                lcd.advance_cycles(4);
+            }
+        }
+
+        /* Back to the top after the last vblank line */
+        assert_eq!(lcd.get(0x0044), 0);
+    }
+
+    #[test]
+    fn test_display_disabled() {
+        let mut lcd = enabled_lcd();
+        next_transition(&mut lcd);
+        while lcd.lines < 10 {
+            lcd.advance_cycles(4);
+        }
+
+        lcd.set(0x0040, 0x00);
+        assert_eq!(lcd.get(0x0044), 0);
+
+        for _ in 0..(70224 / 4) {
+            assert_eq!(lcd.advance_cycles(4), None);
+        }
+        assert_eq!(lcd.get(0x0044), 0);
+
+        /* Turning it back on starts from the top of a frame */
+        lcd.set(0x0040, 0x80);
+        assert_eq!(next_transition(&mut lcd), (Mode::OAM, Mode::VRAM));
+        assert_eq!(lcd.get(0x0044), 0);
+    }
+
+    #[test]
+    fn test_lyc_write_coincidence() {
+        let mut lcd = LCD::new();
+        lcd.lines = 5;
+
+        lcd.set(0x0045, 5);
+        assert_eq!(lcd.get(0x0041) & 0x04, 0x04);
+
+        lcd.set(0x0045, 6);
+        assert_eq!(lcd.get(0x0041) & 0x04, 0x00);
+    }
+
+    #[test]
+    fn test_palette_log() {
+        let mut lcd = LCD::new();
+        lcd.set(0x0047, 0xE4);
+        assert!(lcd.log.is_none());
+
+        lcd.log = Some(Vec::new());
+        lcd.set(0x0047, 0xE4);
+
+        assert_eq!(
+            lcd.log.unwrap(),
+            vec!["BGP: E4 White LightGrey DarkGrey Black".to_string()],
+        );
+    }
+}
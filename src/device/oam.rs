@@ -0,0 +1,181 @@
+use std::io::Error;
+
+use crate::bytes;
+use crate::device::Device;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/* Object Attribute Memory
+ *
+ * 0xFE00 - 0xFE9F holds 40 sprites of 4 bytes each
+ *
+ * Byte 0 - Y position on screen plus 16
+ * Byte 1 - X position on screen plus 8
+ * Byte 2 - Tile index
+ * Byte 3 - Flags
+ */
+
+pub const SPRITE_COUNT: usize = 40;
+
+/* How many sprites the OAM search will select for a single line, hardware
+ * draws at most 10 but it can be useful to raise that when debugging.
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SpriteLimit {
+    Limited(usize),
+    Unlimited,
+}
+
+impl SpriteLimit {
+    pub fn hardware() -> SpriteLimit {
+        SpriteLimit::Limited(10)
+    }
+}
+
+/* A single OAM entry
+ *
+ * Flags
+ *   bit 7: drawn behind background colors 1-3
+ *   bit 6: flipped vertically
+ *   bit 5: flipped horizontally
+ *   bit 4: uses object_palette_1 rather than object_palette_0
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+    pub priority: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub palette: bool,
+}
+
+impl std::convert::From<[u8; 4]> for Sprite {
+    fn from(entry: [u8; 4]) -> Self {
+        let [y, x, tile, flags] = entry;
+
+        Sprite {
+            y,
+            x,
+            tile,
+            flags,
+            priority: bytes::check_bit(flags, 7),
+            y_flip: bytes::check_bit(flags, 6),
+            x_flip: bytes::check_bit(flags, 5),
+            palette: bytes::check_bit(flags, 4),
+        }
+    }
+}
+
+pub struct Oam {
+    storage: [u8; SPRITE_COUNT * 4],
+}
+
+impl Oam {
+    pub fn new() -> Oam {
+        Oam {
+            storage: [0; SPRITE_COUNT * 4],
+        }
+    }
+
+    pub fn sprite(&self, i: usize) -> Sprite {
+        let start = i * 4;
+        Sprite::from([
+            self.storage[start],
+            self.storage[start + 1],
+            self.storage[start + 2],
+            self.storage[start + 3],
+        ])
+    }
+
+    /* Scans OAM in order for the sprites that cover the given line
+     * returning their indexes, stopping once the limit is reached.
+     */
+    pub fn line_sprites(&self, line: u8, height: u8, limit: SpriteLimit) -> Vec<usize> {
+        let max = match limit {
+            SpriteLimit::Limited(n) => n,
+            SpriteLimit::Unlimited => SPRITE_COUNT,
+        };
+
+        let line = line as u16 + 16;
+
+        (0..SPRITE_COUNT)
+            .filter(|i| {
+                let y = self.storage[i * 4] as u16;
+                line >= y && line < y + height as u16
+            })
+            .take(max)
+            .collect()
+    }
+}
+
+impl Default for Oam {
+    fn default() -> Oam {
+        Oam::new()
+    }
+}
+
+impl Device for Oam {
+    fn get(&self, address: u16) -> u8 {
+        self.storage[address as usize]
+    }
+
+    fn set(&mut self, address: u16, value: u8) {
+        self.storage[address as usize] = value;
+    }
+}
+
+impl SaveState for Oam {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.storage);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        r.bytes_into(&mut self.storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sprite() {
+        let mut oam = Oam::new();
+        for (i, b) in [0x20, 0x18, 0x05, 0x00, 0x30, 0x28, 0x06, 0xF0].iter().enumerate() {
+            oam.set(4 + i as u16, *b);
+        }
+
+        assert_eq!(oam.sprite(1), Sprite {
+            y: 0x20,
+            x: 0x18,
+            tile: 0x05,
+            flags: 0x00,
+            priority: false,
+            y_flip: false,
+            x_flip: false,
+            palette: false,
+        });
+
+        let sprite = oam.sprite(2);
+        assert_eq!((sprite.y, sprite.x, sprite.tile, sprite.flags), (0x30, 0x28, 0x06, 0xF0));
+        assert!(sprite.priority && sprite.y_flip && sprite.x_flip && sprite.palette);
+
+        assert_eq!(oam.sprite(0), Sprite::from([0; 4]));
+    }
+
+    #[test]
+    fn test_sprite_flag_bits() {
+        let flag = |flags| Sprite::from([0, 0, 0, flags]);
+
+        assert!(flag(0x80).priority);
+        assert!(flag(0x40).y_flip);
+        assert!(flag(0x20).x_flip);
+        assert!(flag(0x10).palette);
+
+        /* The low bits are CGB only */
+        let sprite = flag(0x0F);
+        assert!(!sprite.priority && !sprite.y_flip && !sprite.x_flip && !sprite.palette);
+    }
+}
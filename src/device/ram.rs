@@ -1,4 +1,7 @@
+use std::io::Error;
+
 use crate::device::Device;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 pub struct Ram2k {
     storage: [u8;2048]
@@ -12,12 +15,20 @@ impl Ram2k {
     }
 }
 
+impl Default for Ram2k {
+    fn default() -> Ram2k {
+        Ram2k::new()
+    }
+}
+
 impl Device for Ram2k {
     fn get(&self, address: u16) -> u8 {
+        debug_assert!((address as usize) < self.storage.len(), "Ram2k address out of range: {:X}", address);
         self.storage[address as usize]
     }
 
     fn set(&mut self, address: u16, value: u8) {
+        debug_assert!((address as usize) < self.storage.len(), "Ram2k address out of range: {:X}", address);
         self.storage[address as usize] = value;
     }
 }
@@ -35,12 +46,20 @@ impl Ram8k {
     }
 }
 
+impl Default for Ram8k {
+    fn default() -> Ram8k {
+        Ram8k::new()
+    }
+}
+
 impl Device for Ram8k {
     fn get(&self, address: u16) -> u8 {
+        debug_assert!((address as usize) < self.storage.len(), "Ram8k address out of range: {:X}", address);
         self.storage[address as usize]
     }
 
     fn set(&mut self, address: u16, value: u8) {
+        debug_assert!((address as usize) < self.storage.len(), "Ram8k address out of range: {:X}", address);
         self.storage[address as usize] = value;
     }
 }
@@ -57,12 +76,50 @@ impl HighRam {
     }
 }
 
+impl Default for HighRam {
+    fn default() -> HighRam {
+        HighRam::new()
+    }
+}
+
 impl Device for HighRam {
     fn get(&self, address: u16) -> u8 {
+        debug_assert!((address as usize) < self.storage.len(), "HighRam address out of range: {:X}", address);
         self.storage[address as usize]
     }
 
     fn set(&mut self, address: u16, value: u8) {
+        debug_assert!((address as usize) < self.storage.len(), "HighRam address out of range: {:X}", address);
         self.storage[address as usize] = value;
     }
 }
+
+impl SaveState for Ram2k {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.storage);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        r.bytes_into(&mut self.storage)
+    }
+}
+
+impl SaveState for Ram8k {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.storage);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        r.bytes_into(&mut self.storage)
+    }
+}
+
+impl SaveState for HighRam {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.storage);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        r.bytes_into(&mut self.storage)
+    }
+}
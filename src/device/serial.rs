@@ -0,0 +1,288 @@
+use std::io::Error;
+
+use crate::bytes;
+use crate::device::Device;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+// 0xFF01 = SB, serial transfer data
+// 0xFF02 = SC, serial transfer control
+//
+// SC bit 7 starts a transfer and stays set until it completes, bit 0 picks
+// the clock. With the internal clock we shift one bit every 512 cycles
+// (8192Hz), with the external clock nothing happens until a peer drives the
+// transfer.
+
+/* Cycles per bit at the 8192Hz internal serial clock */
+pub const BIT_CYCLES: u16 = 512;
+
+/* The other end of the link cable. When we drive the clock the peer is
+ * handed our byte as the transfer starts and returns the byte it shifts
+ * back to us.
+ */
+pub trait SerialLink {
+    fn transfer(&mut self, outgoing: u8) -> u8;
+}
+
+pub struct Serial {
+    pub data: u8,
+    pub control: u8,
+
+    /* Bytes sent out over the link, only collected when enabled. Test roms
+     * report their results this way.
+     */
+    pub output: Option<Vec<u8>>,
+
+    link: Option<Box<dyn SerialLink>>,
+
+    /* Bits still to be shifted in for the current transfer */
+    incoming: u8,
+
+    bit_clock: u16,
+    bits: u8,
+
+    /* An externally clocked transfer finished, reported by the next
+     * advance_cycles
+     */
+    interrupt: bool,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            output: None,
+            link: None,
+            incoming: 0xFF,
+            bit_clock: 0,
+            bits: 0,
+            interrupt: false,
+        }
+    }
+
+    pub fn connect(&mut self, link: Box<dyn SerialLink>) {
+        self.link = Some(link);
+    }
+
+    pub fn transferring(&self) -> bool {
+        bytes::check_bit(self.control, 7)
+    }
+
+    pub fn internal_clock(&self) -> bool {
+        bytes::check_bit(self.control, 0)
+    }
+
+    /* Shifts out internally clocked transfers, with no peer attached the
+     * bits shifted in are all 1s. Returns true when a transfer completes
+     * and the serial interrupt should be raised, externally clocked ones
+     * included.
+     */
+    pub fn advance_cycles(&mut self, n: u8) -> bool {
+        if std::mem::take(&mut self.interrupt) {
+            return true
+        }
+
+        if !self.transferring() || !self.internal_clock() {
+            return false
+        }
+
+        self.bit_clock += n as u16;
+
+        while self.bit_clock >= BIT_CYCLES {
+            self.bit_clock -= BIT_CYCLES;
+            self.data = (self.data << 1) | (self.incoming >> 7);
+            self.incoming <<= 1;
+            self.bits += 1;
+
+            if self.bits == 8 {
+                self.finish();
+                return true
+            }
+        }
+
+        false
+    }
+
+    /* Completes a pending externally clocked transfer, the peer hands us
+     * its byte and gets ours back. The serial interrupt is raised on the
+     * next advance_cycles. Returns None if we aren't waiting on an external
+     * clock.
+     */
+    pub fn external_clock(&mut self, incoming: u8) -> Option<u8> {
+        if !self.transferring() || self.internal_clock() {
+            return None
+        }
+
+        let outgoing = self.data;
+        self.data = incoming;
+        self.finish();
+        self.interrupt = true;
+        Some(outgoing)
+    }
+
+    fn finish(&mut self) {
+        self.control = bytes::set_bit(self.control, 7, false);
+        self.bit_clock = 0;
+        self.bits = 0;
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+impl Device for Serial {
+    fn get(&self, address: u16) -> u8 {
+        match address {
+            0x0001 => self.data,
+            // unused bits read back as 1
+            0x0002 => self.control | 0x7E,
+            _ => panic!("invalid serial address: {:X}", address),
+        }
+    }
+
+    fn set(&mut self, address: u16, v: u8) {
+        match address {
+            0x0001 => self.data = v,
+            0x0002 => {
+                self.control = v & 0x81;
+                self.bit_clock = 0;
+                self.bits = 0;
+
+                if self.transferring() {
+                    if let Some(output) = self.output.as_mut() {
+                        output.push(self.data);
+                    }
+
+                    if self.internal_clock() {
+                        self.incoming = match self.link.as_mut() {
+                            Some(link) => link.transfer(self.data),
+                            None => 0xFF,
+                        };
+                    }
+                }
+            },
+            _ => panic!("invalid serial address: {:X}", address),
+        }
+    }
+}
+
+/* The link and captured output belong to the session rather than the
+ * machine so they're left alone.
+ */
+impl SaveState for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u8(self.incoming);
+        w.u16(self.bit_clock);
+        w.u8(self.bits);
+        w.bool(self.interrupt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.data = r.u8()?;
+        self.control = r.u8()?;
+        self.incoming = r.u8()?;
+        self.bit_clock = r.u16()?;
+        self.bits = r.u8()?;
+        self.interrupt = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /* Records what it's sent and always answers with the same byte */
+    struct Peer {
+        received: Rc<RefCell<Vec<u8>>>,
+        reply: u8,
+    }
+
+    impl SerialLink for Peer {
+        fn transfer(&mut self, outgoing: u8) -> u8 {
+            self.received.borrow_mut().push(outgoing);
+            self.reply
+        }
+    }
+
+    #[test]
+    fn test_link_transfer() {
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let mut serial = Serial::new();
+        serial.connect(Box::new(Peer { received: received.clone(), reply: 0x5A }));
+
+        serial.set(0x0001, 0x42);
+        serial.set(0x0002, 0x81);
+        assert_eq!(*received.borrow(), vec![0x42]);
+
+        let mut interrupt = false;
+        for _ in 0..(8 * BIT_CYCLES / 4) {
+            interrupt = serial.advance_cycles(4);
+        }
+
+        assert!(interrupt);
+        assert_eq!(serial.get(0x0001), 0x5A);
+    }
+
+    #[test]
+    fn test_internal_transfer_timing() {
+        let mut serial = Serial::new();
+        serial.set(0x0001, 0x42);
+        serial.set(0x0002, 0x81);
+
+        /* 8 bits at 512 cycles each, 4 cycles at a time */
+        for _ in 0..(8 * BIT_CYCLES / 4 - 1) {
+            assert!(!serial.advance_cycles(4));
+        }
+        assert!(serial.transferring());
+
+        assert!(serial.advance_cycles(4));
+        assert!(!serial.transferring());
+        assert_eq!(serial.get(0x0001), 0xFF);
+        assert_eq!(serial.get(0x0002), 0x7F);
+    }
+
+    #[test]
+    fn test_output_capture() {
+        let mut serial = Serial::new();
+        serial.set(0x0001, 0x41);
+        serial.set(0x0002, 0x81);
+        assert!(serial.output.is_none());
+
+        serial.output = Some(Vec::new());
+        serial.set(0x0001, 0x42);
+        serial.set(0x0002, 0x81);
+        serial.set(0x0001, 0x43);
+        serial.set(0x0002, 0x01);
+
+        assert_eq!(serial.output, Some(vec![0x42]));
+    }
+
+    #[test]
+    fn test_external_transfer_waits() {
+        let mut serial = Serial::new();
+        serial.set(0x0001, 0x42);
+        serial.set(0x0002, 0x80);
+
+        for _ in 0..10_000 {
+            assert!(!serial.advance_cycles(4));
+        }
+        assert!(serial.transferring());
+
+        assert_eq!(serial.external_clock(0x24), Some(0x42));
+        assert!(!serial.transferring());
+        assert_eq!(serial.get(0x0001), 0x24);
+        assert_eq!(serial.external_clock(0x24), None);
+
+        /* The interrupt fires once on the next advance */
+        assert!(serial.advance_cycles(4));
+        assert!(!serial.advance_cycles(4));
+    }
+}
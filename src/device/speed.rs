@@ -0,0 +1,102 @@
+use std::io::Error;
+
+use crate::bytes;
+use crate::device::Device;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/* KEY1, 0xFF4D, the CGB speed switch
+ *
+ *   bit 7: the current speed, 1 for double (read only)
+ *   bit 0: prepare a switch, the next STOP toggles the speed
+ *
+ * At double speed the cpu, timer, serial and DMA run twice as fast while
+ * the LCD and audio keep their normal rate, so they see half the cycles the
+ * cpu does.
+ */
+pub struct Speed {
+    pub double: bool,
+    pub armed: bool,
+}
+
+impl Speed {
+    pub fn new() -> Speed {
+        Speed {
+            double: false,
+            armed: false,
+        }
+    }
+
+    /* Called on STOP, switches speed if a switch was prepared and returns
+     * whether it did.
+     */
+    pub fn switch(&mut self) -> bool {
+        if !self.armed {
+            return false
+        }
+
+        self.double = !self.double;
+        self.armed = false;
+        true
+    }
+
+    /* How many cpu cycles pass per LCD cycle */
+    pub fn multiplier(&self) -> u8 {
+        if self.double { 2 } else { 1 }
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Speed {
+        Speed::new()
+    }
+}
+
+impl Device for Speed {
+    fn get(&self, _address: u16) -> u8 {
+        /* The unused bits read as 1 */
+        let value = bytes::set_bit(0x7E, 7, self.double);
+        bytes::set_bit(value, 0, self.armed)
+    }
+
+    fn set(&mut self, _address: u16, value: u8) {
+        self.armed = bytes::check_bit(value, 0);
+    }
+}
+
+impl SaveState for Speed {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.double);
+        w.bool(self.armed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.double = r.bool()?;
+        self.armed = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_switch() {
+        let mut speed = Speed::new();
+        assert_eq!(speed.get(0), 0x7E);
+
+        /* STOP without preparing a switch does nothing */
+        assert!(!speed.switch());
+
+        speed.set(0, 0x01);
+        assert_eq!(speed.get(0), 0x7F);
+        assert!(speed.switch());
+        assert_eq!(speed.get(0), 0xFE);
+        assert_eq!(speed.multiplier(), 2);
+
+        speed.set(0, 0x01);
+        assert!(speed.switch());
+        assert_eq!(speed.get(0), 0x7E);
+        assert_eq!(speed.multiplier(), 1);
+    }
+}
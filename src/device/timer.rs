@@ -0,0 +1,344 @@
+use std::io::Error;
+
+use crate::bytes;
+use crate::device::Device;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+// 0xFF04 = divider
+// 0xFF05 = tima
+// 0xFF06 = tma
+// 0xFF07 = tac
+
+/* DIV is the top byte of a 16 bit counter that runs at the cpu clock. TIMA
+ * doesn't keep its own count, it ticks on the falling edge of one of the
+ * counter's bits, picked by the frequency, ANDed with the enable bit. So
+ * anything that drops that signal ticks TIMA, resetting the counter with a
+ * DIV write while the bit is set, or a TAC write that disables the timer or
+ * moves it to a bit that's clear.
+ */
+
+#[derive(Debug, Clone, Copy)]
+pub enum Frequency {
+    F1024 = 1024,
+    F16   = 16,
+    F64   = 64,
+    F256  = 256,
+}
+
+impl Frequency {
+    /* The counter bit whose falling edge ticks TIMA, half the period */
+    fn bit(&self) -> u8 {
+        match self {
+            Frequency::F1024 => 9,
+            Frequency::F16 => 3,
+            Frequency::F64 => 5,
+            Frequency::F256 => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TimerControl {
+    enabled: bool,
+    frequency: Frequency,
+}
+
+impl TimerControl {
+    pub fn new() -> TimerControl {
+        TimerControl {
+            enabled: false,
+            frequency: Frequency::F1024,
+        }
+    }
+
+    /* The signal TIMA watches for a falling edge */
+    fn signal(&self, clock: u16) -> bool {
+        self.enabled && clock & (1 << self.frequency.bit()) != 0
+    }
+}
+
+impl Default for TimerControl {
+    fn default() -> TimerControl {
+        TimerControl::new()
+    }
+}
+
+impl std::convert::From<u8> for TimerControl {
+    fn from(byte: u8) -> Self {
+        let f = match (bytes::check_bit(byte, 1), bytes::check_bit(byte, 0)) {
+            (false, false) => Frequency::F1024,
+            (false, true)  => Frequency::F16,
+            (true, false)  => Frequency::F64,
+            (true, true)   => Frequency::F256,
+        };
+
+        TimerControl {
+            enabled: bytes::check_bit(byte, 2),
+            frequency: f,
+        }
+    }
+}
+
+impl std::convert::From<TimerControl> for u8 {
+    fn from(t: TimerControl) -> Self {
+        let mut u:u8 = 0;
+
+        u = match t.frequency {
+            Frequency::F1024 => u,
+            Frequency::F16 => u | 0b0000_00001,
+            Frequency::F64 => u | 0b0000_00010,
+            Frequency::F256 => u | 0b0000_00011,
+        };
+
+        u = bytes::set_bit(u, 2, t.enabled);
+
+        u
+    }
+}
+
+pub struct Timer {
+    pub clock: u16,
+    pub tma: u8,
+    pub tima: u8,
+    pub tac: TimerControl,
+
+    /* A write ticked TIMA over, reported by the next advance_cycles */
+    pub overflowed: bool,
+}
+
+impl Timer {
+    /* Returns true if TIMA overflowed, it's reloaded from TMA and the
+     * timer interrupt should be raised.
+     */
+    pub fn advance_cycles(&mut self, n: u8) -> bool {
+        let mut overflowed = std::mem::take(&mut self.overflowed);
+        let mut remaining = n;
+
+        /* A machine cycle at a time, the fastest bit falls every 16 cycles
+         * so no edge is skipped
+         */
+        while remaining > 0 {
+            let step = remaining.min(4);
+            remaining -= step;
+
+            let before = self.tac.signal(self.clock);
+            self.clock = self.clock.wrapping_add(step as u16);
+
+            if before && !self.tac.signal(self.clock) {
+                overflowed |= self.tick();
+            }
+        }
+
+        overflowed
+    }
+
+    /* Increments TIMA, returns true when it overflowed */
+    fn tick(&mut self) -> bool {
+        let (v, overflow) = self.tima.overflowing_add(1);
+        self.tima = if overflow { self.tma } else { v };
+        overflow
+    }
+
+    /* Writes that drop the signal tick TIMA like the counter would */
+    fn update_signal(&mut self, clock: u16, tac: TimerControl) {
+        let before = self.tac.signal(self.clock);
+
+        self.clock = clock;
+        self.tac = tac;
+
+        if before && !self.tac.signal(self.clock) {
+            self.overflowed |= self.tick();
+        }
+    }
+
+    pub fn get_div(&self, ) -> u8 {
+        (self.clock >> 8) as u8
+    }
+
+    pub fn new() -> Timer {
+        Timer {
+            clock: 0,
+            tma: 0,
+            tima: 0,
+            tac: TimerControl::new(),
+            overflowed: false,
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new()
+    }
+}
+
+impl Device for Timer {
+    fn get(&self, address: u16) -> u8 {
+        match address {
+            0x0004 => self.get_div(),
+            0x0005 => self.tima,
+            0x0006 => self.tma,
+            0x0007 => u8::from(self.tac),
+            _ => panic!("invalid timer address: {:X}", address),
+        }
+    }
+
+    fn set(&mut self, address: u16, v: u8) {
+        match address {
+            // Any write to the divider resets it
+            0x0004 => self.update_signal(0, self.tac),
+            0x0005 => self.tima = v,
+            0x0006 => self.tma = v,
+            0x0007 => self.update_signal(self.clock, TimerControl::from(v)),
+            _ => panic!("invalid timer address: {:X}", address),
+        }
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.clock);
+        w.u8(self.tma);
+        w.u8(self.tima);
+        w.u8(u8::from(self.tac));
+        w.bool(self.overflowed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.clock = r.u16()?;
+        self.tma = r.u8()?;
+        self.tima = r.u8()?;
+        self.tac = TimerControl::from(r.u8()?);
+        self.overflowed = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(timer: &mut Timer, cycles: u32) -> bool {
+        let mut overflowed = false;
+        for _ in 0..(cycles / 4) {
+            overflowed |= timer.advance_cycles(4);
+        }
+        overflowed
+    }
+
+    #[test]
+    fn test_frequencies() {
+        for (tac, period) in [(0x04, 1024), (0x05, 16), (0x06, 64), (0x07, 256)].iter() {
+            let mut timer = Timer::new();
+            timer.set(0x0007, *tac);
+            assert_eq!(timer.get(0x0007), *tac);
+
+            run(&mut timer, period - 4);
+            assert_eq!(timer.tima, 0, "tac {:02X}", tac);
+
+            run(&mut timer, 4);
+            assert_eq!(timer.tima, 1, "tac {:02X}", tac);
+
+            run(&mut timer, period * 10);
+            assert_eq!(timer.tima, 11, "tac {:02X}", tac);
+        }
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut timer = Timer::new();
+        timer.set(0x0007, 0x01);
+
+        run(&mut timer, 1024);
+        assert_eq!(timer.tima, 0);
+        assert_eq!(timer.get(0x0004), 4);
+    }
+
+    #[test]
+    fn test_overflow_reloads() {
+        let mut timer = Timer::new();
+        timer.set(0x0005, 0xFE);
+        timer.set(0x0006, 0x42);
+        timer.set(0x0007, 0x05);
+
+        assert!(!run(&mut timer, 16));
+        assert_eq!(timer.tima, 0xFF);
+
+        assert!(run(&mut timer, 16));
+        assert_eq!(timer.tima, 0x42);
+
+        assert!(!run(&mut timer, 16));
+        assert_eq!(timer.tima, 0x43);
+    }
+
+    #[test]
+    fn test_div_reset() {
+        let mut timer = Timer::new();
+        run(&mut timer, 0x300);
+        assert_eq!(timer.get(0x0004), 3);
+
+        timer.set(0x0004, 0x99);
+        assert_eq!(timer.get(0x0004), 0);
+    }
+
+    #[test]
+    fn test_div_write_ticks_tima() {
+        let mut timer = Timer::new();
+        timer.set(0x0007, 0x05);
+
+        /* Bit 3 is clear, resetting the counter doesn't change the signal */
+        run(&mut timer, 4);
+        timer.set(0x0004, 0);
+        assert_eq!(timer.tima, 0);
+
+        /* Bit 3 is set, resetting the counter is a falling edge */
+        run(&mut timer, 8);
+        timer.set(0x0004, 0);
+        assert_eq!(timer.tima, 1);
+
+        /* And the count starts over from the reset */
+        run(&mut timer, 12);
+        assert_eq!(timer.tima, 1);
+        run(&mut timer, 4);
+        assert_eq!(timer.tima, 2);
+    }
+
+    #[test]
+    fn test_tac_write_ticks_tima() {
+        let mut timer = Timer::new();
+        timer.set(0x0007, 0x05);
+        run(&mut timer, 8);
+
+        /* Bit 9 is clear at 8 cycles so moving to 1024 is a falling edge */
+        timer.set(0x0007, 0x04);
+        assert_eq!(timer.tima, 1);
+
+        /* Disabling the timer with the bit set drops the signal too */
+        timer.set(0x0007, 0x05);
+        run(&mut timer, 16);
+        assert_eq!(timer.tima, 2);
+        timer.set(0x0007, 0x01);
+        assert_eq!(timer.tima, 3);
+
+        /* Without the bit set there's nothing to fall from */
+        timer.set(0x0007, 0x05);
+        run(&mut timer, 12);
+        assert_eq!(timer.tima, 4);
+        timer.set(0x0007, 0x01);
+        assert_eq!(timer.tima, 4);
+    }
+
+    #[test]
+    fn test_write_overflow_raises_interrupt() {
+        let mut timer = Timer::new();
+        timer.set(0x0005, 0xFF);
+        timer.set(0x0006, 0x42);
+        timer.set(0x0007, 0x05);
+        run(&mut timer, 8);
+
+        timer.set(0x0004, 0);
+        assert_eq!(timer.tima, 0x42);
+        assert!(timer.advance_cycles(4));
+        assert!(!timer.advance_cycles(4));
+    }
+}
@@ -0,0 +1,93 @@
+use crate::mmu::MMU;
+use crate::device::oam::SPRITE_COUNT;
+
+/* PPU state as JSON for external viewers
+ *
+ * {
+ *   "tiles": [[[0-3; 8]; 8]; 384],   color indexes of each tile in vram
+ *   "tile_maps": [[u8; 1024]; 2],    0x9800 and 0x9C00
+ *   "oam": [{"y", "x", "tile", "flags"}; 40],
+ *   "palettes": {"bgp", "obp0", "obp1"}
+ * }
+ *
+ * There's no json library in the tree and the shape is fixed, so it's
+ * written out by hand.
+ */
+pub fn ppu_json(mmu: &MMU) -> String {
+    let tiles: Vec<String> = mmu.gpu.vram.tile_set.iter()
+        .map(|tile| {
+            let rows: Vec<String> = tile.data.iter()
+                .map(|row| list(row.iter().map(|p| (*p as u8).to_string())))
+                .collect();
+            list(rows.into_iter())
+        })
+        .collect();
+
+    let tile_maps: Vec<String> = (0..2)
+        .map(|bank| {
+            list(
+                (0..32u8)
+                    .flat_map(|y| (0..32u8).map(move |x| (y, x)))
+                    .map(|(y, x)| mmu.gpu.tile_map.map(y, x, bank == 1).to_string())
+            )
+        })
+        .collect();
+
+    let oam: Vec<String> = (0..SPRITE_COUNT)
+        .map(|i| {
+            let sprite = mmu.oam.sprite(i);
+            format!("{{\"y\":{},\"x\":{},\"tile\":{},\"flags\":{}}}", sprite.y, sprite.x, sprite.tile, sprite.flags)
+        })
+        .collect();
+
+    format!(
+        "{{\"tiles\":{},\"tile_maps\":{},\"oam\":{},\"palettes\":{{\"bgp\":{},\"obp0\":{},\"obp1\":{}}}}}",
+        list(tiles.into_iter()),
+        list(tile_maps.into_iter()),
+        list(oam.into_iter()),
+        u8::from(mmu.lcd.bg_palette),
+        u8::from(mmu.lcd.object_palette_0),
+        u8::from(mmu.lcd.object_palette_1),
+    )
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<String>>().join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::BootRom;
+    use crate::cartridge::Cartridge;
+
+    #[test]
+    fn test_ppu_json() {
+        let mut mmu = MMU::new(BootRom::zero(), Cartridge::zero());
+
+        /* First row of tile 1 is all color 1 */
+        mmu.set(0x8010, 0xFF);
+        mmu.set(0x8011, 0x00);
+
+        /* Map tile 1 into the top left of the first map */
+        mmu.set(0x9800, 0x01);
+
+        mmu.set(0xFE00, 16);
+        mmu.set(0xFE01, 8);
+        mmu.set(0xFE02, 1);
+        mmu.set(0xFE03, 0x20);
+
+        mmu.set(0xFF47, 0xE4);
+
+        let json = ppu_json(&mmu);
+
+        let zero_row = "[0,0,0,0,0,0,0,0]";
+        let tile_0 = format!("[{}]", [zero_row; 8].join(","));
+        let tile_1 = format!("[[1,1,1,1,1,1,1,1],{}]", [zero_row; 7].join(","));
+        assert!(json.starts_with(&format!("{{\"tiles\":[{},{},", tile_0, tile_1)));
+
+        assert!(json.contains("\"tile_maps\":[[1,0,0,"));
+        assert!(json.contains("\"oam\":[{\"y\":16,\"x\":8,\"tile\":1,\"flags\":32},{\"y\":0,"));
+        assert!(json.ends_with("\"palettes\":{\"bgp\":228,\"obp0\":0,\"obp1\":0}}"));
+    }
+}
@@ -1,7 +1,143 @@
+use std::fs;
+use std::io::Error;
+
 use crate::shade::Shade;
+use crate::config::ShadeColors;
+use crate::color::{self, ColorCorrection};
+use crate::png;
 
 pub type Buffer = [[Shade;160];144];
 
+/* The screen of a CGB cart, RGB555 per pixel */
+pub type ColorBuffer = [[u16;160];144];
+
 pub fn new() -> Buffer {
     [[Shade::White;160];144]
 }
+
+pub fn new_colors() -> ColorBuffer {
+    [[0x7FFF;160];144]
+}
+
+/* The screen as a PNG, each shade drawn in its configured color */
+pub fn encode_png(buffer: &Buffer, colors: &ShadeColors) -> Vec<u8> {
+    let rgba: Vec<u8> = buffer.iter()
+        .flat_map(|row| row.iter())
+        .flat_map(|shade| colors.rgba(*shade).to_vec())
+        .collect();
+
+    png::encode(160, 144, &rgba)
+}
+
+pub fn write_png(path: &str, buffer: &Buffer, colors: &ShadeColors) -> Result<(), Error> {
+    fs::write(path, encode_png(buffer, colors))
+}
+
+/* A CGB screen as a PNG */
+pub fn encode_color_png(buffer: &ColorBuffer, correction: ColorCorrection) -> Vec<u8> {
+    let rgba: Vec<u8> = buffer.iter()
+        .flat_map(|row| row.iter())
+        .flat_map(|c| {
+            let (r, g, b) = color::rgb555_to_rgb888(*c, correction);
+            vec![r, g, b, 255]
+        })
+        .collect();
+
+    png::encode(160, 144, &rgba)
+}
+
+pub fn write_color_png(path: &str, buffer: &ColorBuffer, correction: ColorCorrection) -> Result<(), Error> {
+    fs::write(path, encode_color_png(buffer, correction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Reads back what png::encode writes, stored deflate blocks only */
+    fn decode(bytes: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(&bytes[1..4], b"PNG");
+
+        let mut position = 8;
+        let mut size = (0, 0);
+        let mut zlib = Vec::new();
+
+        while position < bytes.len() {
+            let len = u32::from_be_bytes([bytes[position], bytes[position + 1], bytes[position + 2], bytes[position + 3]]) as usize;
+            let kind = &bytes[position + 4..position + 8];
+            let data = &bytes[position + 8..position + 8 + len];
+            let crc = &bytes[position + 8 + len..position + 12 + len];
+            assert_eq!(png::crc32(&bytes[position + 4..position + 8 + len]).to_be_bytes(), crc);
+
+            match kind {
+                b"IHDR" => {
+                    size = (
+                        u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                        u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+                    );
+                    assert_eq!(&data[8..], [8, 6, 0, 0, 0]);
+                },
+                b"IDAT" => zlib.extend_from_slice(data),
+                _ => {},
+            }
+            position += len + 12;
+        }
+
+        let mut raw = Vec::new();
+        let mut i = 2;
+        loop {
+            let last = zlib[i] & 1 == 1;
+            let len = u16::from_le_bytes([zlib[i + 1], zlib[i + 2]]) as usize;
+            raw.extend_from_slice(&zlib[i + 5..i + 5 + len]);
+            i += 5 + len;
+            if last {
+                break
+            }
+        }
+        assert_eq!(png::adler32(&raw).to_be_bytes(), zlib[i..i + 4]);
+
+        /* Drop the filter byte that starts each row */
+        let pixels = raw.chunks(size.0 as usize * 4 + 1)
+            .flat_map(|row| {
+                assert_eq!(row[0], 0);
+                row[1..].to_vec()
+            })
+            .collect();
+
+        (size.0, size.1, pixels)
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let mut buffer = new();
+        for (y, row) in buffer.iter_mut().enumerate() {
+            for (x, shade) in row.iter_mut().enumerate() {
+                *shade = [Shade::White, Shade::LightGrey, Shade::DarkGrey, Shade::Black][(x + y) % 4];
+            }
+        }
+
+        let mut colors = ShadeColors::greyscale();
+        colors.set(Shade::DarkGrey, [0x34, 0x68, 0x56, 255]);
+
+        let (width, height, pixels) = decode(&encode_png(&buffer, &colors));
+        assert_eq!((width, height), (160, 144));
+        assert_eq!(pixels.len(), 160 * 144 * 4);
+
+        for (y, row) in buffer.iter().enumerate() {
+            for (x, shade) in row.iter().enumerate() {
+                let offset = (y * 160 + x) * 4;
+                assert_eq!(pixels[offset..offset + 4], colors.rgba(*shade), "pixel {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_color_png() {
+        let mut buffer = new_colors();
+        buffer[0][1] = 0x001F;
+
+        let (_, _, pixels) = decode(&encode_color_png(&buffer, ColorCorrection::None));
+        assert_eq!(pixels[0..4], [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(pixels[4..8], [0xFF, 0x00, 0x00, 0xFF]);
+    }
+}
@@ -1,55 +1,811 @@
 use std::io::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use crate::rom::BootRom;
+use crate::rom::{BootRom, BootRomVariant};
 use crate::mmu::MMU;
 use crate::register::Registers;
 use crate::cpu::CPUManager;
 use crate::cartridge::Cartridge;
-use crate::msg::Frame;
+use crate::msg::{Frame, Input, DebugCommand, DebugOutput};
+use crate::bytes;
+use crate::cheat::Cheat;
+use crate::watcher::RegisterCondition;
+use crate::shade::Shade;
 
-use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::{SyncSender, Sender, Receiver};
+use std::time::Duration;
 
 pub struct Gameboy {
     pub cpu: CPUManager,
-    sender: SyncSender<Box<Frame>>,
+    sender: Option<SyncSender<Box<Frame>>>,
+    input: Option<Receiver<Input>>,
+    audio: Option<Sender<Vec<f32>>>,
+
+    /* Replies to debugger commands */
+    debug_output: Option<Sender<DebugOutput>>,
+    frames: u64,
+
+    /* Game Shark codes, written every vblank */
+    cheats: Vec<Cheat>,
+
+    /* Boot hang detection, see check_boot_hang */
+    boot_hang_limit: Option<u64>,
+    boot_frames: u64,
+    boot_hung: bool,
+
+    /* Where battery backed cartridge ram is persisted */
+    save_path: Option<PathBuf>,
+
+    /* The save state slot written by Input::SaveState */
+    state_path: Option<PathBuf>,
+
+    /* run_headless stops when the pc reaches this address */
+    headless_breakpoint: Option<u16>,
+
+    /* Set when run_headless stops at a mooneye test's result */
+    mooneye_result: Option<bool>,
+
+    /* An Input::SetSpeed waiting to go out with the next frame */
+    speed: Option<f64>,
 }
 
+/* The DMG boot animation takes a little under 3 seconds, anything past ten
+ * is almost certainly a boot rom that will never hand off.
+ */
+pub const DEFAULT_BOOT_HANG_FRAMES: u64 = 600;
+
+/* While paused the emulator waits this long for input before resending the
+ * current frame, which keeps the display responsive without spinning.
+ */
+const PAUSED_INPUT_WAIT: Duration = Duration::from_millis(16);
+
 impl Gameboy {
     pub fn new(
         boot_rom: &str,
+        boot_variant: BootRomVariant,
         game_rom: &str,
         skip_boot: bool,
-        sender: SyncSender<Box<Frame>>,
     ) -> Result<Gameboy, Error> {
         let cartridge = Cartridge::read(game_rom)?;
-        let boot_rom = BootRom::read(boot_rom)?;
+        let boot_rom = BootRom::read(boot_rom, boot_variant)?;
 
-        let mmu = if skip_boot {
-            MMU::skip_boot(cartridge)
-        } else {
-            MMU::new(boot_rom, cartridge)
+        let mut gameboy = Gameboy::from_roms(boot_rom, cartridge, skip_boot);
+        gameboy.set_save_path(Path::new(game_rom).with_extension("sav"))?;
+        gameboy.set_state_path(Path::new(game_rom).with_extension("state"));
+        Ok(gameboy)
+    }
 
+    pub fn from_roms(
+        boot_rom: BootRom,
+        cartridge: Cartridge,
+        skip_boot: bool,
+    ) -> Gameboy {
+        let cpu = if skip_boot {
+            CPUManager::skip_boot(boot_rom.variant, cartridge)
+        } else {
+            CPUManager::new(Registers::new(), MMU::new(boot_rom, cartridge))
         };
 
-        let registers = if skip_boot {
-            Registers::skip_boot()
-        } else {
-            Registers::new()
+        Gameboy {
+            cpu: cpu,
+            sender: None,
+            input: None,
+            audio: None,
+            debug_output: None,
+            frames: 0,
+            cheats: Vec::new(),
+            boot_hang_limit: Some(DEFAULT_BOOT_HANG_FRAMES),
+            boot_frames: 0,
+            boot_hung: false,
+            save_path: None,
+            state_path: None,
+            headless_breakpoint: None,
+            mooneye_result: None,
+            speed: None,
+        }
+    }
+
+    /* Loads battery backed ram from path, if it exists, and saves back to
+     * it when the Gameboy is dropped.
+     */
+    pub fn set_save_path(&mut self, path: PathBuf) -> Result<(), Error> {
+        self.cpu.load_ram(&path)?;
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        match &self.save_path {
+            Some(path) => self.cpu.save_ram(path),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_state_path(&mut self, path: PathBuf) {
+        self.state_path = Some(path);
+    }
+
+    /* Snapshots the whole machine, see state.rs. A state can only be
+     * loaded back into the same game.
+     */
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.cpu.load_state(bytes)
+    }
+
+    pub fn save_state_slot(&self) -> Result<(), Error> {
+        match &self.state_path {
+            Some(path) => fs::write(path, self.save_state()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn load_state_slot(&mut self) -> Result<(), Error> {
+        match self.state_path.clone() {
+            Some(path) => self.load_state(&fs::read(path)?),
+            None => Ok(()),
+        }
+    }
+
+    /* Button presses from the display, applied at the start of each frame */
+    pub fn set_input(&mut self, input: Receiver<Input>) {
+        self.input = Some(input);
+    }
+
+    /* Completed frames are sent here by next_frame */
+    pub fn set_frame_output(&mut self, sender: SyncSender<Box<Frame>>) {
+        self.sender = Some(sender);
+    }
+
+    /* Samples generated during each frame are sent here */
+    pub fn set_audio(&mut self, audio: Sender<Vec<f32>>) {
+        self.audio = Some(audio);
+    }
+
+    /* Debugger commands that produce output reply on this channel */
+    pub fn set_debug_output(&mut self, debug_output: Sender<DebugOutput>) {
+        self.debug_output = Some(debug_output);
+    }
+
+    pub fn handle_input(&mut self, input: Input) {
+        match input {
+            Input::Button(button, pressed) => self.cpu.set_button(button, pressed),
+            Input::SaveState => {
+                if let Err(e) = self.save_state_slot() {
+                    println!("failed to save state: {}", e);
+                }
+            },
+            Input::LoadState => {
+                if let Err(e) = self.load_state_slot() {
+                    println!("failed to load state: {}", e);
+                }
+            },
+            Input::TileMapView(view) => self.cpu.set_tile_map_view(view),
+            Input::SetSpeed(speed) => self.speed = Some(speed),
+            Input::Step => { self.cpu.step_debug(); },
+            Input::Debug(command) => {
+                if let Some(output) = self.handle_debug(command) {
+                    if let Some(debug_output) = self.debug_output.as_ref() {
+                        let _ = debug_output.send(output);
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn handle_debug(&mut self, command: DebugCommand) -> Option<DebugOutput> {
+        match command {
+            DebugCommand::Pause => self.cpu.pause(),
+            DebugCommand::Continue => self.cpu.resume(),
+            DebugCommand::Step => {
+                self.cpu.step_debug();
+                return Some(DebugOutput::Registers(self.cpu.snapshot()))
+            },
+            DebugCommand::Next => {
+                self.cpu.step_over();
+                return Some(DebugOutput::Registers(self.cpu.snapshot()))
+            },
+            DebugCommand::Break(pc) => self.cpu.set_break_point(pc),
+            DebugCommand::Delete(pc) => self.cpu.clear_break_point(pc),
+            DebugCommand::BreakRegister(register, value) => {
+                self.cpu.set_register_break(RegisterCondition::new(register, value))
+            },
+            DebugCommand::DeleteRegister(register, value) => {
+                self.cpu.clear_register_break(RegisterCondition::new(register, value))
+            },
+            DebugCommand::Registers => {
+                return Some(DebugOutput::Registers(self.cpu.snapshot()))
+            },
+            DebugCommand::Read(address, length) => {
+                let bytes = (0..length).map(|i| self.peek(address.wrapping_add(i))).collect();
+                return Some(DebugOutput::Memory(address, bytes))
+            },
+            DebugCommand::Dump => return Some(DebugOutput::Text(self.debug_dump_json())),
+        }
+
+        None
+    }
+
+    /* Runs the emulator until the next frame is complete and sends it to
+     * the display. Returns false if the display has gone away and the
+     * emulator should shut down.
+     */
+    pub fn next_frame(&mut self) -> bool {
+        let inputs: Vec<Input> = match &self.input {
+            Some(input) => input.try_iter().collect(),
+            None => Vec::new(),
         };
 
-        let cpu = CPUManager::new(
-            registers,
-            mmu,
-        );
+        for input in inputs {
+            self.handle_input(input);
+        }
 
-        Ok(Gameboy {
-            cpu: cpu,
-            sender: sender,
-        })
+        if self.cpu.debugging() {
+            return self.next_paused_frame();
+        }
+
+        let frame = self.step_until_vblank();
+
+        let samples = self.cpu.take_samples();
+        if let Some(audio) = self.audio.as_ref() {
+            /* The audio device going away isn't a reason to stop */
+            let _ = audio.send(samples);
+        }
+
+        for line in self.cpu.take_log() {
+            println!("{}", line);
+        }
+
+        self.send_frame(frame)
+    }
+
+    /* Nothing executes while paused, the next input (a step or continue
+     * from the display or debugger) is waited for and the current frame
+     * resent so a step shows up on screen.
+     */
+    fn next_paused_frame(&mut self) -> bool {
+        let input = self.input.as_ref().and_then(|input| input.recv_timeout(PAUSED_INPUT_WAIT).ok());
+
+        if let Some(input) = input {
+            self.handle_input(input);
+        }
+
+        let frame = self.cpu.frame_info();
+        self.send_frame(frame)
     }
 
-    pub fn next_frame(&mut self) {
+    fn send_frame(&mut self, mut frame: Box<Frame>) -> bool {
+        frame.speed = self.speed.take();
+
+        match self.sender.as_ref() {
+            Some(sender) => sender.send(frame).is_ok(),
+            None => true,
+        }
+    }
+
+    /* Runs the emulator until the next vblank and returns the completed frame */
+    pub fn step_until_vblank(&mut self) -> Box<Frame> {
+        self.step_frame();
+        self.cpu.frame_info()
+    }
+
+    /* Runs until the next vblank, the finished screen is in frame_buffer */
+    pub fn step_frame(&mut self) {
         self.cpu.next_frame();
-        self.sender.send(self.cpu.frame_info()).unwrap();
+        self.frames += 1;
+        self.apply_cheats();
+        self.check_boot_hang();
+    }
+
+    /* Executes a single instruction and returns the clock cycles (4 per
+     * machine cycle) it took, including servicing an interrupt first or
+     * idling while halted.
+     */
+    pub fn step_instruction(&mut self) -> u64 {
+        let start = self.cpu.cycles();
+        self.cpu.step();
+        self.cpu.cycles() - start
+    }
+
+    /* Clock cycles since power on */
+    pub fn total_cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+
+    /* The screen as of the last completed line */
+    pub fn frame_buffer(&self) -> &[[Shade; 160]; 144] {
+        self.cpu.frame_buffer()
+    }
+
+    pub fn set_headless_breakpoint(&mut self, pc: Option<u16>) {
+        self.headless_breakpoint = pc;
+    }
+
+    /* Runs without a display for up to max_cycles, or until the headless
+     * breakpoint is reached or a mooneye test rom reports its result, and
+     * returns everything written to the serial port. Test roms like
+     * Blargg's cpu_instrs report their results this way.
+     */
+    pub fn run_headless(&mut self, max_cycles: u64) -> String {
+        self.cpu.capture_serial();
+
+        let end = self.cpu.cycles() + max_cycles;
+        let mut output = Vec::new();
+
+        while self.cpu.cycles() < end && !self.cpu.debugging() {
+            if self.headless_breakpoint == Some(self.cpu.pc()) {
+                break;
+            }
+
+            if let Some(passed) = self.cpu.mooneye_result() {
+                self.mooneye_result = Some(passed);
+                break;
+            }
+
+            self.cpu.step();
+            output.extend(self.cpu.take_serial_output());
+        }
+
+        String::from_utf8_lossy(&output).to_string()
+    }
+
+    /* Some(passed) once run_headless has stopped at the end of a mooneye
+     * test rom
+     */
+    pub fn mooneye_result(&self) -> Option<bool> {
+        self.mooneye_result
+    }
+
+    /* Number of consecutive frames the pc may spend in the boot rom region
+     * before boot_hung reports true, None disables the check. Setting a
+     * limit resets the detector.
+     */
+    pub fn set_boot_hang_limit(&mut self, frames: Option<u64>) {
+        self.boot_hang_limit = frames;
+        self.boot_frames = 0;
+        self.boot_hung = false;
+    }
+
+    pub fn boot_hung(&self) -> bool {
+        self.boot_hung
+    }
+
+    /* A mismatched or corrupt boot rom usually shows up as a logo that
+     * never stops scrolling, which gives no clue what went wrong. Count
+     * the frames that end with the pc still in 0x0000 - 0x00FF and flag it
+     * once the limit is passed.
+     */
+    fn check_boot_hang(&mut self) {
+        let limit = match self.boot_hang_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        if self.cpu.pc() > 0x00FF {
+            self.boot_frames = 0;
+            return
+        }
+
+        self.boot_frames += 1;
+
+        if self.boot_frames > limit {
+            self.boot_hung = true;
+        }
+    }
+
+    /* Reads and writes memory as the cpu would see it, for cheats and test
+     * harnesses. 16 bit values are little endian.
+     */
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.peek(address)
+    }
+
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.cpu.poke(address, value);
+    }
+
+    pub fn peek16(&self, address: u16) -> u16 {
+        let ls = self.peek(address);
+        let ms = self.peek(address.wrapping_add(1));
+        bytes::combine_ms_ls(ms, ls)
+    }
+
+    pub fn poke16(&mut self, address: u16, value: u16) {
+        let (ms, ls) = bytes::split_ms_ls(value);
+        self.poke(address, ls);
+        self.poke(address.wrapping_add(1), ms);
+    }
+
+    /* Game Genie codes patch the rom immediately, Game Shark codes are kept
+     * and reapplied at every vblank.
+     */
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), Error> {
+        match Cheat::parse(code)? {
+            Cheat::GameGenie { address, value, compare } => {
+                self.cpu.patch_rom(address, value, compare);
+            },
+            cheat => self.cheats.push(cheat),
+        }
+        Ok(())
+    }
+
+    fn apply_cheats(&mut self) {
+        for i in 0..self.cheats.len() {
+            if let Cheat::GameShark { address, value, .. } = self.cheats[i] {
+                self.poke(address, value);
+            }
+        }
+    }
+
+    /* Writes VRAM, OAM and the IO registers to a file, see MMU::dump_state */
+    pub fn write_state_dump(&self, path: &str) -> Result<(), Error> {
+        fs::write(path, self.cpu.dump_state())
+    }
+
+    /* The tile set, tile maps, OAM and palettes as JSON, see dump.rs */
+    pub fn debug_dump_json(&self) -> String {
+        self.cpu.debug_dump_json()
+    }
+
+    /* The number of frames completed so far */
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+}
+
+impl Drop for Gameboy {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            println!("failed to save cartridge ram: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Header;
+    use std::sync::mpsc::{channel, sync_channel};
+
+    fn test_gameboy() -> Gameboy {
+        gameboy_with_program(&[])
+    }
+
+    /* A gameboy past the boot rom about to run program at 0x0100 */
+    fn gameboy_with_program(program: &[u8]) -> Gameboy {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x100..0x100 + program.len()].copy_from_slice(program);
+        gameboy_with_rom(bytes)
+    }
+
+    fn gameboy_with_rom(bytes: Vec<u8>) -> Gameboy {
+        let cartridge = Cartridge::new(bytes, Header::zero());
+        Gameboy::from_roms(BootRom::zero(), cartridge, true)
+    }
+
+    #[test]
+    fn test_set_speed_goes_out_with_the_next_frame() {
+        let (sender, receiver) = sync_channel(2);
+
+        let mut gameboy = test_gameboy();
+        gameboy.set_frame_output(sender);
+
+        gameboy.handle_input(Input::SetSpeed(0.0));
+        assert!(gameboy.next_frame());
+        assert!(gameboy.next_frame());
+
+        assert_eq!(receiver.recv().unwrap().speed, Some(0.0));
+        assert_eq!(receiver.recv().unwrap().speed, None);
+    }
+
+    #[test]
+    fn test_display_step_does_not_reply() {
+        let (debug_sender, debug_receiver) = channel();
+        let mut gameboy = test_gameboy();
+        gameboy.set_debug_output(debug_sender);
+        gameboy.poke(0xC000, 0x12);
+
+        /* A step from the display lands between a debugger request and its
+         * reply, the debugger should still only see the reply it asked for
+         */
+        gameboy.handle_input(Input::Debug(DebugCommand::Pause));
+        gameboy.handle_input(Input::Step);
+        gameboy.handle_input(Input::Debug(DebugCommand::Read(0xC000, 1)));
+        gameboy.handle_input(Input::Step);
+
+        match debug_receiver.try_recv().unwrap() {
+            DebugOutput::Memory(address, bytes) => {
+                assert_eq!(address, 0xC000);
+                assert_eq!(bytes, vec![0x12]);
+            },
+            output => panic!("unexpected output: {:?}", output),
+        }
+        assert!(debug_receiver.try_recv().is_err());
+        assert_eq!(gameboy.cpu.snapshot().pc, 0x0102);
+    }
+
+    #[test]
+    fn test_next_frame_disconnected() {
+        let (sender, receiver) = sync_channel(0);
+        drop(receiver);
+
+        let mut gameboy = test_gameboy();
+        gameboy.set_frame_output(sender);
+
+        assert!(!gameboy.next_frame());
+    }
+
+    #[test]
+    fn test_debug_commands() {
+        let (debug_sender, debug_receiver) = channel();
+        let mut gameboy = test_gameboy();
+        gameboy.set_debug_output(debug_sender);
+
+        /* The test cartridge is all NOPs */
+        gameboy.handle_input(Input::Debug(DebugCommand::Break(0x0102)));
+        gameboy.step_until_vblank();
+        assert!(gameboy.cpu.debugging());
+
+        gameboy.handle_input(Input::Debug(DebugCommand::Registers));
+        match debug_receiver.try_recv().unwrap() {
+            DebugOutput::Registers(snapshot) => assert_eq!(snapshot.pc, 0x0102),
+            output => panic!("unexpected output: {:?}", output),
+        }
+
+        gameboy.handle_input(Input::Debug(DebugCommand::Step));
+        match debug_receiver.try_recv().unwrap() {
+            DebugOutput::Registers(snapshot) => assert_eq!(snapshot.pc, 0x0103),
+            output => panic!("unexpected output: {:?}", output),
+        }
+
+        gameboy.poke(0xC000, 0x12);
+        gameboy.handle_input(Input::Debug(DebugCommand::Read(0xC000, 2)));
+        assert_eq!(debug_receiver.try_recv().unwrap(), DebugOutput::Memory(0xC000, vec![0x12, 0x00]));
+
+        gameboy.handle_input(Input::Debug(DebugCommand::Continue));
+        assert!(!gameboy.cpu.debugging());
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        /* LD A, 0x42; LD (0xC000), A; CALL 0x010C; NOP; CALL 0x010C
+         * with INC A; RET at 0x010C
+         */
+        let mut gameboy = gameboy_with_program(&[
+            0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xCD, 0x0C, 0x01, 0x00, 0xCD, 0x0C, 0x01,
+            0x3C, 0xC9,
+        ]);
+        gameboy.handle_debug(DebugCommand::Pause);
+
+        let pc = |gameboy: &mut Gameboy, command| match gameboy.handle_debug(command) {
+            Some(DebugOutput::Registers(snapshot)) => (snapshot.pc, snapshot.a),
+            output => panic!("unexpected output: {:?}", output),
+        };
+
+        /* Each step is one instruction whatever its length */
+        assert_eq!(pc(&mut gameboy, DebugCommand::Step).0, 0x0102);
+        assert_eq!(pc(&mut gameboy, DebugCommand::Step).0, 0x0105);
+
+        /* Next runs the whole call */
+        assert_eq!(pc(&mut gameboy, DebugCommand::Next), (0x0108, 0x43));
+        assert_eq!(pc(&mut gameboy, DebugCommand::Next).0, 0x0109);
+
+        /* Step follows it */
+        assert_eq!(pc(&mut gameboy, DebugCommand::Step), (0x010C, 0x43));
+        assert!(gameboy.cpu.debugging());
+    }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        /* INC A; LD (0xC000), A; LD (0x8000), A; JR -9 */
+        let mut gameboy = gameboy_with_program(&[0x3C, 0xEA, 0x00, 0xC0, 0xEA, 0x00, 0x80, 0x18, 0xF7]);
+
+        for _ in 0..3 {
+            gameboy.step_until_vblank();
+        }
+        let state = gameboy.save_state();
+
+        for _ in 0..3 {
+            gameboy.step_until_vblank();
+        }
+        let expected = gameboy.save_state();
+        let expected_pc = gameboy.cpu.pc();
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.save_state(), state);
+
+        for _ in 0..3 {
+            gameboy.step_until_vblank();
+        }
+        assert_eq!(gameboy.cpu.pc(), expected_pc);
+        assert_eq!(gameboy.save_state(), expected);
+    }
+
+    #[test]
+    fn test_load_state_rejects_garbage() {
+        let mut gameboy = test_gameboy();
+
+        assert!(gameboy.load_state(&[0; 16]).is_err());
+
+        let mut state = gameboy.save_state();
+        state.truncate(state.len() - 1);
+        assert!(gameboy.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_bad_load_state_keeps_machine() {
+        let mut gameboy = test_gameboy();
+        gameboy.step_until_vblank();
+        let mut state = gameboy.save_state();
+
+        gameboy.step_until_vblank();
+        gameboy.poke(0xC000, 0x42);
+        let running = gameboy.save_state();
+
+        /* Cut off part way through so the cpu and some devices would load */
+        state.truncate(state.len() / 2);
+        assert!(gameboy.load_state(&state).is_err());
+        assert_eq!(gameboy.save_state(), running);
+    }
+
+    #[test]
+    fn test_boot_hang() {
+        /* JR -2, spins forever at 0x0000 */
+        let mut bytes = [0; 256];
+        bytes[0] = 0x18;
+        bytes[1] = 0xFE;
+        let boot_rom = BootRom::from_bytes(&bytes, BootRomVariant::DMG).unwrap();
+        let cartridge = Cartridge::new(vec![0; 0x8000], Header::zero());
+
+        let mut gameboy = Gameboy::from_roms(boot_rom, cartridge, false);
+        gameboy.set_boot_hang_limit(Some(2));
+
+        gameboy.step_until_vblank();
+        gameboy.step_until_vblank();
+        assert!(!gameboy.boot_hung());
+
+        gameboy.step_until_vblank();
+        assert!(gameboy.boot_hung());
+    }
+
+    #[test]
+    fn test_boot_hang_after_handoff() {
+        let mut gameboy = test_gameboy();
+        gameboy.set_boot_hang_limit(Some(0));
+
+        gameboy.step_until_vblank();
+        assert!(!gameboy.boot_hung());
+    }
+
+    #[test]
+    fn test_peek_poke_wram() {
+        let mut gameboy = test_gameboy();
+
+        gameboy.poke(0xC123, 0x42);
+        assert_eq!(gameboy.peek(0xC123), 0x42);
+
+        gameboy.poke16(0xC200, 0xBEEF);
+        assert_eq!(gameboy.peek(0xC200), 0xEF);
+        assert_eq!(gameboy.peek(0xC201), 0xBE);
+        assert_eq!(gameboy.peek16(0xC200), 0xBEEF);
+    }
+
+    #[test]
+    fn test_poke_io_register() {
+        let mut gameboy = test_gameboy();
+        gameboy.cpu.set_logging(true);
+
+        gameboy.poke(0xFF47, 0xE4);
+
+        assert_eq!(gameboy.peek(0xFF47), 0xE4);
+        assert_eq!(gameboy.cpu.take_log(), vec!["BGP: E4 White LightGrey DarkGrey Black"]);
+    }
+
+    #[test]
+    fn test_game_shark_holds_value() {
+        /* LD A, 0x12; LD (0xC100), A; JR -7 */
+        let mut gameboy = gameboy_with_program(&[0x3E, 0x12, 0xEA, 0x00, 0xC1, 0x18, 0xF9]);
+
+        gameboy.add_cheat("01FF00C1").unwrap();
+
+        gameboy.step_until_vblank();
+        assert_eq!(gameboy.peek(0xC100), 0xFF);
+
+        gameboy.step_until_vblank();
+        assert_eq!(gameboy.peek(0xC100), 0xFF);
+    }
+
+    /* Writes "Hi" over serial then loops forever on the JR at 0x0110 */
+    fn serial_gameboy() -> Gameboy {
+        gameboy_with_program(&[
+            0x3E, 0x48, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0x3E, 0x69, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02,
+            0x18, 0xFE,
+        ])
+    }
+
+    #[test]
+    fn test_run_headless() {
+        let mut gameboy = serial_gameboy();
+
+        assert_eq!(gameboy.run_headless(100_000), "Hi");
+        assert!(gameboy.cpu.cycles() >= 100_000);
+    }
+
+    #[test]
+    fn test_run_headless_breakpoint() {
+        let mut gameboy = serial_gameboy();
+        gameboy.set_headless_breakpoint(Some(0x0110));
+
+        assert_eq!(gameboy.run_headless(100_000), "Hi");
+        assert_eq!(gameboy.cpu.pc(), 0x0110);
+        assert!(gameboy.cpu.cycles() < 1_000);
+    }
+
+    #[test]
+    fn test_run_headless_mooneye_result() {
+        /* LD B, 3; LD C, 5; LD D, 8; LD E, 13; LD H, 21; LD L, 34; LD B, B */
+        let mut gameboy = gameboy_with_program(&[
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, 0x40,
+        ]);
+
+        assert_eq!(gameboy.mooneye_result(), None);
+        gameboy.run_headless(100_000);
+        assert_eq!(gameboy.mooneye_result(), Some(true));
+        assert_eq!(gameboy.cpu.pc(), 0x010C);
+    }
+
+    #[test]
+    fn test_game_genie_patches_rom() {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x05A9] = 0x11;
+        bytes[0x4A17] = 0xC8;
+        let mut gameboy = gameboy_with_rom(bytes);
+
+        gameboy.add_cheat("3E5-A9F").unwrap();
+        gameboy.add_cheat("00A-17B-C49").unwrap();
+
+        assert_eq!(gameboy.peek(0x05A9), 0x3E);
+        assert_eq!(gameboy.peek(0x4A17), 0x00);
+
+        /* compare doesn't match, so nothing changes */
+        gameboy.add_cheat("FF5-A9F-C49").unwrap();
+        assert_eq!(gameboy.peek(0x05A9), 0x3E);
+    }
+
+    #[test]
+    fn test_write_state_dump() {
+        let mut gameboy = test_gameboy();
+        gameboy.poke(0x8000, 0xAB);
+        gameboy.poke(0xFE9F, 0xCD);
+
+        let path = std::env::temp_dir().join("gameboy_test_state_dump.bin");
+        let path = path.to_str().unwrap();
+        gameboy.write_state_dump(path).unwrap();
+
+        let bytes = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(bytes.len(), 0x2000 + 0xA0 + 0x80);
+        assert_eq!(bytes[0x0000], 0xAB);
+        assert_eq!(bytes[0x209F], 0xCD);
+    }
+
+    #[test]
+    fn test_step_until_vblank() {
+        let mut gameboy = test_gameboy();
+
+        assert_eq!(gameboy.frames(), 0);
+
+        let frame = gameboy.step_until_vblank();
+        assert_eq!(frame.main.len(), 144);
+        assert_eq!(gameboy.frames(), 1);
+
+        gameboy.step_until_vblank();
+        assert_eq!(gameboy.frames(), 2);
     }
 }
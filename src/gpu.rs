@@ -1,6 +1,9 @@
+use std::io::Error;
+
 use crate::tile::Tile;
 use crate::device::Device;
 use crate::pixel::Pixel;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 const VRAM_BEGIN: usize = 0x8000;
 const VRAM_END: usize = 0x9FFF;
@@ -115,22 +118,37 @@ impl GPU {
         }
     }
 
-    fn draw_tile(&mut self, oy: usize, ox: usize, tile: Tile) {
-        for y in 0..8 as usize {
-            for x in 0..8 as usize {
-                let p = tile.data[y][x];
-                self.buffer[oy + y][ox + x] = p;
-            }
+    /* unsigned_tiles is LCDC bit 4, see tile_index, and map picks the
+     * 0x9C00 map over the 0x9800 one like LCDC bit 3
+     */
+    pub fn update_buffer(&mut self, unsigned_tiles: bool, map: bool) {
+        draw_map(&self.tile_map, &self.vram.tile_set, map, unsigned_tiles, &mut self.buffer);
+    }
+
+    /* Renders either map with either tile data region without touching the
+     * background buffer, for the debug view.
+     */
+    pub fn render_map(&self, map: bool, unsigned_tiles: bool) -> [[Pixel; 256]; 256] {
+        let mut buffer = [[Pixel::P0; 256]; 256];
+        draw_map(&self.tile_map, &self.vram.tile_set, map, unsigned_tiles, &mut buffer);
+        buffer
+    }
+}
+
+fn draw_map(tile_map: &TileMap, tile_set: &[Tile; 384], map: bool, unsigned_tiles: bool, buffer: &mut [[Pixel; 256]]) {
+    for y in 0..32 {
+        for x in 0..32 {
+            let mapping = tile_map.map(y, x, map);
+            let tile = tile_set[tile_index(mapping, unsigned_tiles)];
+            draw_tile(buffer, y as usize * 8, x as usize * 8, tile);
         }
     }
+}
 
-    pub fn update_buffer(&mut self) {
-        for y in 0..32 {
-            for x in 0..32 {
-                let mapping = self.tile_map.map(y, x, false);
-                let tile = self.vram.tile_set[mapping as usize];
-                self.draw_tile(y as usize * 8, x as usize * 8, tile);
-            }
+fn draw_tile(buffer: &mut [[Pixel; 256]], oy: usize, ox: usize, tile: Tile) {
+    for y in 0..8 {
+        for x in 0..8 {
+            buffer[oy + y][ox + x] = tile.data[y][x];
         }
     }
 }
@@ -158,11 +176,55 @@ impl Device for GPU {
     }
 }
 
+/* The background and window tile maps hold tile numbers in one of two
+ * addressing modes picked by LCDC bit 4
+ *
+ *   1: 0x8000 - 0x8FFF, unsigned numbers from tile 0
+ *   0: 0x8800 - 0x97FF, signed numbers from tile 256, so 0x80 - 0xFF are
+ *      tiles 128 - 255 and 0x00 - 0x7F are tiles 256 - 383
+ */
+pub fn tile_index(mapping: u8, unsigned_tiles: bool) -> usize {
+    if unsigned_tiles {
+        mapping as usize
+    } else {
+        (256 + mapping as i8 as i16) as usize
+    }
+}
+
 fn make_even(i: u16) -> u16 {
     // 0x01 is the first byte making it 0 will make the number even
     i & !0x0001
 }
 
+/* The tile set is rebuilt from the raw memory, the background buffer needs
+ * the lcd control register so the mmu redraws it.
+ */
+impl SaveState for GPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.vram.storage);
+        for row in self.tile_map.storage.iter() {
+            w.bytes(row);
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        let mut storage = [0; VRAM_SIZE];
+        r.bytes_into(&mut storage)?;
+        /* Only the tile data region backs the tile set, the maps live in
+         * tile_map
+         */
+        for (address, value) in storage.iter().enumerate().take(0x1800) {
+            self.vram.set(address as u16, *value);
+        }
+
+        for row in self.tile_map.storage.iter_mut() {
+            r.bytes_into(row)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -174,6 +236,65 @@ mod tests {
         assert_eq!(t.get(272), 0x19);
     }
 
+    #[test]
+    fn test_tile_index() {
+        assert_eq!(tile_index(0x00, true), 0);
+        assert_eq!(tile_index(0xFF, true), 255);
+        assert_eq!(tile_index(0x00, false), 256);
+        assert_eq!(tile_index(0x7F, false), 383);
+        assert_eq!(tile_index(0x80, false), 128);
+        assert_eq!(tile_index(0xFF, false), 255);
+    }
+
+    #[test]
+    fn test_update_buffer_signed_tiles() {
+        let mut gpu = GPU::new();
+
+        /* The first row of tile 257 (0x9010) is all color 3 */
+        gpu.set(0x9010, 0xFF);
+        gpu.set(0x9011, 0xFF);
+
+        /* Tile map entry 0 points at tile 1 */
+        gpu.set(0x9800, 0x01);
+
+        gpu.update_buffer(true, false);
+        assert_eq!(gpu.buffer[0][0], Pixel::P0);
+
+        gpu.update_buffer(false, false);
+        assert_eq!(gpu.buffer[0][0], Pixel::P3);
+        assert_eq!(gpu.buffer[0][7], Pixel::P3);
+        assert_eq!(gpu.buffer[1][0], Pixel::P0);
+    }
+
+    #[test]
+    fn test_update_buffer_second_map() {
+        let mut gpu = GPU::new();
+
+        /* The first row of tile 1 is all color 3 */
+        gpu.set(0x8010, 0xFF);
+        gpu.set(0x8011, 0xFF);
+
+        /* Only the 0x9C00 map points at it, second row second column */
+        gpu.set(0x9C21, 0x01);
+        assert_eq!(gpu.tile_map.map(1, 1, true), 0x01);
+        assert_eq!(gpu.tile_map.map(1, 1, false), 0x00);
+
+        gpu.update_buffer(true, false);
+        assert_eq!(gpu.buffer[8][8], Pixel::P0);
+
+        gpu.update_buffer(true, true);
+        assert_eq!(gpu.buffer[8][8], Pixel::P3);
+        assert_eq!(gpu.buffer[8][15], Pixel::P3);
+        assert_eq!(gpu.buffer[0][0], Pixel::P0);
+
+        /* The debug view renders any map without changing the buffer */
+        let view = gpu.render_map(true, false);
+        assert_eq!(view[8][8], Pixel::P0);
+        assert_eq!(gpu.render_map(true, true)[8][8], Pixel::P3);
+        assert_eq!(gpu.render_map(false, true)[8][8], Pixel::P0);
+        assert_eq!(gpu.buffer[8][8], Pixel::P3);
+    }
+
     #[test]
     fn test_vram_set() {
         let mut vram = VRam::new();
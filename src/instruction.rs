@@ -4,15 +4,38 @@ use crate::bytes;
 
 pub mod helper;
 pub mod opcode;
+pub mod disassemble;
 
-pub struct OpResult {
+pub struct OpResult<'a> {
     pub cycles: u8,
+
+    /* The description of the instruction that produced this result, filled
+     * in by Instruction::call. Borrowed so running an instruction doesn't
+     * allocate.
+     */
+    pub name: &'a str,
+}
+
+/* A memory access made by an instruction, each one takes a machine cycle
+ * (4 clock cycles).
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Access {
+    Fetch,
+    Read,
+    Write,
 }
 
 pub struct Instruction {
     pub f: IFn,
     pub description: String,
     pub args: u8,
+
+    /* The bus accesses the instruction makes in order, including fetching
+     * the opcode and its arguments. Lets a scheduler tick the clock between
+     * accesses, only some instructions declare them so far.
+     */
+    pub accesses: Option<Vec<Access>>,
 }
 
 impl Instruction {
@@ -21,9 +44,15 @@ impl Instruction {
             description: description.to_string(),
             args: args,
             f: f,
+            accesses: None,
         }
     }
 
+    pub fn with_accesses(mut self, accesses: Vec<Access>) -> Instruction {
+        self.accesses = Some(accesses);
+        self
+    }
+
     pub fn no_args(description: String, f: IFn) -> Instruction {
         Instruction::new(description.to_string(), 0, f)
     }
@@ -36,12 +65,15 @@ impl Instruction {
         Instruction::new(description.to_string(), 2, f)
     }
 
-    pub fn call(&self, cpu: &mut CPU, arg: u16) -> OpResult {
-        (self.f)(cpu, arg)
+    pub fn call(&self, cpu: &mut CPU, arg: u16) -> OpResult<'_> {
+        OpResult {
+            name: &self.description,
+            ..(self.f)(cpu, arg)
+        }
     }
 }
 
-pub type IFn = Box<dyn Fn(&mut CPU, u16) -> OpResult>;
+pub type IFn = Box<dyn Fn(&mut CPU, u16) -> OpResult<'static>>;
 
 #[derive(Debug, Clone, Copy)]
 pub enum RstFlag {
@@ -66,11 +98,24 @@ pub enum JumpFlag {
     NZ, Z, NC, C
 }
 
+/* Whether a conditional jump, call or return is taken, taken branches cost
+ * extra cycles.
+ */
+fn condition(cpu: &CPU, f: JumpFlag) -> bool {
+    match f {
+        JumpFlag::NZ => !cpu.registers.get_flag(Flag::Z),
+        JumpFlag::Z => cpu.registers.get_flag(Flag::Z),
+        JumpFlag::NC => !cpu.registers.get_flag(Flag::C),
+        JumpFlag::C => cpu.registers.get_flag(Flag::C),
+    }
+}
+
 
 
-fn cycles(a: u8) -> OpResult {
+fn cycles(a: u8) -> OpResult<'static> {
     OpResult {
         cycles: a,
+        name: "",
     }
 }
 
@@ -82,6 +127,7 @@ pub fn nop() -> Instruction {
         Box::new(move |_cpu: &mut CPU, _arg: u16| {
             cycles(4)
         }))
+        .with_accesses(vec![Access::Fetch])
 }
 
 
@@ -217,6 +263,7 @@ pub fn ld_ar16_r8(r1: Registers16, r2: Registers8) -> Instruction {
 
             cycles(8)
         }))
+        .with_accesses(vec![Access::Fetch, Access::Write])
 }
 
 /* Loads a 8 bit immediate value into the memory addressed by r
@@ -229,6 +276,7 @@ pub fn ld_ar16_n8(r: Registers16) -> Instruction {
             cpu.mmu.set(address, arg as u8);
             cycles(12)
         }))
+        .with_accesses(vec![Access::Fetch, Access::Fetch, Access::Write])
 }
 
 /* Loads a 8 bit value from the memory addressed by r2 into r1
@@ -242,6 +290,7 @@ pub fn ld_r8_ar16(r1: Registers8, r2: Registers16) -> Instruction {
             cpu.registers.set8(r1, value);
             cycles(8)
         }))
+        .with_accesses(vec![Access::Fetch, Access::Read])
 }
 
 /* Loads a 8 bit value from the memory addressed by a 16 bit immediate value into r1
@@ -575,7 +624,7 @@ pub fn rr_r8(r: Registers8) -> Instruction {
             let value = cpu.registers.get8(r);
             let out = helper::rr(cpu, value);
             cpu.registers.set8(r, out);
-            cycles(9)
+            cycles(8)
         }))
 }
 
@@ -684,7 +733,7 @@ pub fn sra_ar16(r: Registers16) -> Instruction {
             let value = cpu.mmu.get(address);
             let out = helper::sra(cpu, value);
             cpu.mmu.set(address, out);
-            cycles(1)
+            cycles(16)
         }))
 }
 
@@ -717,33 +766,12 @@ pub fn jp_f_n16(f: JumpFlag) -> Instruction {
     Instruction::arg16(
         format!("JP F | {:?}", f),
         Box::new(move |cpu: &mut CPU, n: u16| {
-            /* TODO: note there is a difference in cycle count
-             * between matching and not matching branches
-             */
-            match f {
-                JumpFlag::NZ => {
-                    if !cpu.registers.get_flag(Flag::Z) {
-                        helper::jump(cpu, n);
-                    }
-                },
-                JumpFlag::Z => {
-                    if cpu.registers.get_flag(Flag::Z) {
-                        helper::jump(cpu, n);
-                    }
-                },
-                JumpFlag::NC => {
-                    if !cpu.registers.get_flag(Flag::C) {
-                        helper::jump(cpu, n);
-                    }
-                }
-                JumpFlag::C => {
-                    if cpu.registers.get_flag(Flag::C) {
-                        helper::jump(cpu, n);
-                    }
-                }
+            if condition(cpu, f) {
+                helper::jump(cpu, n);
+                cycles(16)
+            } else {
+                cycles(12)
             }
-
-            cycles(12)
         }))
 }
 
@@ -780,30 +808,12 @@ pub fn call_f_n16(f: JumpFlag) -> Instruction {
     Instruction::arg16(
         format!("CALL F N16 | {:?}", f),
         Box::new(move |cpu: &mut CPU, n: u16| {
-            match f {
-                JumpFlag::NZ => {
-                    if !cpu.registers.get_flag(Flag::Z) {
-                        helper::call(cpu, n);
-                    }
-                },
-                JumpFlag::Z => {
-                    if cpu.registers.get_flag(Flag::Z) {
-                        helper::call(cpu, n);
-                    }
-                },
-                JumpFlag::NC => {
-                    if !cpu.registers.get_flag(Flag::C) {
-                        helper::call(cpu, n);
-                    }
-                }
-                JumpFlag::C => {
-                    if cpu.registers.get_flag(Flag::C) {
-                        helper::call(cpu, n);
-                    }
-                }
+            if condition(cpu, f) {
+                helper::call(cpu, n);
+                cycles(24)
+            } else {
+                cycles(12)
             }
-
-            cycles(12)
         }))
 }
 
@@ -875,30 +885,12 @@ pub fn ret_f(f: JumpFlag) -> Instruction {
     Instruction::no_args(
         format!("RET F | {:?}", f),
         Box::new(move |cpu: &mut CPU, _arg: u16| {
-            match f {
-                JumpFlag::NZ => {
-                    if !cpu.registers.get_flag(Flag::Z) {
-                        helper::ret(cpu);
-                    }
-                },
-                JumpFlag::Z => {
-                    if cpu.registers.get_flag(Flag::Z) {
-                        helper::ret(cpu);
-                    }
-                },
-                JumpFlag::NC => {
-                    if !cpu.registers.get_flag(Flag::C) {
-                        helper::ret(cpu);
-                    }
-                }
-                JumpFlag::C => {
-                    if cpu.registers.get_flag(Flag::C) {
-                        helper::ret(cpu);
-                    }
-                }
+            if condition(cpu, f) {
+                helper::ret(cpu);
+                cycles(20)
+            } else {
+                cycles(8)
             }
-
-            cycles(8)
         }))
 }
 
@@ -917,7 +909,7 @@ pub fn jr_n8() -> Instruction {
         format!("JR N8"),
         Box::new(move |cpu: &mut CPU, arg: u16| {
             helper::jr(cpu, arg as u8);
-            cycles(8)
+            cycles(12)
         }))
 }
 
@@ -928,30 +920,12 @@ pub fn jr_f_n8(f: JumpFlag) -> Instruction {
         Box::new(move |cpu: &mut CPU, arg: u16| {
             let n = arg as u8;
 
-            match f {
-                JumpFlag::NZ => {
-                    if !cpu.registers.get_flag(Flag::Z) {
-                        helper::jr(cpu, n);
-                    }
-                },
-                JumpFlag::Z => {
-                    if cpu.registers.get_flag(Flag::Z) {
-                        helper::jr(cpu, n);
-                    }
-                },
-                JumpFlag::NC => {
-                    if !cpu.registers.get_flag(Flag::C) {
-                        helper::jr(cpu, n);
-                    }
-                }
-                JumpFlag::C => {
-                    if cpu.registers.get_flag(Flag::C) {
-                        helper::jr(cpu, n);
-                    }
-                }
+            if condition(cpu, f) {
+                helper::jr(cpu, n);
+                cycles(12)
+            } else {
+                cycles(8)
             }
-
-            cycles(8)
         }))
 }
 
@@ -1571,6 +1545,47 @@ mod tests {
         assert_eq!(cpu.registers.get_flag(Flag::H), true);
     }
 
+    #[test]
+    fn test_ld_ar16_n8_accesses() {
+        let instruction = ld_ar16_n8(Registers16::HL);
+
+        assert_eq!(
+            instruction.accesses,
+            Some(vec![Access::Fetch, Access::Fetch, Access::Write]),
+        );
+    }
+
+    #[test]
+    fn test_accesses_match_cycles() {
+        let instructions = [
+            nop(),
+            ld_ar16_n8(Registers16::HL),
+            ld_r8_ar16(Registers8::A, Registers16::HL),
+            ld_ar16_r8(Registers16::HL, Registers8::A),
+        ];
+
+        for instruction in instructions.iter() {
+            let mut cpu = test_cpu();
+            cpu.registers.set16(Registers16::HL, 0xC000);
+
+            let accesses = instruction.accesses.as_ref().unwrap();
+            let result = cpu.execute(instruction);
+
+            assert_eq!(accesses.len() as u8 * 4, result.cycles, "{}", instruction.description);
+        }
+    }
+
+    #[test]
+    fn test_result_name() {
+        let mut cpu = test_cpu();
+
+        let nop = nop();
+        let set = set_r8(3, Registers8::A);
+
+        assert_eq!(cpu.execute(&nop).name, "NOP");
+        assert_eq!(cpu.execute(&set).name, "SET R8 | 3 A");
+    }
+
     #[test]
     fn test_set_r8() {
         let mut cpu = test_cpu();
@@ -1703,6 +1718,14 @@ mod tests {
         assert_eq!(cpu.registers.get_flag(Flag::C), true);
     }
 
+    #[test]
+    fn test_rotate_r8_cycles() {
+        let mut cpu = test_cpu();
+
+        assert_eq!(cpu.execute(&rl_r8(Registers8::B)).cycles, 8);
+        assert_eq!(cpu.execute(&rr_r8(Registers8::B)).cycles, 8);
+    }
+
     #[test]
     fn test_sra() {
         let mut cpu = test_cpu();
@@ -1719,6 +1742,19 @@ mod tests {
         assert_eq!(cpu.registers.get_flag(Flag::C), false);
     }
 
+    #[test]
+    fn test_sra_ar16() {
+        let mut cpu = test_cpu();
+
+        cpu.mmu.set(0xFF80, 0x8A);
+        cpu.registers.set16(Registers16::HL, 0xFF80);
+
+        let cycles = cpu.execute(&sra_ar16(Registers16::HL)).cycles;
+
+        assert_eq!(cycles, 16);
+        assert_eq!(cpu.mmu.get(0xFF80), 0xC5);
+    }
+
     #[test]
     fn test_dec_ar16() {
         let mut cpu = test_cpu();
@@ -1817,7 +1853,8 @@ mod tests {
         assert_eq!(cpu.registers.get_flag(Flag::Z), false);
         assert_eq!(cpu.registers.get_flag(Flag::N), false);
         assert_eq!(cpu.registers.get_flag(Flag::H), true);
-        assert_eq!(cpu.registers.get_flag(Flag::C), false);
+        /* 0xFD + the offset, unsigned, carries out of the low byte */
+        assert_eq!(cpu.registers.get_flag(Flag::C), true);
 
     }
 
@@ -1850,7 +1887,8 @@ mod tests {
         assert_eq!(cpu.registers.get_flag(Flag::Z), false);
         assert_eq!(cpu.registers.get_flag(Flag::N), false);
         assert_eq!(cpu.registers.get_flag(Flag::H), true);
-        assert_eq!(cpu.registers.get_flag(Flag::C), false);
+        /* 0xFD + the offset, unsigned, carries out of the low byte */
+        assert_eq!(cpu.registers.get_flag(Flag::C), true);
     }
 
     #[test]
@@ -1877,6 +1915,29 @@ mod tests {
         assert_eq!(cpu.registers.get16(Registers16::PC), 0xFF82);
     }
 
+    #[test]
+    fn test_conditional_cycles() {
+        let branches = [
+            (jp_f_n16(JumpFlag::Z), 16, 12),
+            (jr_f_n8(JumpFlag::Z), 12, 8),
+            (call_f_n16(JumpFlag::Z), 24, 12),
+            (ret_f(JumpFlag::Z), 20, 8),
+        ];
+
+        for (instruction, taken, not_taken) in branches.iter() {
+            let mut cpu = test_cpu();
+            cpu.registers.set16(Registers16::SP, 0xDFF0);
+            cpu.registers.set16(Registers16::PC, 0xC000);
+
+            cpu.registers.set_flag(Flag::Z, true);
+            assert_eq!(cpu.execute(instruction).cycles, *taken, "{}", instruction.description);
+
+            cpu.registers.set16(Registers16::PC, 0xC000);
+            cpu.registers.set_flag(Flag::Z, false);
+            assert_eq!(cpu.execute(instruction).cycles, *not_taken, "{}", instruction.description);
+        }
+    }
+
     #[test]
     fn test_call() {
         let mut cpu = test_cpu();
@@ -1959,4 +2020,59 @@ mod tests {
         assert_eq!(cpu.registers.get_flag(Flag::N), true);
         assert_eq!(cpu.registers.get_flag(Flag::C), false);
     }
+
+    #[test]
+    fn test_pop_af() {
+        let mut cpu = test_cpu();
+
+        /* Seed the stack with AF = 0x12FF, little endian */
+        cpu.registers.set16(Registers16::SP, 0xFFF0);
+        cpu.mmu.set(0xFFF0, 0xFF);
+        cpu.mmu.set(0xFFF1, 0x12);
+
+        cpu.execute(&pop_r16(Registers16::AF));
+
+        /* The low nibble of F doesn't exist in hardware */
+        assert_eq!(cpu.registers.get16(Registers16::AF), 0x12F0);
+        assert_eq!(cpu.registers.get16(Registers16::SP), 0xFFF2);
+
+        assert!(cpu.registers.get_flag(Flag::Z));
+        assert!(cpu.registers.get_flag(Flag::N));
+        assert!(cpu.registers.get_flag(Flag::H));
+        assert!(cpu.registers.get_flag(Flag::C));
+    }
+
+    #[test]
+    fn test_push_pop_round_trip() {
+        let mut cpu = test_cpu();
+        cpu.registers.set16(Registers16::SP, 0xFFFE);
+        cpu.registers.set16(Registers16::BC, 0x1234);
+
+        cpu.execute(&push_r16(Registers16::BC));
+
+        /* The high byte goes to the higher address so get16 reads it back */
+        assert_eq!(cpu.registers.get16(Registers16::SP), 0xFFFC);
+        assert_eq!(cpu.mmu.get(0xFFFD), 0x12);
+        assert_eq!(cpu.mmu.get(0xFFFC), 0x34);
+        assert_eq!(cpu.mmu.get16(0xFFFC), 0x1234);
+
+        cpu.execute(&pop_r16(Registers16::DE));
+
+        assert_eq!(cpu.registers.get16(Registers16::DE), 0x1234);
+        assert_eq!(cpu.registers.get16(Registers16::SP), 0xFFFE);
+    }
+
+    #[test]
+    fn test_ld_an16_sp() {
+        let mut cpu = test_cpu();
+        cpu.push_pc(0x8002, 0xC1);
+        cpu.push_pc(0x8001, 0x00);
+        cpu.registers.set16(Registers16::SP, 0xFFF8);
+
+        /* LD (0xC100), SP stores the low byte first */
+        cpu.execute(&ld_an16_r16(Registers16::SP));
+
+        assert_eq!(cpu.mmu.get(0xC100), 0xF8);
+        assert_eq!(cpu.mmu.get(0xC101), 0xFF);
+    }
 }
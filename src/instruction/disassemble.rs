@@ -0,0 +1,178 @@
+use crate::mmu::MMU;
+
+/* Disassembly
+ *
+ * Decodes the instruction at a pc into assembly like "JP 0x0150" along
+ * with its length in bytes. Immediate operands are read from memory, jumps
+ * relative to the pc show the address they land on.
+ *
+ * Opcodes follow a regular layout once split into fields
+ *
+ *   x: bits 7-6, y: bits 5-3, z: bits 2-0
+ *   p: bits 5-4, q: bit 3
+ *
+ * which is how the tables below are indexed.
+ */
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STACK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A, ", "ADC A, ", "SUB ", "SBC A, ", "AND ", "XOR ", "OR ", "CP "];
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const ACCUMULATOR: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+pub fn disassemble(mmu: &MMU, pc: u16) -> (String, u16) {
+    let opcode = mmu.peek(pc);
+    let n8 = mmu.peek(pc.wrapping_add(1));
+    let n16 = ((mmu.peek(pc.wrapping_add(2)) as u16) << 8) | n8 as u16;
+
+    /* JR targets are relative to the end of the two byte instruction */
+    let relative = pc.wrapping_add(2).wrapping_add(n8 as i8 as u16);
+    let signed = if (n8 as i8) < 0 {
+        format!("-0x{:02X}", (n8 as i8).unsigned_abs())
+    } else {
+        format!("+0x{:02X}", n8)
+    };
+
+    let x = opcode >> 6;
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let z = opcode & 0x07;
+    let p = y >> 1;
+    let q = y & 0x01;
+
+    let (text, length) = match (x, z) {
+        (0, 0) => match y {
+            0 => (String::from("NOP"), 1),
+            1 => (format!("LD (0x{:04X}), SP", n16), 3),
+            2 => (String::from("STOP"), 2),
+            3 => (format!("JR 0x{:04X}", relative), 2),
+            _ => (format!("JR {}, 0x{:04X}", CONDITIONS[y - 4], relative), 2),
+        },
+        (0, 1) if q == 0 => (format!("LD {}, 0x{:04X}", R16[p], n16), 3),
+        (0, 1) => (format!("ADD HL, {}", R16[p]), 1),
+        (0, 2) => {
+            let address = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 {
+                (format!("LD {}, A", address), 1)
+            } else {
+                (format!("LD A, {}", address), 1)
+            }
+        },
+        (0, 3) if q == 0 => (format!("INC {}", R16[p]), 1),
+        (0, 3) => (format!("DEC {}", R16[p]), 1),
+        (0, 4) => (format!("INC {}", R8[y]), 1),
+        (0, 5) => (format!("DEC {}", R8[y]), 1),
+        (0, 6) => (format!("LD {}, 0x{:02X}", R8[y], n8), 2),
+        (0, _) => (String::from(ACCUMULATOR[y]), 1),
+
+        (1, 6) if y == 6 => (String::from("HALT"), 1),
+        (1, _) => (format!("LD {}, {}", R8[y], R8[z as usize]), 1),
+
+        (2, _) => (format!("{}{}", ALU[y], R8[z as usize]), 1),
+
+        (3, 0) => match y {
+            0..=3 => (format!("RET {}", CONDITIONS[y]), 1),
+            4 => (format!("LDH (0xFF{:02X}), A", n8), 2),
+            5 => (format!("ADD SP, {}", signed), 2),
+            6 => (format!("LDH A, (0xFF{:02X})", n8), 2),
+            _ => (format!("LD HL, SP{}", signed), 2),
+        },
+        (3, 1) if q == 0 => (format!("POP {}", R16_STACK[p]), 1),
+        (3, 1) => (String::from(["RET", "RETI", "JP HL", "LD SP, HL"][p]), 1),
+        (3, 2) => match y {
+            0..=3 => (format!("JP {}, 0x{:04X}", CONDITIONS[y], n16), 3),
+            4 => (String::from("LD (C), A"), 1),
+            5 => (format!("LD (0x{:04X}), A", n16), 3),
+            6 => (String::from("LD A, (C)"), 1),
+            _ => (format!("LD A, (0x{:04X})", n16), 3),
+        },
+        (3, 3) => match y {
+            0 => (format!("JP 0x{:04X}", n16), 3),
+            1 => (disassemble_prefixed(n8), 2),
+            6 => (String::from("DI"), 1),
+            7 => (String::from("EI"), 1),
+            _ => illegal(opcode),
+        },
+        (3, 4) if y < 4 => (format!("CALL {}, 0x{:04X}", CONDITIONS[y], n16), 3),
+        (3, 5) if q == 0 => (format!("PUSH {}", R16_STACK[p]), 1),
+        (3, 5) if p == 0 => (format!("CALL 0x{:04X}", n16), 3),
+        (3, 6) => (format!("{}0x{:02X}", ALU[y], n8), 2),
+        (3, 7) => (format!("RST 0x{:02X}", y * 8), 1),
+        _ => illegal(opcode),
+    };
+
+    (text, length)
+}
+
+/* The second byte of a CB prefixed instruction */
+fn disassemble_prefixed(opcode: u8) -> String {
+    let y = ((opcode >> 3) & 0x07) as usize;
+    let r = R8[(opcode & 0x07) as usize];
+
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATIONS[y], r),
+        1 => format!("BIT {}, {}", y, r),
+        2 => format!("RES {}, {}", y, r),
+        _ => format!("SET {}, {}", y, r),
+    }
+}
+
+/* Illegal opcodes lock up the cpu, they're shown as data a byte at a time */
+fn illegal(opcode: u8) -> (String, u16) {
+    (format!("ILLEGAL 0x{:02X}", opcode), 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::rom::BootRomVariant;
+
+    fn decode(bytes: &[u8]) -> (String, u16) {
+        let mut mmu = MMU::skip_boot(Cartridge::zero(), BootRomVariant::DMG);
+        for (i, b) in bytes.iter().enumerate() {
+            mmu.set(0xC000 + i as u16, *b);
+        }
+
+        disassemble(&mmu, 0xC000)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(decode(&[0x00]), (String::from("NOP"), 1));
+        assert_eq!(decode(&[0xC3, 0x50, 0x01]), (String::from("JP 0x0150"), 3));
+        assert_eq!(decode(&[0x3E, 0x42]), (String::from("LD A, 0x42"), 2));
+        assert_eq!(decode(&[0x21, 0x34, 0x12]), (String::from("LD HL, 0x1234"), 3));
+        assert_eq!(decode(&[0x77]), (String::from("LD (HL), A"), 1));
+        assert_eq!(decode(&[0x76]), (String::from("HALT"), 1));
+        assert_eq!(decode(&[0xE0, 0x40]), (String::from("LDH (0xFF40), A"), 2));
+        assert_eq!(decode(&[0xAF]), (String::from("XOR A"), 1));
+        assert_eq!(decode(&[0xFE, 0x90]), (String::from("CP 0x90"), 2));
+        assert_eq!(decode(&[0xCD, 0x00, 0x02]), (String::from("CALL 0x0200"), 3));
+        assert_eq!(decode(&[0xF5]), (String::from("PUSH AF"), 1));
+        assert_eq!(decode(&[0xFF]), (String::from("RST 0x38"), 1));
+        assert_eq!(decode(&[0xF8, 0xFE]), (String::from("LD HL, SP-0x02"), 2));
+    }
+
+    #[test]
+    fn test_disassemble_relative_jumps() {
+        /* Relative to the end of the instruction at 0xC002 */
+        assert_eq!(decode(&[0x18, 0xFE]), (String::from("JR 0xC000"), 2));
+        assert_eq!(decode(&[0x20, 0x05]), (String::from("JR NZ, 0xC007"), 2));
+    }
+
+    #[test]
+    fn test_disassemble_prefixed() {
+        assert_eq!(decode(&[0xCB, 0x7C]), (String::from("BIT 7, H"), 2));
+        assert_eq!(decode(&[0xCB, 0x11]), (String::from("RL C"), 2));
+        assert_eq!(decode(&[0xCB, 0x37]), (String::from("SWAP A"), 2));
+        assert_eq!(decode(&[0xCB, 0xFE]), (String::from("SET 7, (HL)"), 2));
+    }
+
+    #[test]
+    fn test_disassemble_illegal() {
+        assert_eq!(decode(&[0xD3]), (String::from("ILLEGAL 0xD3"), 1));
+        assert_eq!(decode(&[0xFD]), (String::from("ILLEGAL 0xFD"), 1));
+        assert_eq!(decode(&[0xE4]), (String::from("ILLEGAL 0xE4"), 1));
+    }
+}
@@ -0,0 +1,37 @@
+/* The emulator core
+ *
+ * Gameboy can be driven directly with step_instruction and step_frame, or
+ * run on its own thread with next_frame sending each frame over a channel
+ * which is how the SDL frontend in main.rs uses it.
+ */
+
+pub mod gameboy;
+pub mod msg;
+pub mod shade;
+pub mod palette;
+pub mod pixel;
+pub mod framebuffer;
+pub mod device;
+pub mod rom;
+pub mod cartridge;
+pub mod cheat;
+pub mod config;
+pub mod color;
+
+mod register;
+mod bytes;
+mod cpu;
+mod gpu;
+mod mmu;
+mod tile;
+mod helpers;
+mod mbc;
+mod instruction;
+mod watcher;
+mod trace;
+mod mooneye;
+mod dump;
+mod state;
+mod png;
+
+pub use gameboy::Gameboy;
@@ -1,29 +1,18 @@
 #[macro_use]
 extern crate clap;
 
-use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::{channel, sync_channel};
 use std::thread;
+use std::io::Write;
+use std::time::{Duration, Instant};
 
 mod sdl;
-mod gameboy;
-mod framebuffer;
-mod palette;
-mod pixel;
-mod shade;
-mod register;
-mod bytes;
-mod cpu;
-mod gpu;
-mod mmu;
-mod tile;
-mod device;
-mod rom;
-mod helpers;
-mod cartridge;
-mod msg;
-mod instruction;
-
-use gameboy::Gameboy;
+mod repl;
+
+use gameboy_emulator_v2::{msg, shade, config, device, framebuffer, color};
+use gameboy_emulator_v2::Gameboy;
+use config::Config;
+use device::oam::SpriteLimit;
 
 fn main() {
     let matches = clap_app!(anders_gameboy_emulator =>
@@ -31,27 +20,183 @@ fn main() {
         (author: "Anders Conbere <anders@conbere.org>")
         (about: "Emulates a gameboy V2")
         (@arg BOOT_ROM: --boot_rom +takes_value +required "The file of the boot rom to load.")
+        (@arg BOOT_VARIANT: --boot_variant +takes_value "The model of the boot rom: dmg0, dmg, or mgb (default dmg).")
         (@arg GAME_ROM: --game_rom +takes_value +required "The file of the game rom to load.")
-        (@arg LOG: --log "If true print debug output.")
+        (@arg LOG: --log "If true print debug output and a line for every executed instruction.")
+        (@arg LOG_CONFLICTS: --log_conflicts "If true print VRAM and OAM accesses the PPU would block.")
+        (@arg PRINT_SERIAL: --print_serial "If true print bytes sent over the serial port, test roms report results this way.")
         (@arg SKIP_BOOT: --skip_boot "If true skips booting from the rom.")
         (@arg CONFIG: --config +takes_value "An optional configuration file to read.")
+        (@arg TRACE_HASH: --trace_hash "If true print a running hash of executed instructions each frame.")
+        (@arg SPRITE_LIMIT: --sprite_limit +takes_value "Max sprites drawn per line, a number or \"unlimited\" (default 10).")
+        (@arg BOOT_HANG_FRAMES: --boot_hang_frames +takes_value "Frames spent in the boot rom before warning it isn't handing off, 0 disables (default 600).")
+        (@arg CHEAT: --cheat +takes_value +multiple "A Game Genie (ABC-DEF-GHI) or Game Shark (01VVLLHH) code to apply.")
+        (@arg TURBO_TOGGLE: --turbo_toggle "If true the turbo key (tab) toggles fast forward instead of being held.")
+        (@arg SCALE: --scale +takes_value "How many times larger than the gameboy screen to draw it, 1 to 8 (default 4).")
+        (@arg NO_DEBUG_VIEW: --no_debug_view "If true show only the screen, the tile map and tile set panels can still be toggled with D.")
+        (@arg SPEED: --speed +takes_value "Multiplies the target frame rate, 2 runs twice as fast (default 1).")
+        (@arg MUTE: --mute "If true disable audio output.")
+        (@arg UNLIMITED: --unlimited "If true run as fast as possible and report instructions per second.")
+        (@arg DUMP_VRAM_ON_EXIT: --dump_vram_on_exit +takes_value "Write VRAM, OAM and the IO registers to this file on exit.")
+        (@arg DEBUGGER: --debugger "If true read debugger commands from stdin.")
+        (@arg WATCH_READ: --watch_read +takes_value +multiple "Enter the debug state when this hex address is read.")
     ).get_matches();
 
     let (sender, receiver) = sync_channel(0);
+    let (input_sender, input_receiver) = channel();
+    let (audio_sender, audio_receiver) = channel();
+    let mute = matches.is_present("MUTE");
+    let unlimited = matches.is_present("UNLIMITED");
+    let turbo_toggle = matches.is_present("TURBO_TOGGLE");
+    let speed: f64 = matches.value_of("SPEED").unwrap_or("1").parse().unwrap();
+    assert!(speed > 0.0, "--speed must be greater than 0");
+    let scale: u32 = matches.value_of("SCALE").unwrap_or("4").parse().unwrap();
+    assert!((sdl::MIN_SCALE..=sdl::MAX_SCALE).contains(&scale), "--scale must be between {} and {}", sdl::MIN_SCALE, sdl::MAX_SCALE);
+    let config = match matches.value_of("CONFIG") {
+        Some(path) => Config::read(path).unwrap(),
+        None => Config::new(),
+    };
+
+    let debug_output = if matches.is_present("DEBUGGER") {
+        let (debug_sender, debug_receiver) = channel();
+        let link = repl::Link::new(input_sender.clone(), debug_receiver);
+
+        /* Blocks on stdin so it's never joined, it ends with the process */
+        thread::spawn(move || repl::run(link));
+        Some(debug_sender)
+    } else {
+        None
+    };
+
+    /* Without a display there's nothing to run, see examples/headless.rs
+     * for running the core on its own.
+     */
+    let mut display = match sdl::SDL::new(receiver, input_sender, scale, !matches.is_present("NO_DEBUG_VIEW")) {
+        Ok(display) => display,
+        Err(e) => {
+            eprintln!("could not start the display: {}", e);
+            std::process::exit(1);
+        },
+    };
+    display.set_colors(config.colors);
+    display.set_color_correction(config.color_correction);
+    if !mute {
+        if let Err(e) = display.enable_audio(audio_receiver) {
+            eprintln!("could not start audio, continuing without: {}", e);
+        }
+    }
+    if unlimited {
+        display.set_unlimited();
+    }
+    display.set_speed(speed);
+    if turbo_toggle {
+        display.set_turbo_mode(sdl::TurboMode::Toggle);
+    }
+
+    let emulator = thread::spawn(move || {
+        let boot_variant = matches.value_of("BOOT_VARIANT")
+            .unwrap_or("dmg")
+            .parse()
+            .unwrap();
 
-    thread::spawn(move || {
         let mut gameboy = Gameboy::new(
             matches.value_of("BOOT_ROM").unwrap(),
+            boot_variant,
             matches.value_of("GAME_ROM").unwrap(),
             matches.is_present("SKIP_BOOT"),
-            sender,
         ).unwrap();
+        gameboy.set_frame_output(sender);
+        gameboy.set_input(input_receiver);
+        if let Some(debug_output) = debug_output {
+            gameboy.set_debug_output(debug_output);
+        }
+        if !mute {
+            gameboy.set_audio(audio_sender);
+        }
+
+        if let Some(addresses) = matches.values_of("WATCH_READ") {
+            for a in addresses {
+                let address = u16::from_str_radix(a.trim_start_matches("0x"), 16).unwrap();
+                gameboy.cpu.watch_read(address);
+            }
+        }
+
+        if let Some(codes) = matches.values_of("CHEAT") {
+            for code in codes {
+                gameboy.add_cheat(code).unwrap();
+            }
+        }
+
+        if matches.is_present("LOG") {
+            gameboy.cpu.set_logging(true);
+        }
+
+        if matches.is_present("PRINT_SERIAL") {
+            gameboy.cpu.capture_serial();
+        }
 
-        loop {
-            gameboy.next_frame();
+        if matches.is_present("LOG_CONFLICTS") {
+            gameboy.cpu.set_conflict_logging(true);
+        }
+
+        if let Some(limit) = matches.value_of("SPRITE_LIMIT") {
+            let limit = match limit {
+                "unlimited" => SpriteLimit::Unlimited,
+                n => SpriteLimit::Limited(n.parse().unwrap()),
+            };
+            gameboy.cpu.set_sprite_limit(limit);
+        }
+
+        if let Some(frames) = matches.value_of("BOOT_HANG_FRAMES") {
+            let frames: u64 = frames.parse().unwrap();
+            gameboy.set_boot_hang_limit(if frames == 0 { None } else { Some(frames) });
+        }
+
+        if matches.is_present("TRACE_HASH") {
+            gameboy.cpu.enable_trace_hash();
+        }
+
+        let mut last_report = Instant::now();
+        let mut last_instructions = 0;
+
+        while gameboy.next_frame() {
+            if unlimited && last_report.elapsed() >= Duration::from_secs(1) {
+                let instructions = gameboy.cpu.instruction_count();
+                let rate = (instructions - last_instructions) as f64 / last_report.elapsed().as_secs_f64();
+                println!("{:.0} instructions/sec", rate);
+                last_instructions = instructions;
+                last_report = Instant::now();
+            }
+
+            let serial = gameboy.cpu.take_serial_output();
+            if !serial.is_empty() {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&serial).unwrap();
+                stdout.flush().unwrap();
+            }
+
+            if gameboy.boot_hung() {
+                println!("boot ROM not handing off after {} frames, check ROM files", gameboy.frames());
+                gameboy.set_boot_hang_limit(None);
+            }
+
+            if let Some(digest) = gameboy.cpu.trace_digest() {
+                println!("frame {} trace hash: {:016X}", gameboy.frames(), digest);
+            }
+        }
+
+        if let Some(path) = matches.value_of("DUMP_VRAM_ON_EXIT") {
+            gameboy.write_state_dump(path).unwrap();
         }
     });
 
-    let mut display = sdl::SDL::new(receiver).unwrap();
-    display.start();
+    if let Err(e) = display.start() {
+        eprintln!("display error: {}", e);
+    }
+
+    /* Dropping the display disconnects the frame channel which stops the
+     * emulator, wait for it so it can finish writing anything on exit.
+     */
+    drop(display);
+    emulator.join().unwrap();
 }
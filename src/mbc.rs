@@ -0,0 +1,290 @@
+use std::io::{Error, ErrorKind};
+
+use crate::cartridge::CartridgeType;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/* Memory bank controllers
+ *
+ * Cartridges bigger than 32KB sit behind a controller that maps banks of
+ * rom into 0x4000 - 0x7FFF (and of ram into 0xA000 - 0xBFFF) in response to
+ * writes into the rom address space.
+ */
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+pub enum Mbc {
+    None,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc5(Mbc5),
+}
+
+impl Mbc {
+    pub fn new(cart_type: CartridgeType) -> Mbc {
+        match cart_type {
+            CartridgeType::MCB1 |
+            CartridgeType::MCB1RAM |
+            CartridgeType::MCB1RAMBattery => Mbc::Mbc1(Mbc1::new()),
+            CartridgeType::MCB2 |
+            CartridgeType::MCB2Battery => Mbc::Mbc2(Mbc2::new()),
+            CartridgeType::MCB5 |
+            CartridgeType::MCB5RAM |
+            CartridgeType::MCB5RAMBattery |
+            CartridgeType::MCB5Rumble |
+            CartridgeType::MCB5RumbleRAM |
+            CartridgeType::MCB5RumbleRAMBattery => Mbc::Mbc5(Mbc5::new()),
+            _ => Mbc::None,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match self {
+            Mbc::None => {},
+            Mbc::Mbc1(mbc) => mbc.write(address, value),
+            Mbc::Mbc2(mbc) => mbc.write(address, value),
+            Mbc::Mbc5(mbc) => mbc.write(address, value),
+        }
+    }
+
+    /* Offset into the rom for an address in 0x0000 - 0x7FFF */
+    pub fn rom_offset(&self, address: u16) -> usize {
+        let (bank_0, bank_n) = match self {
+            Mbc::None => (0, 1),
+            Mbc::Mbc1(mbc) => (mbc.rom_bank_0(), mbc.rom_bank()),
+            Mbc::Mbc2(mbc) => (0, mbc.rom_bank as usize),
+            Mbc::Mbc5(mbc) => (0, mbc.rom_bank as usize),
+        };
+
+        let bank = if address < 0x4000 { bank_0 } else { bank_n };
+        bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    /* Offset into the ram for an address relative to 0xA000, None if ram
+     * is disabled.
+     */
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        match self {
+            Mbc::None => Some(address as usize),
+            Mbc::Mbc1(mbc) => {
+                if mbc.ram_enabled {
+                    Some(mbc.ram_bank() * RAM_BANK_SIZE + address as usize)
+                } else {
+                    None
+                }
+            }
+            Mbc::Mbc2(mbc) => {
+                if mbc.ram_enabled {
+                    Some(address as usize & (MBC2_RAM_SIZE - 1))
+                } else {
+                    None
+                }
+            }
+            Mbc::Mbc5(mbc) => {
+                if mbc.ram_enabled {
+                    Some(mbc.ram_bank as usize * RAM_BANK_SIZE + address as usize)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /* The bits of each ram byte the controller stores, the rest read as 1s */
+    pub fn ram_mask(&self) -> u8 {
+        match self {
+            Mbc::Mbc2(_) => 0x0F,
+            _ => 0xFF,
+        }
+    }
+}
+
+/* MBC1
+ *
+ * 0x0000 - 0x1FFF: RAM enable, 0x_A in the low nibble enables
+ * 0x2000 - 0x3FFF: low 5 bits of the rom bank, 0 is treated as 1
+ * 0x4000 - 0x5FFF: 2 more bits, either the ram bank or the high rom bits
+ * 0x6000 - 0x7FFF: banking mode, in mode 1 the 2 bits also apply to ram
+ *                  banking and to the 0x0000 - 0x3FFF region
+ */
+pub struct Mbc1 {
+    rom_bank: u8,
+    high: u8,
+    mode: bool,
+    ram_enabled: bool,
+}
+
+impl Mbc1 {
+    pub fn new() -> Mbc1 {
+        Mbc1 {
+            rom_bank: 1,
+            high: 0,
+            mode: false,
+            ram_enabled: false,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = (value & 0x0F) == 0x0A,
+            0x2000..=0x3FFF => {
+                /* Bank 0 can't be selected here, it's remapped to 1 */
+                let bank = value & 0x1F;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            },
+            0x4000..=0x5FFF => self.high = value & 0x03,
+            0x6000..=0x7FFF => self.mode = (value & 0x01) == 0x01,
+            _ => {},
+        }
+    }
+
+    fn rom_bank_0(&self) -> usize {
+        if self.mode {
+            (self.high as usize) << 5
+        } else {
+            0
+        }
+    }
+
+    fn rom_bank(&self) -> usize {
+        ((self.high as usize) << 5) | self.rom_bank as usize
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.mode {
+            self.high as usize
+        } else {
+            0
+        }
+    }
+}
+
+/* MBC2
+ *
+ * 0x0000 - 0x3FFF: with address bit 8 clear, RAM enable, 0x_A in the low
+ *                  nibble enables. With it set, the 4 bit rom bank, 0 is
+ *                  treated as 1
+ *
+ * The ram is built into the controller, 512 half bytes repeating across
+ * 0xA000 - 0xBFFF.
+ */
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+pub struct Mbc2 {
+    rom_bank: u8,
+    ram_enabled: bool,
+}
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            rom_bank: 1,
+            ram_enabled: false,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x3FFF if address & 0x0100 == 0 => self.ram_enabled = (value & 0x0F) == 0x0A,
+            0x0000..=0x3FFF => {
+                let bank = value & 0x0F;
+                self.rom_bank = if bank == 0 { 1 } else { bank };
+            },
+            _ => {},
+        }
+    }
+}
+
+/* MBC5
+ *
+ * 0x0000 - 0x1FFF: RAM enable, 0x_A in the low nibble enables
+ * 0x2000 - 0x2FFF: low 8 bits of the rom bank
+ * 0x3000 - 0x3FFF: bit 8 of the rom bank
+ * 0x4000 - 0x5FFF: 4 bit ram bank
+ *
+ * Unlike MBC1 any bank, including 0, can be mapped into 0x4000 - 0x7FFF.
+ */
+pub struct Mbc5 {
+    rom_bank: u16,
+    ram_bank: u8,
+    ram_enabled: bool,
+}
+
+impl Mbc5 {
+    pub fn new() -> Mbc5 {
+        Mbc5 {
+            rom_bank: 1,
+            ram_bank: 0,
+            ram_enabled: false,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = (value & 0x0F) == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as u16 & 0x01) << 8),
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {},
+        }
+    }
+}
+
+/* Written as the controller kind followed by its registers, loading into
+ * a cartridge with a different controller fails.
+ */
+impl SaveState for Mbc {
+    fn save_state(&self, w: &mut StateWriter) {
+        match self {
+            Mbc::None => w.u8(0),
+            Mbc::Mbc1(mbc) => {
+                w.u8(1);
+                w.u8(mbc.rom_bank);
+                w.u8(mbc.high);
+                w.bool(mbc.mode);
+                w.bool(mbc.ram_enabled);
+            },
+            Mbc::Mbc2(mbc) => {
+                w.u8(3);
+                w.u8(mbc.rom_bank);
+                w.bool(mbc.ram_enabled);
+            },
+            Mbc::Mbc5(mbc) => {
+                w.u8(2);
+                w.u16(mbc.rom_bank);
+                w.u8(mbc.ram_bank);
+                w.bool(mbc.ram_enabled);
+            },
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        let kind = r.u8()?;
+
+        match self {
+            Mbc::None if kind == 0 => {},
+            Mbc::Mbc1(mbc) if kind == 1 => {
+                mbc.rom_bank = r.u8()?;
+                mbc.high = r.u8()?;
+                mbc.mode = r.bool()?;
+                mbc.ram_enabled = r.bool()?;
+            },
+            Mbc::Mbc2(mbc) if kind == 3 => {
+                mbc.rom_bank = r.u8()?;
+                mbc.ram_enabled = r.bool()?;
+            },
+            Mbc::Mbc5(mbc) if kind == 2 => {
+                mbc.rom_bank = r.u16()?;
+                mbc.ram_bank = r.u8()?;
+                mbc.ram_enabled = r.bool()?;
+            },
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid save state: memory bank controller doesn't match the cartridge",
+                ))
+            },
+        }
+        Ok(())
+    }
+}
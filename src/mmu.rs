@@ -1,114 +1,40 @@
+use std::cell::RefCell;
+use std::io::Error;
+
 use crate::bytes;
 use crate::gpu::GPU;
 use crate::cartridge::Cartridge;
 use crate::device::Device;
-use crate::device::ram::{Ram2k, Ram8k, HighRam};
-use crate::device::lcd::LCD;
+use crate::device::ram::{Ram8k, HighRam};
+use crate::device::lcd::{LCD, Mode};
 use crate::device::interrupt::InterruptFlag;
-use crate::rom::BootRom;
-
-#[derive(Debug, Clone, Copy)]
-pub enum Frequency {
-    F1024 = 1024,
-    F16   = 16,
-    F64   = 64,
-    F256  = 256,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub struct TimerControl {
-    enabled: bool,
-    frequency: Frequency,
-}
-
-impl TimerControl {
-    pub fn new() -> TimerControl {
-        TimerControl {
-            enabled: false,
-            frequency: Frequency::F1024,
-        }
-    }
-}
-
-impl std::convert::From<u8> for TimerControl {
-    fn from(byte: u8) -> Self {
-        let f = match (bytes::check_bit(byte, 0), bytes::check_bit(byte, 1)) {
-            (false, false) => Frequency::F1024,
-            (false, true)  => Frequency::F16,
-            (true, false)  => Frequency::F64,
-            (true, true)   => Frequency::F256,
-        };
-
-        TimerControl {
-            enabled: bytes::check_bit(byte, 2),
-            frequency: f,
-        }
-    }
-}
-
-impl std::convert::From<TimerControl> for u8 {
-    fn from(t: TimerControl) -> Self {
-        let mut u:u8 = 0;
-
-        u = match t.frequency {
-            Frequency::F1024 => u,
-            Frequency::F16 => u | 0b0000_00001,
-            Frequency::F64 => u | 0b0000_00010,
-            Frequency::F256 => u | 0b0000_00011,
-        };
-
-        u = bytes::set_bit(u, 2, t.enabled);
-
-        u
-    }
-}
-
-pub struct Timer {
-    pub clock: u16,
-    pub tma: u8,
-    pub tima: u8,
-    pub tac: TimerControl,
-
-    pub tima_clock: u16,
-}
-
-impl Timer {
-    pub fn advance_cycles(&mut self, n: u8) -> bool {
-        self.clock = self.clock.wrapping_add(n as u16);
-
-        if self.tac.enabled {
-            self.tima_clock = self.clock.wrapping_add(n as u16);
-
-            if self.tima_clock >= self.tac.frequency as u16 {
-                let (v, overflow) = self.tima.overflowing_add(1);
-
-                if overflow {
-                    self.tima = self.tma;
-                } else {
-                    self.tima = v;
-                }
-
-                self.tima_clock = 0;
-
-                return overflow
-            }
-        }
-        false
-    }
-
-    pub fn get_div(&self, ) -> u8 {
-        (self.clock >> 8) as u8
-    }
-
-    pub fn new() -> Timer {
-        Timer {
-            clock: 0,
-            tma: 0,
-            tima: 0,
-            tac: TimerControl::new(),
-            tima_clock: 0,
-        }
-    }
+use crate::device::oam::Oam;
+use crate::device::timer::Timer;
+use crate::device::serial::Serial;
+use crate::device::joypad::Joypad;
+use crate::device::speed::Speed;
+use crate::device::cgb_palette::CgbPalettes;
+use crate::device::apu::{Apu, DEFAULT_SAMPLE_RATE};
+use crate::rom::{BootRom, BootRomVariant};
+use crate::watcher::MemoryWatcher;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/* Peripherals mapped into the 0xFF00 - 0xFF7F IO register range */
+enum IORef {
+    Joypad,
+    Serial,
+    Timer,
+    InterruptFlag,
+    Audio,
+    LCD,
+    Speed,
+    BackgroundPalettes,
+    BootRomDisable,
+
+    /* Nothing is connected, reads see an open bus (0xFF) and writes are
+     * dropped
+     */
+    Unmapped,
 }
 
 enum DeviceRef {
@@ -128,47 +54,86 @@ enum DeviceRef {
 
 pub struct MMU {
     boot_rom: BootRom,
-    cartridge: Cartridge,
-    io: Ram2k,
+    pub cartridge: Cartridge,
     ram: Ram8k,
     high_ram: HighRam,
 
     pub interrupt_enable: InterruptFlag,
     pub interrupt_flag: InterruptFlag,
 
+    /* IE's top 3 bits don't enable anything but read back as written */
+    interrupt_enable_unused: u8,
+
     pub lcd: LCD,
     pub gpu: GPU,
+    pub oam: Oam,
 
     pub timer: Timer,
+    pub serial: Serial,
+    pub joypad: Joypad,
+    pub apu: Apu,
+    pub speed: Speed,
+    pub background_palettes: CgbPalettes,
+
+    pub watcher: MemoryWatcher,
+
+    /* Accesses the PPU would block on hardware, only collected when
+     * enabled. Reads go through &self so this needs a RefCell.
+     */
+    pub conflicts: Option<RefCell<Vec<String>>>,
+
+    /* Cycles left in the current OAM DMA transfer */
+    dma_cycles: u16,
 
     booted: bool,
 }
 
+/* An OAM DMA transfer copies one byte per machine cycle */
+pub const DMA_CYCLES: u16 = 0xA0 * 4;
+
 impl MMU {
     pub fn new(boot_rom: BootRom, cartridge: Cartridge) -> MMU {
         MMU {
             boot_rom: boot_rom,
             cartridge: cartridge,
-            io: Ram2k::new(),
             ram: Ram8k::new(),
             high_ram: HighRam::new(),
             interrupt_enable: InterruptFlag::new(),
             interrupt_flag: InterruptFlag::new(),
+            interrupt_enable_unused: 0,
 
             lcd: LCD::new(),
             gpu: GPU::new(),
+            oam: Oam::new(),
 
             timer: Timer::new(),
+            serial: Serial::new(),
+            joypad: Joypad::new(),
+            apu: Apu::new(DEFAULT_SAMPLE_RATE),
+            speed: Speed::new(),
+            background_palettes: CgbPalettes::new(),
+
+            watcher: MemoryWatcher::new(),
+
+            conflicts: None,
+
+            dma_cycles: 0,
 
             booted: false,
         }
     }
 
-    pub fn skip_boot(cartridge: Cartridge) -> MMU {
+    /* The io registers as the boot rom leaves them, Registers::skip_boot
+     * has the cpu's half and CPUManager::skip_boot puts the two together.
+     */
+    pub fn skip_boot(cartridge: Cartridge, variant: BootRomVariant) -> MMU {
         let mut mmu = MMU::new(BootRom::zero(), cartridge);
         mmu.set(0xFF05, 0x00);
         mmu.set(0xFF06, 0x00);
         mmu.set(0xFF07, 0x00);
+
+        /* The sound registers only take writes with the APU powered on */
+        mmu.set(0xFF26, 0xF1);
         mmu.set(0xFF10, 0x80);
         mmu.set(0xFF11, 0xBF);
         mmu.set(0xFF12, 0xF3);
@@ -186,7 +151,6 @@ impl MMU {
         mmu.set(0xFF23, 0xBF);
         mmu.set(0xFF24, 0x77);
         mmu.set(0xFF25, 0xF3);
-        mmu.set(0xFF26, 0xF1);
         mmu.set(0xFF40, 0x91);
         mmu.set(0xFF42, 0x00);
         mmu.set(0xFF43, 0x00);
@@ -197,34 +161,128 @@ impl MMU {
         mmu.set(0xFF4A, 0x00);
         mmu.set(0xFF4B, 0x00);
         mmu.set(0xFFFF, 0x00);
+
+        /* The boot rom leaves a VBlank interrupt requested */
+        mmu.set(0xFF0F, 0x01);
+
+        /* and hands off on the last line of VBlank with LY=LYC, on hardware
+         * LY already reads 0 for most of line 153.
+         */
+        mmu.lcd.lines = 153;
+        mmu.lcd.status.mode = Mode::VBlank;
+        mmu.lcd.status.coincidence = true;
+
+        /* The divider has been counting since power on. The low byte can't
+         * be read directly, DMG and MGB's is from mooneye's boot_div tests
+         * but DMG0's isn't known so it starts at the top of 0x18.
+         */
+        mmu.timer.clock = match variant {
+            BootRomVariant::DMG0 => 0x1800,
+            BootRomVariant::DMG | BootRomVariant::MGB => 0xABCC,
+        };
+
         mmu
     }
 
     pub fn get(&self, address: u16) -> u8 {
+        match self.get_device(address) {
+            (_, DeviceRef::VRam) => self.check_conflict(address, "VRAM read"),
+            (_, DeviceRef::SpriteTable) if !self.dma_active() => self.check_conflict(address, "OAM read"),
+            _ => {},
+        }
+
+        let value = self.peek(address);
+
+        if self.watcher.is_enabled() {
+            self.watcher.check_read(address, value);
+        }
+
+        value
+    }
+
+    /* Reads like get but without tripping read watches or logging PPU
+     * conflicts, for the debugger and other tools looking at memory
+     */
+    pub fn peek(&self, address: u16) -> u8 {
         match self.get_device(address) {
             (start, DeviceRef::BootRom) => self.boot_rom.get(address - start),
             (_, DeviceRef::Cartridge) => self.cartridge.get(address),
-            (start, DeviceRef::CartridgeRam) => self.cartridge.ram.get(address - start),
+            (start, DeviceRef::CartridgeRam) => self.cartridge.get_ram(address - start),
             (_, DeviceRef::VRam) => self.gpu.get(address),
             (start, DeviceRef::Ram) => self.ram.get(address - start),
-            (_, DeviceRef::Unused) => 0x00,
-            (start, DeviceRef::IORegisters) => {
-                match address {
-                    0xFF04 => self.timer.get_div(),
-                    0xFF05 => self.timer.tima,
-                    0xFF06 => self.timer.tma,
-                    0xFF07 => u8::from(self.timer.tac),
-                    0xFF0F => u8::from(self.interrupt_flag),
-                    0xFF40..=0xFF4B => self.lcd.get(address - start),
-                    _ => self.io.get(address - start)
+            (start, DeviceRef::SpriteTable) => {
+                /* OAM is busy for the length of a DMA transfer */
+                if self.dma_active() {
+                    return 0xFF;
                 }
+                self.oam.get(address - start)
             },
+            (_, DeviceRef::Unused) => 0x00,
+            (_, DeviceRef::IORegisters) => self.get_io(address),
             (start, DeviceRef::HighRam) => self.high_ram.get(address - start),
-            (_, DeviceRef::InterruptEnable) => u8::from(self.interrupt_enable),
-            _ => panic!("Get Memory Not implemented: {:X}", address),
+            (_, DeviceRef::InterruptEnable) => self.get_interrupt_enable(),
+        }
+    }
+
+    fn get_interrupt_enable(&self) -> u8 {
+        (u8::from(self.interrupt_enable) & 0x1F) | self.interrupt_enable_unused
+    }
+
+    /* While the PPU is drawing the cpu can't reach VRAM (mode 3) or OAM
+     * (modes 2 and 3), writes are dropped and reads return 0xFF. We don't
+     * gate the accesses yet, they still go through, but log them so it's
+     * clear when a rom is racing the PPU.
+     */
+    fn check_conflict(&self, address: u16, access: &str) {
+        let conflicts = match self.conflicts.as_ref() {
+            Some(conflicts) => conflicts,
+            None => return,
+        };
+
+        if !self.lcd.control.display_enabled {
+            return
+        }
+
+        let mode = self.lcd.status.mode;
+        let blocked = match address {
+            0x8000..=0x9FFF => mode == Mode::VRAM,
+            _ => mode == Mode::VRAM || mode == Mode::OAM,
+        };
+
+        if blocked {
+            conflicts.borrow_mut().push(
+                format!("{} {:04X} during {:?}, not blocked", access, address, mode)
+            );
+        }
+    }
+
+    fn get_io(&self, address: u16) -> u8 {
+        let offset = address - 0xFF00;
+
+        match get_io_device(address, self.cartridge.cgb()) {
+            IORef::Joypad => self.joypad.get(offset),
+            IORef::Serial => self.serial.get(offset),
+            IORef::Timer => self.timer.get(offset),
+            IORef::InterruptFlag => u8::from(self.interrupt_flag),
+            IORef::Audio => self.apu.get(offset),
+            IORef::LCD => self.lcd.get(offset),
+            IORef::Speed => self.speed.get(offset),
+            IORef::BackgroundPalettes => self.background_palettes.get(address - 0xFF68),
+            IORef::BootRomDisable | IORef::Unmapped => 0xFF,
         }
     }
 
+    /* Raw VRAM (0x8000 - 0x9FFF), OAM and the IO registers
+     * (0xFF00 - 0xFF7F) for offline debugging.
+     */
+    pub fn dump_state(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(0x2000 + 0xA0 + 0x80);
+        bytes.extend((0x8000..=0x9FFF).map(|a| self.gpu.get(a)));
+        bytes.extend((0x0000..0x00A0).map(|a| self.oam.get(a)));
+        bytes.extend((0xFF00..=0xFF7F).map(|a| self.get_io(a)));
+        bytes
+    }
+
     pub fn get16(&self, address: u16) -> u16 {
         let ls = self.get(address);
         let ms = self.get(address+1);
@@ -235,46 +293,79 @@ impl MMU {
         match self.get_device(address) {
             (_, DeviceRef::BootRom) => panic!("BootRom is read only: {:X}", address),
             (_, DeviceRef::Cartridge) => self.cartridge.set(address, value),
-            (start, DeviceRef::CartridgeRam) => self.cartridge.ram.set(address - start, value),
-            (_, DeviceRef::VRam) => self.gpu.set(address, value),
+            (start, DeviceRef::CartridgeRam) => self.cartridge.set_ram(address - start, value),
+            (_, DeviceRef::VRam) => {
+                self.check_conflict(address, "VRAM write");
+                self.gpu.set(address, value)
+            },
             (start, DeviceRef::Ram) => self.ram.set(address - start, value),
+            (start, DeviceRef::SpriteTable) => {
+                if self.dma_active() {
+                    return;
+                }
+                self.check_conflict(address, "OAM write");
+                self.oam.set(address - start, value)
+            },
             (_, DeviceRef::Unused) => {},
             (start, DeviceRef::IORegisters) => {
-                match address {
-                    0xFF04 => self.timer.clock = 0,
-                    0xFF05 => {
-                        self.timer.tima = value;
-                    },
-                    0xFF06 => {
-                        self.timer.tma = value;
-                    },
-                    0xFF07 => {
-                        self.timer.tac = TimerControl::from(value);
-                    },
-                    0xFF0F => {
-                        self.interrupt_flag = InterruptFlag::from(value);
+                match get_io_device(address, self.cartridge.cgb()) {
+                    IORef::Joypad => self.joypad.set(address - start, value),
+                    IORef::Serial => self.serial.set(address - start, value),
+                    IORef::Timer => self.timer.set(address - start, value),
+                    IORef::InterruptFlag => self.interrupt_flag = InterruptFlag::from(value),
+                    IORef::Audio => self.apu.set(address - start, value),
+                    IORef::LCD => {
+                        self.lcd.set(address - start, value);
+                        if address == 0xFF46 {
+                            self.dma_transfer(value);
+                        }
                     },
-                    0xFF40..=0xFF4B => self.lcd.set(address - start, value),
-                    0xFF50 => {
+                    IORef::Speed => self.speed.set(address - start, value),
+                    IORef::BackgroundPalettes => self.background_palettes.set(address - 0xFF68, value),
+                    IORef::BootRomDisable => {
                         if value == 1 {
                             self.booted = true;
                         }
                     }
-                    _ => self.io.set(address - start, value),
+                    IORef::Unmapped => {},
                 }
             }
             (start, DeviceRef::HighRam) => self.high_ram.set(address - start, value),
             (_start, DeviceRef::InterruptEnable) => {
-                self.interrupt_enable = InterruptFlag::from(value)
+                self.interrupt_enable = InterruptFlag::from(value);
+                self.interrupt_enable_unused = value & 0xE0;
             }
-            _ => panic!("Set Memory Not implemented: {:X}", address),
         }
     }
 
+    /* Writing n to 0xFF46 copies 0xn00 - 0xn9F into OAM. The copy happens
+     * up front but OAM stays locked to the cpu until the transfer would have
+     * finished on hardware.
+     */
+    fn dma_transfer(&mut self, page: u8) {
+        let source = (page as u16) << 8;
+
+        for i in 0..0xA0 {
+            let value = self.peek(source + i);
+            self.oam.set(i, value);
+        }
+
+        self.dma_cycles = DMA_CYCLES;
+    }
+
+    pub fn dma_active(&self) -> bool {
+        self.dma_cycles > 0
+    }
+
+    pub fn advance_dma(&mut self, cycles: u8) {
+        self.dma_cycles = self.dma_cycles.saturating_sub(cycles as u16);
+    }
+
+    /* Little endian like get16, the low byte goes at address */
     pub fn set16(&mut self, address: u16, value: u16) {
         let (ms, ls) = bytes::split_ms_ls(value);
-        self.set(address, ms);
-        self.set(address.wrapping_add(1), ls);
+        self.set(address, ls);
+        self.set(address.wrapping_add(1), ms);
     }
 
     fn get_device(&self, address: u16) -> (u16, DeviceRef) {
@@ -289,17 +380,36 @@ impl MMU {
             0x0100..=0x7FFF => (0x0150, DeviceRef::Cartridge),
             0x8000..=0x9FFF => (0x8000, DeviceRef::VRam),
             0xA000..=0xBFFF => (0xA000, DeviceRef::CartridgeRam),
-            0xC000..=0xE000 => (0xC000, DeviceRef::Ram),
+            0xC000..=0xDFFF => (0xC000, DeviceRef::Ram),
+            // Echo ram, mirrors 0xC000 - 0xDDFF
+            0xE000..=0xFDFF => (0xE000, DeviceRef::Ram),
             0xFE00..=0xFE9F => (0xFE00, DeviceRef::SpriteTable),
             0xFEA0..=0xFEFF => (0xFEA0, DeviceRef::Unused),
             0xFF00..=0xFF7F => (0xFF00, DeviceRef::IORegisters),
             0xFF80..=0xFFFE => (0xFF80, DeviceRef::HighRam),
             0xFFFF          => (0xFFFF, DeviceRef::InterruptEnable),
-            _ =>  panic!("unimplemented memory location: {:X}", address)
         }
     }
 }
 
+/* The CGB registers are only there for carts that run in CGB mode, a DMG
+ * cart sees unmapped io in their place
+ */
+fn get_io_device(address: u16, cgb: bool) -> IORef {
+    match address {
+        0xFF00          => IORef::Joypad,
+        0xFF01..=0xFF02 => IORef::Serial,
+        0xFF04..=0xFF07 => IORef::Timer,
+        0xFF0F          => IORef::InterruptFlag,
+        0xFF10..=0xFF3F => IORef::Audio,
+        0xFF40..=0xFF4B => IORef::LCD,
+        0xFF4D if cgb   => IORef::Speed,
+        0xFF50          => IORef::BootRomDisable,
+        0xFF68..=0xFF69 if cgb => IORef::BackgroundPalettes,
+        _               => IORef::Unmapped,
+    }
+}
+
 /*
         0x00FF...0x014F => Kind::CartridgeHeader,
         0x014F...0x3FFF => Kind::CartridgeROMBank0,
@@ -328,9 +438,60 @@ impl MMU {
         0xFFFF...0xFFFF => Kind::InterruptEnableFlag,
 */
 
+/* The boot rom, watch points and logging aren't part of the machine state.
+ * Audio isn't saved either, channels stay quiet until they're next
+ * triggered.
+ */
+impl SaveState for MMU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.cartridge.save_state(w);
+        self.ram.save_state(w);
+        self.high_ram.save_state(w);
+        w.u8(self.get_interrupt_enable());
+        w.u8(u8::from(self.interrupt_flag));
+        self.lcd.save_state(w);
+        self.gpu.save_state(w);
+        self.oam.save_state(w);
+        self.timer.save_state(w);
+        self.serial.save_state(w);
+        self.joypad.save_state(w);
+        self.speed.save_state(w);
+        self.background_palettes.save_state(w);
+        w.u16(self.dma_cycles);
+        w.bool(self.booted);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        self.cartridge.load_state(r)?;
+        self.ram.load_state(r)?;
+        self.high_ram.load_state(r)?;
+        let interrupt_enable = r.u8()?;
+        self.interrupt_enable = InterruptFlag::from(interrupt_enable);
+        self.interrupt_enable_unused = interrupt_enable & 0xE0;
+        self.interrupt_flag = InterruptFlag::from(r.u8()?);
+        self.lcd.load_state(r)?;
+        self.gpu.load_state(r)?;
+        self.oam.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.speed.load_state(r)?;
+        self.background_palettes.load_state(r)?;
+        self.dma_cycles = r.u16()?;
+        self.booted = r.bool()?;
+
+        self.gpu.update_buffer(self.lcd.control.tile_data, self.lcd.control.display_select);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::pixel::Pixel;
+    use crate::cartridge::Header;
+    use crate::device::interrupt::Interrupt;
+    use crate::msg::Button;
 
     #[test]
     fn test_get_set_tile_map() {
@@ -339,4 +500,228 @@ mod tests {
         m.set(a, 0x19);
         assert_eq!(m.get(a), 0x19);
     }
+
+    #[test]
+    fn test_set16_round_trip() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+        m.set16(0xC000, 0x1234);
+
+        assert_eq!(m.get(0xC000), 0x34);
+        assert_eq!(m.get(0xC001), 0x12);
+        assert_eq!(m.get16(0xC000), 0x1234);
+    }
+
+    #[test]
+    fn test_skip_boot_unused_bits() {
+        let m = MMU::skip_boot(Cartridge::zero(), BootRomVariant::DMG);
+
+        assert_eq!(m.get(0xFF0F), 0xE1);
+        assert_eq!(m.get(0xFF41), 0x85);
+    }
+
+    #[test]
+    fn test_unmapped_io_reads_ff() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+
+        for address in [0xFF03, 0xFF08, 0xFF4C, 0xFF50, 0xFF51, 0xFF7F].iter() {
+            assert_eq!(m.get(*address), 0xFF, "{:04X}", address);
+
+            /* Writes don't stick */
+            m.set(*address, 0x12);
+            assert_eq!(m.get(*address), 0xFF, "{:04X}", address);
+        }
+
+        /* Writing 1 to 0xFF50 still unmaps the boot rom */
+        assert!(!m.booted);
+        m.set(0xFF50, 0x01);
+        assert!(m.booted);
+    }
+
+    #[test]
+    fn test_vram_conflict_log() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+        m.set(0xFF40, 0x80);
+        m.lcd.status.mode = Mode::VRAM;
+
+        /* Disabled by default */
+        m.set(0x8000, 0x12);
+        assert!(m.conflicts.is_none());
+
+        m.conflicts = Some(RefCell::new(Vec::new()));
+        m.set(0x8000, 0x12);
+        m.get(0xFE00);
+
+        /* Tools looking at memory aren't the cpu racing the PPU */
+        m.peek(0x8000);
+
+        m.lcd.status.mode = Mode::HBlank;
+        m.set(0x8001, 0x12);
+
+        assert_eq!(
+            m.conflicts.unwrap().into_inner(),
+            vec![
+                "VRAM write 8000 during VRAM, not blocked".to_string(),
+                "OAM read FE00 during VRAM, not blocked".to_string(),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_dma_register_readback() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+        m.set(0xFF46, 0xC0);
+
+        assert_eq!(m.get(0xFF46), 0xC0);
+        assert_eq!(m.lcd.dma, 0xC0);
+    }
+
+    #[test]
+    fn test_dma_transfer() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+        for i in 0..0xA0 {
+            m.set(0xC100 + i, i as u8 ^ 0x5A);
+        }
+
+        m.set(0xFF46, 0xC1);
+        for i in 0..0xA0 {
+            assert_eq!(m.oam.get(i), i as u8 ^ 0x5A);
+        }
+
+        /* The cpu can't see OAM until the transfer completes */
+        assert_eq!(m.get(0xFE00), 0xFF);
+        m.set(0xFE00, 0x00);
+
+        m.advance_dma(255);
+        m.advance_dma(255);
+        assert!(m.dma_active());
+        m.advance_dma(130);
+        assert!(!m.dma_active());
+        assert_eq!(m.get(0xFE00), 0x5A);
+    }
+
+    #[test]
+    fn test_echo_ram() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+
+        m.set(0xC010, 0x42);
+        assert_eq!(m.get(0xE010), 0x42);
+
+        m.set(0xFDFF, 0x24);
+        assert_eq!(m.get(0xDDFF), 0x24);
+
+        m.set(0xE000, 0x11);
+        assert_eq!(m.get(0xC000), 0x11);
+        assert_eq!(m.get(0xDFFF), 0x00);
+    }
+
+    #[test]
+    fn test_interrupt_registers_unused_bits() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+
+        m.set(0xFF0F, 0x00);
+        assert_eq!(m.get(0xFF0F), 0xE0);
+        m.set(0xFF0F, 0xFF);
+        assert_eq!(m.get(0xFF0F), 0xFF);
+
+        /* IE keeps whatever is written */
+        m.set(0xFFFF, 0x00);
+        assert_eq!(m.get(0xFFFF), 0x00);
+        m.set(0xFFFF, 0xA5);
+        assert_eq!(m.get(0xFFFF), 0xA5);
+        assert!(m.interrupt_enable.get(Interrupt::VBlank));
+        assert!(!m.interrupt_enable.get(Interrupt::LCDStat));
+    }
+
+    #[test]
+    fn test_background_palette_registers() {
+        let mut m = MMU::new(BootRom::zero(), cgb_cartridge());
+
+        m.set(0xFF68, 0x80);
+        m.set(0xFF69, 0xFF);
+        m.set(0xFF69, 0x7F);
+
+        assert_eq!(m.get(0xFF68), 0xC2);
+        assert_eq!(m.background_palettes.color(0, Pixel::P0), 0x7FFF);
+    }
+
+    fn cgb_cartridge() -> Cartridge {
+        let mut header = [0; 0x4F];
+        header[0x43] = 0x80;
+        Cartridge::new(vec![0; 0x8000], Header::new(header))
+    }
+
+    #[test]
+    fn test_cgb_registers_unmapped_on_dmg() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+
+        for address in [0xFF4D, 0xFF68, 0xFF69].iter() {
+            m.set(*address, 0x01);
+            assert_eq!(m.get(*address), 0xFF, "{:04X}", address);
+        }
+        assert!(!m.speed.switch());
+
+        let m = MMU::new(BootRom::zero(), cgb_cartridge());
+        assert_eq!(m.get(0xFF4D), 0x7E);
+    }
+
+    #[test]
+    fn test_dump_state() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+        m.set(0x8000, 0x11);
+        m.set(0x9FFF, 0x22);
+        m.set(0xFE00, 0x33);
+        m.set(0xFF42, 0x44);
+
+        let dump = m.dump_state();
+
+        assert_eq!(dump.len(), 0x2000 + 0xA0 + 0x80);
+        assert_eq!(dump[0x0000], 0x11);
+        assert_eq!(dump[0x1FFF], 0x22);
+        assert_eq!(dump[0x2000], 0x33);
+        assert_eq!(dump[0x20A0 + 0x42], 0x44);
+    }
+
+    #[test]
+    fn test_io_devices() {
+        let mut m = MMU::new(BootRom::zero(), Cartridge::zero());
+
+        /* Joypad, directions selected with right held */
+        m.set(0xFF00, 0x20);
+        m.joypad.set_button(Button::Right, true);
+        assert_eq!(m.get(0xFF00), 0xEE);
+
+        /* Serial */
+        m.set(0xFF01, 0x42);
+        assert_eq!(m.get(0xFF01), 0x42);
+        assert_eq!(m.serial.data, 0x42);
+
+        /* Timer */
+        m.set(0xFF06, 0x42);
+        assert_eq!(m.get(0xFF06), 0x42);
+        assert_eq!(m.timer.tma, 0x42);
+
+        /* Interrupt flag */
+        m.set(0xFF0F, 0x04);
+        assert_eq!(m.get(0xFF0F), 0xE4);
+        assert!(m.interrupt_flag.timer);
+
+        /* Audio */
+        m.set(0xFF26, 0x80);
+        m.set(0xFF24, 0x77);
+        assert_eq!(m.get(0xFF24), 0x77);
+        assert_eq!(m.get(0xFF26), 0xF0);
+
+        /* LCD */
+        m.set(0xFF42, 0x12);
+        assert_eq!(m.get(0xFF42), 0x12);
+        assert_eq!(m.lcd.scroll_y, 0x12);
+
+        /* Boot rom disable */
+        m.set(0xFF50, 0x01);
+        assert!(m.booted);
+
+        /* Wave ram, read back through the apu */
+        m.set(0xFF30, 0xAB);
+        assert_eq!(m.get(0xFF30), 0xAB);
+    }
 }
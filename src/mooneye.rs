@@ -0,0 +1,71 @@
+use crate::cpu::CPU;
+use crate::register::{Registers8, Registers16};
+
+/* Mooneye test roms
+ *
+ * When a mooneye test finishes it executes LD B, B (0x40), a no-op used as
+ * a software breakpoint, with the result in the registers. A pass loads
+ * the fibonacci numbers 3, 5, 8, 13, 21 and 34 into B, C, D, E, H and L, a
+ * failure loads 0x42 into all of them.
+ */
+const LD_B_B: u8 = 0x40;
+const PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAILED: [u8; 6] = [0x42; 6];
+
+/* Some(passed) once the cpu is at the breakpoint with a result loaded,
+ * None otherwise, including for any ordinary LD B, B.
+ */
+pub fn check_mooneye_result(cpu: &CPU) -> Option<bool> {
+    if cpu.mmu.peek(cpu.registers.get16(Registers16::PC)) != LD_B_B {
+        return None
+    }
+
+    let r = &cpu.registers;
+    let registers = [
+        r.get8(Registers8::B),
+        r.get8(Registers8::C),
+        r.get8(Registers8::D),
+        r.get8(Registers8::E),
+        r.get8(Registers8::H),
+        r.get8(Registers8::L),
+    ];
+
+    match registers {
+        PASSED => Some(true),
+        FAILED => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::rom::BootRomVariant;
+    use crate::mmu::MMU;
+    use crate::register::Registers;
+
+    fn breakpoint_cpu(registers: [u8; 6]) -> CPU {
+        let mut cpu = CPU::new(Registers::new(), MMU::skip_boot(Cartridge::zero(), BootRomVariant::DMG));
+        cpu.mmu.set(0xC000, LD_B_B);
+        cpu.registers.set16(Registers16::PC, 0xC000);
+
+        let targets = [Registers8::B, Registers8::C, Registers8::D, Registers8::E, Registers8::H, Registers8::L];
+        for (r, v) in targets.iter().zip(registers.iter()) {
+            cpu.registers.set8(*r, *v);
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_mooneye_result() {
+        assert_eq!(check_mooneye_result(&breakpoint_cpu(PASSED)), Some(true));
+        assert_eq!(check_mooneye_result(&breakpoint_cpu(FAILED)), Some(false));
+        assert_eq!(check_mooneye_result(&breakpoint_cpu([0; 6])), None);
+
+        /* The registers alone aren't enough, it has to be at LD B, B */
+        let mut cpu = breakpoint_cpu(PASSED);
+        cpu.mmu.set(0xC000, 0x00);
+        assert_eq!(check_mooneye_result(&cpu), None);
+    }
+}
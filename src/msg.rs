@@ -6,7 +6,11 @@ pub struct TileMap {
     pub scroll_x: u8,
     pub scroll_y: u8,
     pub palette: Palette,
-    pub pixels: [[Pixel; 256]; 512],
+    pub view: TileMapView,
+
+    /* Whether the viewed map is the one the background is drawn from */
+    pub background: bool,
+    pub pixels: [[Pixel; 256]; 256],
 }
 
 impl TileMap {
@@ -15,23 +19,156 @@ impl TileMap {
             scroll_x: 0,
             scroll_y: 0,
             palette: Palette::new(),
-            pixels: [[Pixel::P0; 256]; 512],
+            view: TileMapView::new(),
+            background: true,
+            pixels: [[Pixel::P0; 256]; 256],
         }
     }
 }
 
+/* Which of the two tile maps the debug view shows, 0x9800 or 0x9C00 with
+ * map set, and which tile data region its tile numbers index, see
+ * gpu::tile_index.
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct TileMapView {
+    pub map: bool,
+    pub unsigned_tiles: bool,
+}
+
+impl TileMapView {
+    pub fn new() -> TileMapView {
+        TileMapView {
+            map: false,
+            unsigned_tiles: true,
+        }
+    }
+}
+
+impl Default for TileMapView {
+    fn default() -> TileMapView {
+        TileMapView::new()
+    }
+}
+
 pub struct Frame {
     pub main: [[Shade;160];144],
+
+    /* The screen as CGB colors, set instead of main's shades being used
+     * when the cart is CGB
+     */
+    pub colors: Option<[[u16;160];144]>,
     pub tiles: [[Shade; 256]; 96],
     pub tile_map: TileMap,
+
+    /* Set by Input::SetSpeed */
+    pub speed: Option<f64>,
 }
 
 impl Frame {
     pub fn zero() -> Frame {
         Frame {
             main: [[Shade::White;160];144],
+            colors: None,
             tiles: [[Shade::White;256];96],
             tile_map: TileMap::zero(),
+            speed: None,
         }
     }
 }
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    Start,
+    Select,
+}
+
+/* The registers the debugger can break on the value of */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DebugRegister {
+    A, B, C, D, E, F, H, L,
+    AF, BC, DE, HL, PC, SP,
+}
+
+/* Commands from the debugger repl */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DebugCommand {
+    Pause,
+    Continue,
+
+    /* Executes one instruction then returns to the debug state */
+    Step,
+
+    /* Like step but runs a CALL or RST until it returns */
+    Next,
+    Break(u16),
+    Delete(u16),
+
+    /* Stops once the register holds the value, 8 bit registers use the
+     * low byte
+     */
+    BreakRegister(DebugRegister, u16),
+    DeleteRegister(DebugRegister, u16),
+
+    /* These reply with a DebugOutput */
+    Registers,
+    Read(u16, u16),
+    Dump,
+}
+
+/* The cpu registers at the moment they were requested */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+}
+
+/* Sent from the emulator thread back to the debugger repl */
+#[derive(PartialEq, Debug, Clone)]
+pub enum DebugOutput {
+    Registers(Snapshot),
+
+    /* The bytes read starting from the address */
+    Memory(u16, Vec<u8>),
+    Text(String),
+}
+
+/* Sent from the display and debugger to the emulator thread */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Input {
+    Button(Button, bool),
+    Debug(DebugCommand),
+
+    /* Executes one instruction while paused, like DebugCommand::Step, but
+     * without sending the registers back. The display steps with this so the
+     * replies on the debug output only answer the debugger's own commands.
+     */
+    Step,
+
+    /* Writes or restores the save state slot */
+    SaveState,
+    LoadState,
+
+    /* Picks what the tile map debug view shows */
+    TileMapView(TileMapView),
+
+    /* Changes the display's speed multiplier, 0 turns the rate limiter off.
+     * The display picks it up from the next frame.
+     */
+    SetSpeed(f64),
+}
@@ -22,6 +22,12 @@ impl Palette {
 
 }
 
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new()
+    }
+}
+
 
 impl std::convert::From<u8> for Palette {
     fn from(byte: u8) -> Self {
@@ -43,6 +49,21 @@ impl std::convert::From<Palette> for u8 {
     }
 }
 
+/* Describes a palette write for logging, listing the shade for each
+ * color number from 0 to 3
+ */
+pub fn describe(name: &str, byte: u8) -> String {
+    format!(
+        "{}: {:02X} {:?} {:?} {:?} {:?}",
+        name,
+        byte,
+        get_shade(byte, 0),
+        get_shade(byte, 1),
+        get_shade(byte, 2),
+        get_shade(byte, 3),
+    )
+}
+
 /* Shades are stored in palettes as follows
  * Bit 7-6 - Shade for Color Number 3
  * Bit 5-4 - Shade for Color Number 2
@@ -60,8 +81,8 @@ pub fn get_shade(byte: u8, i:u8) -> Shade {
     let i = i * 2;
 
     let v = (
-        bytes::check_bit(byte, i),
         bytes::check_bit(byte, i + 1),
+        bytes::check_bit(byte, i),
     );
 
     match v {
@@ -85,8 +106,8 @@ mod tests {
         assert_eq!(get_shade(0b0000_0011, 0), Shade::Black);
 
         assert_eq!(get_shade(0b0000_0000, 3), Shade::White);
-        assert_eq!(get_shade(0b0100_0000, 3), Shade::DarkGrey);
-        assert_eq!(get_shade(0b1000_0000, 3), Shade::LightGrey);
+        assert_eq!(get_shade(0b0100_0000, 3), Shade::LightGrey);
+        assert_eq!(get_shade(0b1000_0000, 3), Shade::DarkGrey);
         assert_eq!(get_shade(0b1100_0000, 3), Shade::Black);
     }
 }
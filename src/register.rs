@@ -1,6 +1,9 @@
 use super::bytes;
+use crate::rom::BootRomVariant;
 use std::fmt;
 use std::fmt::Debug;
+use std::io::Error;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum HaltedState {
@@ -31,7 +34,7 @@ impl IME {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Registers8 {
     A,
     B,
@@ -123,8 +126,9 @@ impl Registers {
         };
     }
 
-    pub fn skip_boot() -> Registers {
-        return Registers {
+    /* Register values left behind by each boot rom when it hands off to the cartridge */
+    pub fn skip_boot(variant: BootRomVariant) -> Registers {
+        let mut registers = Registers {
             a: 0x01,
             b: 0x00,
             c: 0x13,
@@ -139,6 +143,22 @@ impl Registers {
             stopped: false,
             halted: HaltedState::None
         };
+
+        match variant {
+            BootRomVariant::DMG => {},
+            BootRomVariant::DMG0 => {
+                registers.f = 0x00;
+                registers.b = 0xFF;
+                registers.e = 0xC1;
+                registers.h = 0x84;
+                registers.l = 0x03;
+            },
+            BootRomVariant::MGB => {
+                registers.a = 0xFF;
+            },
+        }
+
+        registers
     }
 
     pub fn get8(&self, r: Registers8) -> u8 {
@@ -202,6 +222,33 @@ impl Registers {
         self.set16(r, v);
     }
 
+    /* Packs a, b, c, d, e, f, h, l followed by sp and pc (little endian)
+     * for compact snapshots. ime and the halted state aren't included.
+     */
+    pub fn dump(&self) -> [u8; 12] {
+        let (sp_ms, sp_ls) = bytes::split_ms_ls(self.sp);
+        let (pc_ms, pc_ls) = bytes::split_ms_ls(self.pc);
+
+        [
+            self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l,
+            sp_ls, sp_ms,
+            pc_ls, pc_ms,
+        ]
+    }
+
+    pub fn restore(&mut self, dump: &[u8; 12]) {
+        self.a = dump[0];
+        self.b = dump[1];
+        self.c = dump[2];
+        self.d = dump[3];
+        self.e = dump[4];
+        self.set8(Registers8::F, dump[5]);
+        self.h = dump[6];
+        self.l = dump[7];
+        self.sp = bytes::combine_ms_ls(dump[9], dump[8]);
+        self.pc = bytes::combine_ms_ls(dump[11], dump[10]);
+    }
+
     fn set_combined(&mut self, r1: Registers8, r2: Registers8, v: u16) {
         let (ms, ls) = bytes::split_ms_ls(v);
         self.set8(r1, ms);
@@ -223,3 +270,71 @@ impl fmt::Debug for Registers {
     } 
 
 }
+
+impl SaveState for Registers {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.dump());
+        w.u8(match self.ime {
+            IME::Disabled => 0,
+            IME::Enabled => 1,
+            IME::Queued => 2,
+        });
+        w.u8(match self.halted {
+            HaltedState::None => 0,
+            HaltedState::Halted => 1,
+            HaltedState::HaltedNoJump => 2,
+            HaltedState::HaltBug => 3,
+        });
+        w.bool(self.stopped);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error> {
+        let mut dump = [0; 12];
+        r.bytes_into(&mut dump)?;
+        self.restore(&dump);
+
+        self.ime = match r.u8()? {
+            1 => IME::Enabled,
+            2 => IME::Queued,
+            _ => IME::Disabled,
+        };
+        self.halted = match r.u8()? {
+            1 => HaltedState::Halted,
+            2 => HaltedState::HaltedNoJump,
+            3 => HaltedState::HaltBug,
+            _ => HaltedState::None,
+        };
+        self.stopped = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skip_boot_variant() {
+        assert_eq!(Registers::skip_boot(BootRomVariant::DMG).get8(Registers8::A), 0x01);
+        assert_eq!(Registers::skip_boot(BootRomVariant::MGB).get8(Registers8::A), 0xFF);
+        assert_eq!(Registers::skip_boot(BootRomVariant::DMG0).get8(Registers8::A), 0x01);
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let mut registers = Registers::skip_boot(BootRomVariant::DMG);
+        registers.set16(Registers16::SP, 0xDFF0);
+        let dump = registers.dump();
+
+        assert_eq!(dump, [0x01, 0x00, 0x13, 0x00, 0xD8, 0xB0, 0x01, 0x4D, 0xF0, 0xDF, 0x00, 0x01]);
+
+        registers.set16(Registers16::AF, 0x1234);
+        registers.set16(Registers16::HL, 0xBEEF);
+        registers.set16(Registers16::PC, 0x4000);
+        assert_ne!(registers.dump(), dump);
+
+        registers.restore(&dump);
+        assert_eq!(registers.dump(), dump);
+        assert_eq!(registers.get16(Registers16::PC), 0x0100);
+    }
+}
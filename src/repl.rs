@@ -1,6 +1,12 @@
+use std::fs;
 use std::io;
 use std::io::{BufRead, Error, ErrorKind, stdout, Write};
+use std::path::{Path, PathBuf};
 use std::str::SplitWhitespace;
+use std::sync::mpsc::{Sender, Receiver};
+
+use crate::msg::{Input, DebugCommand, DebugOutput, DebugRegister, Snapshot};
+use gameboy_emulator_v2::Gameboy;
 
 /// # Mini Debugger Language
 ///
@@ -10,17 +16,28 @@ use std::str::SplitWhitespace;
 ///
 /// > break 0x0100
 ///
+/// ## break r<reg> <value>
+///
+/// Breaks once the register holds the value, checked after each
+/// instruction. It stops when the register comes to hold the value rather
+/// than on every instruction while it does.
+///
+/// > break rA 0x3C
+/// > break rHL 0x9800
+///
 /// ## list
 ///
-/// Lists all set break points
+/// Lists all set break points, followed by any register breaks
 ///
 /// > list
 /// 0x0100
 /// 0xFF80
+/// rA 0x3C
 ///
 /// ## run
 ///
-/// Runs until a breakpoint is reached or the end of the program
+/// Runs until a breakpoint is reached or the end of the program, also
+/// available as continue
 ///
 /// ## print [r|f|a16]
 ///
@@ -35,10 +52,18 @@ use std::str::SplitWhitespace;
 /// 
 /// > p 0xFF80
 ///
-/// 0x010C
+/// 0xFF80: 0C
+///
+/// An optional count prints a hex dump of that many bytes
+///
+/// > p 0xC000 20
+///
+/// 0xC000: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F
+/// 0xC010: 10 11 12 13
 ///
 /// > p
 ///
+/// A: 0x01 F: 0x0C B: 0x00 C: 0x00 D: 0x56 E: 0xAB H: 0x12 L: 0x34
 /// AF: 0x010C
 /// BC: 0x0000
 /// DE: 0x56AB
@@ -49,6 +74,16 @@ use std::str::SplitWhitespace;
 /// IME: 0
 ///
 ///
+/// ## examine <a16> [count]
+///
+/// Prints a hex dump of count bytes (16 by default) with their ASCII, bytes
+/// outside the printable range show as a dot. Also available as x
+///
+/// > x 0x0134 20
+///
+/// 0x0134: 54 45 54 52 49 53 00 00 00 00 00 00 00 00 00 00  TETRIS..........
+/// 0x0144: 00 00 00 00                                      ....
+///
 /// ## step
 ///
 /// Advances to the next instruction, if a call is issued will follow the call
@@ -70,20 +105,43 @@ use std::str::SplitWhitespace;
 /// > finish
 /// PC: 0x01CD; RET
 ///
+/// ## dump
+///
+/// Prints the tile set, tile maps, OAM and palettes as JSON
+///
+/// > dump
+///
+/// {"tiles":[...],"tile_maps":[...],"oam":[...],"palettes":{...}}
+///
 ///
 /// ## delete 
 ///
-/// Deletes a breakpoint
+/// Deletes a breakpoint, or a register break given the same way it was set
 ///
 /// > delete 0x100
+/// > delete rA 0x3C
 /// > list
+///
 /// 0xFF80
-
-#[derive(Debug, Clone, Copy)]
-pub enum DebuggerError {
-    InvalidFlag,
-    InvalidEndOfInput,
-}
+///
+/// ## speed <multiplier>
+///
+/// Sets the display's speed multiplier like --speed, 0 runs as fast as
+/// possible like --unlimited
+///
+/// > speed 2
+/// > speed 0
+///
+/// ## save / load
+///
+/// Break points are kept in .gbedbg in the working directory, one hex
+/// address per line, so they survive restarting the emulator. The file is
+/// read when the debugger starts and rewritten whenever a break point is
+/// set or deleted. save writes it out, load sets any break points in it
+/// that aren't already set.
+///
+/// > save
+/// > load
 
 #[derive(Debug, Clone, Copy)]
 pub enum Token {
@@ -95,12 +153,20 @@ pub enum Token {
     Next,
     Finish,
     Delete,
+    Dump,
+    Examine,
+    Save,
+    Load,
+    Speed,
     Address(u16),
+    Count(u16),
+    Value(u16),
+    Multiplier(f64),
     Register(Register),
     Flag(Flag),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Register {
     A, B, C, D, E, F, H, L,
     AF, BC, DE, HL, PC, SP,
@@ -115,6 +181,15 @@ pub enum Flag {
 pub enum State {
     Command,
     Argument,
+
+    /* An optional byte count following an address */
+    Count,
+
+    /* An optional hex value following a register */
+    Value,
+
+    /* The multiplier following speed */
+    Multiplier,
 }
 
 
@@ -172,12 +247,12 @@ pub fn _tok(input: &mut SplitWhitespace, state: State, _output: &Vec<Token>) ->
                     } else if untok.starts_with("r") {
                         let register = parse_register(&untok[1..])?;
                         output.push(Token::Register(register));
-                        Ok(output)
+                        _tok(input, State::Value, &output)
                     } else if untok.starts_with("0x") {
                         match u16::from_str_radix(&untok[2..], 16) {
                             Ok(register) => {
                                 output.push(Token::Address(register));
-                                Ok(output)
+                                _tok(input, State::Count, &output)
                             }
                             e => Err(_error(format!("Invalid hex string: {} {:?}", untok, e)))
                         }
@@ -186,6 +261,36 @@ pub fn _tok(input: &mut SplitWhitespace, state: State, _output: &Vec<Token>) ->
                     }
                 }
 
+                State::Value => {
+                    match untok.strip_prefix("0x").map(|hex| u16::from_str_radix(hex, 16)) {
+                        Some(Ok(value)) => {
+                            output.push(Token::Value(value));
+                            Ok(output)
+                        }
+                        e => Err(_error(format!("Invalid hex value: {} {:?}", untok, e)))
+                    }
+                }
+
+                State::Multiplier => {
+                    match untok.parse() {
+                        Ok(speed) => {
+                            output.push(Token::Multiplier(speed));
+                            Ok(output)
+                        }
+                        e => Err(_error(format!("Invalid speed: {} {:?}", untok, e)))
+                    }
+                }
+
+                State::Count => {
+                    match untok.parse() {
+                        Ok(count) => {
+                            output.push(Token::Count(count));
+                            Ok(output)
+                        }
+                        e => Err(_error(format!("Invalid count: {} {:?}", untok, e)))
+                    }
+                }
+
                 State::Command => {
                     match untok {
                         "b" | "break" => {
@@ -200,7 +305,7 @@ pub fn _tok(input: &mut SplitWhitespace, state: State, _output: &Vec<Token>) ->
                             output.push(Token::List);
                             Ok(output)
                         }
-                        "r" | "run" => {
+                        "r" | "run" | "c" | "continue" => {
                             output.push(Token::Run);
                             Ok(output)
                         }
@@ -216,6 +321,26 @@ pub fn _tok(input: &mut SplitWhitespace, state: State, _output: &Vec<Token>) ->
                             output.push(Token::Finish);
                             Ok(output)
                         }
+                        "dump" => {
+                            output.push(Token::Dump);
+                            Ok(output)
+                        }
+                        "save" => {
+                            output.push(Token::Save);
+                            Ok(output)
+                        }
+                        "load" => {
+                            output.push(Token::Load);
+                            Ok(output)
+                        }
+                        "speed" => {
+                            output.push(Token::Speed);
+                            _tok(input, State::Multiplier, &output)
+                        }
+                        "x" | "examine" => {
+                            output.push(Token::Examine);
+                            _tok(input, State::Argument, &output)
+                        }
                         "d" | "delete" => {
                             output.push(Token::Delete);
                             _tok(input, State::Argument, &output)
@@ -231,131 +356,388 @@ pub fn _tok(input: &mut SplitWhitespace, state: State, _output: &Vec<Token>) ->
     }
 }
 
-#[derive(Debug)]
-pub struct Registers {
-    af: u16
-}
-
 #[derive(Debug)]
 pub enum Output {
-    Address(u16),
     AddressList(Vec<u16>),
-    Registers(Registers),
+
+    /* Break points followed by register breaks */
+    BreakPoints(Vec<u16>, Vec<(Register, u16)>),
+    Registers(Snapshot),
     Text(String),
     Unit,
 }
 
-struct Debugger {
+/* The emulator being debugged, commands that reply wait for the response */
+pub trait Target {
+    fn send(&mut self, command: DebugCommand) -> Result<(), Error>;
+    fn request(&mut self, command: DebugCommand) -> Result<DebugOutput, Error>;
+
+    /* Input that isn't a debugger command, like changing the speed */
+    fn input(&mut self, input: Input) -> Result<(), Error>;
+}
+
+/* Talks to the emulator thread over the same input channel as the display */
+pub struct Link {
+    commands: Sender<Input>,
+    responses: Receiver<DebugOutput>,
+}
+
+impl Link {
+    pub fn new(commands: Sender<Input>, responses: Receiver<DebugOutput>) -> Link {
+        Link { commands, responses }
+    }
+}
+
+fn disconnected() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "emulator has stopped")
+}
+
+impl Target for Link {
+    fn send(&mut self, command: DebugCommand) -> Result<(), Error> {
+        self.input(Input::Debug(command))
+    }
+
+    fn request(&mut self, command: DebugCommand) -> Result<DebugOutput, Error> {
+        self.send(command)?;
+        self.responses.recv().map_err(|_| disconnected())
+    }
+
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        self.commands.send(input).map_err(|_| disconnected())
+    }
+}
+
+/* Debugs a gameboy owned by the caller, commands run in the same thread */
+impl Target for Gameboy {
+    fn send(&mut self, command: DebugCommand) -> Result<(), Error> {
+        self.handle_debug(command);
+        Ok(())
+    }
+
+    fn request(&mut self, command: DebugCommand) -> Result<DebugOutput, Error> {
+        self.handle_debug(command)
+            .ok_or_else(|| _error(format!("No response to {:?}", command)))
+    }
+
+    fn input(&mut self, input: Input) -> Result<(), Error> {
+        self.handle_input(input);
+        Ok(())
+    }
+}
+
+/* Where the repl keeps break points between runs */
+pub const BREAK_POINT_FILE: &str = ".gbedbg";
+
+/* One hex address per line, blank lines are skipped */
+pub fn read_break_points(path: &Path) -> Result<Vec<u16>, Error> {
+    fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            u16::from_str_radix(line.trim_start_matches("0x"), 16)
+                .map_err(|e| _error(format!("Invalid break point in {}: {} {:?}", path.display(), line, e)))
+        })
+        .collect()
+}
+
+pub fn write_break_points(path: &Path, break_points: &[u16]) -> Result<(), Error> {
+    let lines: Vec<String> = break_points.iter().map(|pc| format!("0x{:04X}\n", pc)).collect();
+    fs::write(path, lines.concat())
+}
+
+pub struct Debugger<T: Target> {
+    target: T,
     break_points: Vec<u16>,
+
+    /* Register breaks aren't saved with the break points */
+    register_breaks: Vec<(Register, u16)>,
+
+    /* Break points are saved here as they change */
+    file: Option<PathBuf>,
 }
 
-impl Debugger {
-    pub fn new() -> Debugger {
+impl<T: Target> Debugger<T> {
+    pub fn new(target: T) -> Debugger<T> {
         Debugger {
+            target,
             break_points: Vec::new(),
+            register_breaks: Vec::new(),
+            file: None,
         }
     }
 
-    pub fn set(&mut self, pc: u16) {
+    /* Sets any break points already saved in the file, a missing file is
+     * an empty list
+     */
+    pub fn with_file(target: T, path: &Path) -> Result<Debugger<T>, Error> {
+        let mut debugger = Debugger::new(target);
+        debugger.file = Some(path.to_path_buf());
+
+        if path.exists() {
+            debugger.load()?;
+        }
+        Ok(debugger)
+    }
+
+    fn file(&self) -> Result<&Path, Error> {
+        self.file.as_deref().ok_or_else(|| _error("No break point file".to_string()))
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        write_break_points(self.file()?, &self.break_points)
+    }
+
+    pub fn load(&mut self) -> Result<(), Error> {
+        for pc in read_break_points(self.file()?)? {
+            self.set_break(pc)?;
+        }
+        Ok(())
+    }
+
+    /* Only saved when there's somewhere to save to */
+    fn persist(&self) -> Result<(), Error> {
+        match self.file {
+            Some(_) => self.save(),
+            None => Ok(()),
+        }
+    }
+
+    fn set_break(&mut self, pc: u16) -> Result<(), Error> {
         if !self.break_points.contains(&pc) {
+            self.target.send(DebugCommand::Break(pc))?;
             self.break_points.push(pc);
         }
+        Ok(())
+    }
+
+    pub fn set(&mut self, pc: u16) -> Result<(), Error> {
+        self.set_break(pc)?;
+        self.persist()
+    }
+
+    pub fn break_register(&mut self, r: Register, value: u16) -> Result<(), Error> {
+        if is_register8(r) && value > 0xFF {
+            return Err(_error(format!("Value too large for {:?}: 0x{:X}", r, value)))
+        }
+        if !self.register_breaks.contains(&(r, value)) {
+            self.target.send(DebugCommand::BreakRegister(debug_register(r), value))?;
+            self.register_breaks.push((r, value));
+        }
+        Ok(())
+    }
+
+    pub fn delete_register(&mut self, r: Register, value: u16) -> Result<(), Error> {
+        self.target.send(DebugCommand::DeleteRegister(debug_register(r), value))?;
+        self.register_breaks.retain(|e| *e != (r, value));
+        Ok(())
     }
 
     pub fn list(&self) -> Vec<u16> {
         self.break_points.clone()
     }
 
-    pub fn run(&self) {
+    pub fn list_registers(&self) -> Vec<(Register, u16)> {
+        self.register_breaks.clone()
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        self.target.send(DebugCommand::Continue)
+    }
+
+    pub fn set_speed(&mut self, speed: f64) -> Result<(), Error> {
+        if speed < 0.0 || speed.is_nan() {
+            return Err(_error(format!("Invalid speed: {}", speed)))
+        }
+        self.target.input(Input::SetSpeed(speed))
     }
 
-    pub fn step(&self) {
+    pub fn step(&mut self) -> Result<Snapshot, Error> {
+        match self.target.request(DebugCommand::Step)? {
+            DebugOutput::Registers(snapshot) => Ok(snapshot),
+            output => Err(_error(format!("Unexpected response to step: {:?}", output))),
+        }
     }
 
-    pub fn next(&self) {
+    pub fn next(&mut self) -> Result<Snapshot, Error> {
+        match self.target.request(DebugCommand::Next)? {
+            DebugOutput::Registers(snapshot) => Ok(snapshot),
+            output => Err(_error(format!("Unexpected response to next: {:?}", output))),
+        }
     }
 
     pub fn finish(&self) {
     }
 
-    pub fn delete(&mut self, pc: u16) {
+    pub fn delete(&mut self, pc: u16) -> Result<(), Error> {
+        self.target.send(DebugCommand::Delete(pc))?;
         self.break_points.retain(|e| *e != pc);
+        self.persist()
     }
 
-    pub fn print_all(&self) -> String {
-        String::from("wtf")
+    pub fn snapshot(&mut self) -> Result<Snapshot, Error> {
+        match self.target.request(DebugCommand::Registers)? {
+            DebugOutput::Registers(snapshot) => Ok(snapshot),
+            output => Err(_error(format!("Unexpected response to print: {:?}", output))),
+        }
     }
 
-    pub fn print_register(&self, r: Register) -> String {
-        match r {
-            Register::AF => format!("{}", "AF"),
-            _ => format!("{}", "??")
+    /* See Gameboy::debug_dump_json */
+    pub fn dump(&mut self) -> Result<String, Error> {
+        match self.target.request(DebugCommand::Dump)? {
+            DebugOutput::Text(json) => Ok(json),
+            output => Err(_error(format!("Unexpected response to dump: {:?}", output))),
         }
     }
 
-    pub fn print_flag(&self, f: Flag) -> String {
-        match f {
-            Flag::Z => format!("{}", "fZ"),
-            _ => format!("{}", "f?")
+    pub fn print_all(&mut self) -> Result<String, Error> {
+        let s = self.snapshot()?;
+
+        let eights = [
+            Register::A, Register::F, Register::B, Register::C,
+            Register::D, Register::E, Register::H, Register::L,
+        ];
+        let sixteens = [
+            Register::AF, Register::BC, Register::DE, Register::HL, Register::PC, Register::SP,
+        ];
+        let flags = [Flag::Z, Flag::H, Flag::N, Flag::C];
+
+        let mut lines = Vec::new();
+        lines.push(eights.iter().map(|r| format_register(&s, *r)).collect::<Vec<_>>().join(" "));
+        lines.extend(sixteens.iter().map(|r| format_register(&s, *r)));
+        lines.push(flags.iter().map(|f| format_flag(&s, *f).replace(' ', "")).collect::<Vec<_>>().join(" "));
+        lines.push(format!("IME: {}", s.ime as u8));
+
+        Ok(lines.join("\n"))
+    }
+
+    pub fn print_register(&mut self, r: Register) -> Result<String, Error> {
+        let s = self.snapshot()?;
+        Ok(format_register(&s, r))
+    }
+
+    pub fn print_flag(&mut self, f: Flag) -> Result<String, Error> {
+        let s = self.snapshot()?;
+        Ok(format_flag(&s, f))
+    }
+
+    pub fn print_address(&mut self, address: u16, count: u16) -> Result<String, Error> {
+        match self.target.request(DebugCommand::Read(address, count))? {
+            DebugOutput::Memory(start, bytes) => Ok(format_memory(start, &bytes)),
+            output => Err(_error(format!("Unexpected response to print: {:?}", output))),
+        }
+    }
+
+    pub fn examine(&mut self, address: u16, count: u16) -> Result<String, Error> {
+        match self.target.request(DebugCommand::Read(address, count))? {
+            DebugOutput::Memory(start, bytes) => Ok(format_examine(start, &bytes)),
+            output => Err(_error(format!("Unexpected response to examine: {:?}", output))),
         }
     }
 
     pub fn eval(&mut self, tokens: Vec<Token>) -> Result<Output, Error> {
-        let command = tokens[0];
+        let command = match tokens.first() {
+            Some(command) => *command,
+            None => return Ok(Output::Unit),
+        };
 
         match command {
             Token::Break => {
-                let arg = tokens[1];
+                let arg = tokens.get(1);
                 match arg {
-                    Token::Address(a) => {
-                        self.set(a);
+                    Some(Token::Address(a)) => {
+                        self.set(*a)?;
                         Ok(Output::Unit)
                     },
+                    Some(Token::Register(r)) => match tokens.get(2) {
+                        Some(Token::Value(v)) => {
+                            self.break_register(*r, *v)?;
+                            Ok(Output::Unit)
+                        },
+                        _ => Err(_error(format!("Missing value to break on for {:?}", r)))
+                    },
                     _ => Err(_error(format!("Invalid argument to break: {:?}", arg)))
                 }
             },
-            Token::List => Ok(Output::AddressList(self.list())),
+            Token::List => Ok(Output::BreakPoints(self.list(), self.list_registers())),
             Token::Print => {
                 let arg = tokens.get(1);
                 match arg {
                     Some(Token::Register(r)) => {
-                        Ok(Output::Text(self.print_register(*r)))
+                        Ok(Output::Text(self.print_register(*r)?))
                     },
                     Some(Token::Flag(f)) => {
-                        Ok(Output::Text(self.print_flag(*f)))
+                        Ok(Output::Text(self.print_flag(*f)?))
+                    },
+                    Some(Token::Address(a)) => {
+                        let count = match tokens.get(2) {
+                            Some(Token::Count(n)) => *n,
+                            _ => 1,
+                        };
+                        Ok(Output::Text(self.print_address(*a, count)?))
                     },
                     Some(_) => {
                         Err(_error(format!("Invalid argument to print: {:?}", arg)))
                     },
                     None => {
-                        Ok(Output::Text(self.print_all()))
+                        Ok(Output::Text(self.print_all()?))
                     }
                 }
             },
             Token::Run => {
-                self.run();
-                Ok(Output::Unit)
-            },
-            Token::Step => {
-                self.step();
-                Ok(Output::Unit)
-            }
-            Token::Next => {
-                self.next();
+                self.run()?;
                 Ok(Output::Unit)
             },
+            Token::Step => Ok(Output::Registers(self.step()?)),
+            Token::Next => Ok(Output::Registers(self.next()?)),
             Token::Finish => {
                 self.finish();
                 Ok(Output::Unit)
             }
+            Token::Dump => Ok(Output::Text(self.dump()?)),
+            Token::Speed => match tokens.get(1) {
+                Some(Token::Multiplier(speed)) => {
+                    self.set_speed(*speed)?;
+                    Ok(Output::Unit)
+                },
+                _ => Err(_error("Missing speed multiplier".to_string()))
+            },
+            Token::Save => {
+                self.save()?;
+                Ok(Output::Unit)
+            },
+            Token::Load => {
+                self.load()?;
+                Ok(Output::AddressList(self.list()))
+            },
+            Token::Examine => {
+                let arg = tokens.get(1);
+                match arg {
+                    Some(Token::Address(a)) => {
+                        let count = match tokens.get(2) {
+                            Some(Token::Count(n)) => *n,
+                            _ => 16,
+                        };
+                        Ok(Output::Text(self.examine(*a, count)?))
+                    },
+                    _ => Err(_error(format!("Invalid argument to examine: {:?}", arg)))
+                }
+            },
             Token::Delete => {
-                let arg = tokens[1];
+                let arg = tokens.get(1);
                 match arg {
-                    Token::Address(a) => {
-                        self.delete(a);
+                    Some(Token::Address(a)) => {
+                        self.delete(*a)?;
                         Ok(Output::AddressList(self.break_points.clone()))
                     },
-                    _ => Err(_error(format!("Invalid argument to break: {:?}", arg)))
+                    Some(Token::Register(r)) => match tokens.get(2) {
+                        Some(Token::Value(v)) => {
+                            self.delete_register(*r, *v)?;
+                            Ok(Output::BreakPoints(self.list(), self.list_registers()))
+                        },
+                        _ => Err(_error(format!("Missing value to delete for {:?}", r)))
+                    },
+                    _ => Err(_error(format!("Invalid argument to delete: {:?}", arg)))
                 }
             }
             _ => Err(_error(format!("Invalid command: {:?}", command)))
@@ -363,37 +745,562 @@ impl Debugger {
     }
 }
 
-fn main() {
+fn format_register(s: &Snapshot, r: Register) -> String {
+    match r {
+        Register::A => format!("A: 0x{:02X}", s.a),
+        Register::B => format!("B: 0x{:02X}", s.b),
+        Register::C => format!("C: 0x{:02X}", s.c),
+        Register::D => format!("D: 0x{:02X}", s.d),
+        Register::E => format!("E: 0x{:02X}", s.e),
+        Register::F => format!("F: 0x{:02X}", s.f),
+        Register::H => format!("H: 0x{:02X}", s.h),
+        Register::L => format!("L: 0x{:02X}", s.l),
+        Register::AF => format!("AF: 0x{:02X}{:02X}", s.a, s.f),
+        Register::BC => format!("BC: 0x{:02X}{:02X}", s.b, s.c),
+        Register::DE => format!("DE: 0x{:02X}{:02X}", s.d, s.e),
+        Register::HL => format!("HL: 0x{:02X}{:02X}", s.h, s.l),
+        Register::PC => format!("PC: 0x{:04X}", s.pc),
+        Register::SP => format!("SP: 0x{:04X}", s.sp),
+    }
+}
+
+/* As it's given to break, rA 0x3C */
+fn format_register_break(r: Register, value: u16) -> String {
+    if is_register8(r) {
+        format!("r{:?} 0x{:02X}", r, value)
+    } else {
+        format!("r{:?} 0x{:04X}", r, value)
+    }
+}
+
+fn is_register8(r: Register) -> bool {
+    matches!(r, Register::A | Register::B | Register::C | Register::D | Register::E | Register::F | Register::H | Register::L)
+}
+
+fn debug_register(r: Register) -> DebugRegister {
+    match r {
+        Register::A => DebugRegister::A,
+        Register::B => DebugRegister::B,
+        Register::C => DebugRegister::C,
+        Register::D => DebugRegister::D,
+        Register::E => DebugRegister::E,
+        Register::F => DebugRegister::F,
+        Register::H => DebugRegister::H,
+        Register::L => DebugRegister::L,
+        Register::AF => DebugRegister::AF,
+        Register::BC => DebugRegister::BC,
+        Register::DE => DebugRegister::DE,
+        Register::HL => DebugRegister::HL,
+        Register::PC => DebugRegister::PC,
+        Register::SP => DebugRegister::SP,
+    }
+}
+
+fn format_flag(s: &Snapshot, f: Flag) -> String {
+    let (name, bit) = match f {
+        Flag::Z => ("Z", 7),
+        Flag::N => ("N", 6),
+        Flag::H => ("H", 5),
+        Flag::C => ("C", 4),
+    };
+
+    format!("{}: {}", name, (s.f >> bit) & 0x01)
+}
+
+/* 16 bytes per line, each line prefixed with its address */
+fn format_memory(start: u16, bytes: &[u8]) -> String {
+    let lines: Vec<String> = bytes.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let values: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("0x{:04X}: {}", start.wrapping_add(i as u16 * 16), values.join(" "))
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+/* Like format_memory with the ASCII alongside, short lines are padded so
+ * the text lines up
+ */
+fn format_examine(start: u16, bytes: &[u8]) -> String {
+    let lines: Vec<String> = bytes.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let values: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = chunk.iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect();
+            format!("0x{:04X}: {:<47}  {}", start.wrapping_add(i as u16 * 16), values.join(" "), text)
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+fn display(output: &Output) -> Option<String> {
+    match output {
+        Output::AddressList(addresses) => {
+            let lines: Vec<String> = addresses.iter().map(|a| format!("0x{:04X}", a)).collect();
+            Some(lines.join("\n"))
+        },
+        Output::BreakPoints(addresses, registers) => {
+            let lines: Vec<String> = addresses.iter().map(|a| format!("0x{:04X}", a))
+                .chain(registers.iter().map(|(r, value)| format_register_break(*r, *value)))
+                .collect();
+            Some(lines.join("\n"))
+        },
+        Output::Registers(snapshot) => Some(format!("PC: 0x{:04X}", snapshot.pc)),
+        Output::Text(text) => Some(text.clone()),
+        Output::Unit => None,
+    }
+}
+
+/* Reads commands from stdin until it closes or the emulator goes away */
+pub fn run<T: Target>(target: T) {
     let stdin = io::stdin();
 
     let mut input_handle = stdin.lock();
     let mut output_handle = stdout();
-    let mut debugger = Debugger::new();
+    let mut debugger = match Debugger::with_file(target, Path::new(BREAK_POINT_FILE)) {
+        Ok(debugger) => debugger,
+        Err(e) => {
+            println!("Error: {}", e);
+            return
+        }
+    };
 
     loop {
-        output_handle.write(prompt().as_bytes()).unwrap();
+        output_handle.write_all(prompt().as_bytes()).unwrap();
         output_handle.flush().unwrap();
 
-        match read(&mut input_handle) {
-            Ok(tokens) => {
-                println!("Tokens: {:?}", tokens);
-                let output = debugger.eval(tokens).unwrap();
-                println!("output: {:?}", output);
+        let mut line = String::new();
+        match input_handle.read_line(&mut line) {
+            Ok(0) => return,
+            Ok(_) => {},
+            Err(e) => {
+                println!("Error: {}", e);
+                return
+            }
+        }
+
+        let result = tok(&mut line.split_whitespace())
+            .and_then(|tokens| debugger.eval(tokens));
+
+        match result {
+            Ok(output) => {
+                if let Some(text) = display(&output) {
+                    println!("{}", text);
+                }
             },
-            e => println!("Error: {:?}", e)
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => return,
+            Err(e) => println!("Error: {}", e),
         }
     }
-
 }
 
-
 fn prompt() -> String {
     "> ".to_string()
 }
 
-fn read(buffer: &mut dyn BufRead) -> Result<Vec<Token>, Error> {
-    let mut input = String::new();
-    buffer.read_line(&mut input)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gameboy_emulator_v2::cartridge::{Cartridge, Header};
+    use gameboy_emulator_v2::rom::BootRom;
+
+    /* Records commands and answers requests from a fixed snapshot */
+    struct MockTarget {
+        sent: Vec<DebugCommand>,
+        inputs: Vec<Input>,
+        snapshot: Snapshot,
+    }
+
+    impl Target for MockTarget {
+        fn send(&mut self, command: DebugCommand) -> Result<(), Error> {
+            self.sent.push(command);
+            Ok(())
+        }
+
+        fn request(&mut self, command: DebugCommand) -> Result<DebugOutput, Error> {
+            self.send(command)?;
+
+            match command {
+                /* Memory reads back as the low byte of each address */
+                DebugCommand::Read(address, count) => {
+                    let bytes = (0..count).map(|i| address.wrapping_add(i) as u8).collect();
+                    Ok(DebugOutput::Memory(address, bytes))
+                },
+                _ => Ok(DebugOutput::Registers(self.snapshot)),
+            }
+        }
+
+        fn input(&mut self, input: Input) -> Result<(), Error> {
+            self.inputs.push(input);
+            Ok(())
+        }
+    }
+
+    fn test_debugger() -> Debugger<MockTarget> {
+        Debugger::new(MockTarget {
+            sent: Vec::new(),
+            inputs: Vec::new(),
+            snapshot: Snapshot {
+                a: 0x01, b: 0x00, c: 0x13, d: 0x00, e: 0xD8, f: 0xB0, h: 0x01, l: 0x4D,
+                sp: 0xFFFE,
+                pc: 0x0100,
+                ime: false,
+            },
+        })
+    }
+
+    /* A gameboy past the boot rom about to run program at 0x0100 */
+    fn gameboy_with_program(program: &[u8]) -> Gameboy {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x100..0x100 + program.len()].copy_from_slice(program);
+        let cartridge = Cartridge::new(bytes, Header::zero());
+        Gameboy::from_roms(BootRom::zero(), cartridge, true)
+    }
+
+    fn eval<T: Target>(debugger: &mut Debugger<T>, line: &str) -> Output {
+        debugger.eval(tok(&mut line.split_whitespace()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_break_installs_break_point() {
+        let mut debugger = test_debugger();
+
+        eval(&mut debugger, "break 0x0150");
+        eval(&mut debugger, "break 0x0150");
+        assert_eq!(debugger.target.sent, vec![DebugCommand::Break(0x0150)]);
+        assert_eq!(debugger.list(), vec![0x0150]);
+
+        eval(&mut debugger, "delete 0x0150");
+        assert_eq!(debugger.target.sent[1], DebugCommand::Delete(0x0150));
+        assert!(debugger.list().is_empty());
+    }
+
+    #[test]
+    fn test_break_points_file() {
+        let path = std::env::temp_dir().join("gbe_test_break_points.gbedbg");
+        let _ = fs::remove_file(&path);
+
+        write_break_points(&path, &[0x0100, 0xFF80, 0x0150]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0x0100\n0xFF80\n0x0150\n");
+        assert_eq!(read_break_points(&path).unwrap(), vec![0x0100, 0xFF80, 0x0150]);
+
+        fs::write(&path, "0x0100\n\n  c000 \n").unwrap();
+        assert_eq!(read_break_points(&path).unwrap(), vec![0x0100, 0xC000]);
+
+        fs::write(&path, "0x0100\nnope\n").unwrap();
+        assert!(read_break_points(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_break_points_persist() {
+        let path = std::env::temp_dir().join("gbe_test_break_points_persist.gbedbg");
+        let _ = fs::remove_file(&path);
+
+        let mut debugger = Debugger::with_file(test_debugger().target, &path).unwrap();
+        assert!(debugger.list().is_empty());
+
+        eval(&mut debugger, "break 0x0150");
+        eval(&mut debugger, "break 0xC000");
+        eval(&mut debugger, "delete 0x0150");
+        eval(&mut debugger, "break 0x0200");
+
+        /* A new session picks up where the last left off */
+        let mut restarted = Debugger::with_file(test_debugger().target, &path).unwrap();
+        assert_eq!(restarted.list(), vec![0xC000, 0x0200]);
+        assert_eq!(restarted.target.sent, vec![DebugCommand::Break(0xC000), DebugCommand::Break(0x0200)]);
+
+        /* load only sets what's missing */
+        write_break_points(&path, &[0x0200, 0x0300]).unwrap();
+        match eval(&mut restarted, "load") {
+            Output::AddressList(list) => assert_eq!(list, vec![0xC000, 0x0200, 0x0300]),
+            output => panic!("unexpected output: {:?}", output),
+        }
+        assert_eq!(restarted.target.sent[2..], [DebugCommand::Break(0x0300)]);
+
+        eval(&mut restarted, "save");
+        assert_eq!(read_break_points(&path).unwrap(), vec![0xC000, 0x0200, 0x0300]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_without_file() {
+        let mut debugger = test_debugger();
+        eval(&mut debugger, "break 0x0150");
+
+        assert!(debugger.eval(vec![Token::Save]).is_err());
+        assert!(debugger.eval(vec![Token::Load]).is_err());
+    }
+
+    #[test]
+    fn test_tok_break_register() {
+        assert!(matches!(tok(&mut "break rA 0x3C".split_whitespace()).unwrap()[..], [Token::Break, Token::Register(Register::A), Token::Value(0x3C)]));
+        assert!(matches!(tok(&mut "b rHL 0x9800".split_whitespace()).unwrap()[..], [Token::Break, Token::Register(Register::HL), Token::Value(0x9800)]));
+        assert!(tok(&mut "break rA 60".split_whitespace()).is_err());
+        assert!(tok(&mut "break rA 0xZZ".split_whitespace()).is_err());
+    }
+
+    #[test]
+    fn test_break_register() {
+        let mut debugger = test_debugger();
+
+        eval(&mut debugger, "break rA 0x3C");
+        eval(&mut debugger, "break rHL 0x9800");
+        assert_eq!(debugger.target.sent, vec![
+            DebugCommand::BreakRegister(DebugRegister::A, 0x3C),
+            DebugCommand::BreakRegister(DebugRegister::HL, 0x9800),
+        ]);
+
+        /* Register breaks aren't pc break points */
+        assert!(debugger.list().is_empty());
+        assert_eq!(debugger.list_registers(), vec![(Register::A, 0x3C), (Register::HL, 0x9800)]);
+
+        /* Setting one twice only sends it once */
+        eval(&mut debugger, "break rA 0x3C");
+        assert_eq!(debugger.target.sent.len(), 2);
+
+        let too_large = tok(&mut "break rA 0x100".split_whitespace()).unwrap();
+        assert!(debugger.eval(too_large).is_err());
+        let no_value = tok(&mut "break rA".split_whitespace()).unwrap();
+        assert!(debugger.eval(no_value).is_err());
+        assert_eq!(debugger.target.sent.len(), 2);
+    }
+
+    #[test]
+    fn test_list_and_delete_register_breaks() {
+        let mut debugger = test_debugger();
+
+        eval(&mut debugger, "break 0x0150");
+        eval(&mut debugger, "break rA 0x3C");
+        eval(&mut debugger, "break rHL 0x9800");
+        assert_eq!(display(&eval(&mut debugger, "list")).unwrap(), "0x0150\nrA 0x3C\nrHL 0x9800");
+
+        eval(&mut debugger, "delete rA 0x3C");
+        assert_eq!(debugger.target.sent[3], DebugCommand::DeleteRegister(DebugRegister::A, 0x3C));
+        assert_eq!(display(&eval(&mut debugger, "list")).unwrap(), "0x0150\nrHL 0x9800");
+
+        let no_value = tok(&mut "delete rHL".split_whitespace()).unwrap();
+        assert!(debugger.eval(no_value).is_err());
+    }
+
+    #[test]
+    fn test_break_register_live_gameboy() {
+        /* LD A, 0x3C; LD HL, 0x9800; NOP */
+        let mut debugger = Debugger::new(gameboy_with_program(&[0x3E, 0x3C, 0x21, 0x00, 0x98, 0x00]));
+
+        eval(&mut debugger, "break rA 0x3C");
+        eval(&mut debugger, "break rHL 0x9800");
+
+        debugger.target.run_headless(100);
+        assert_eq!(eval_text(&mut debugger, "print rPC"), "PC: 0x0102");
+
+        eval(&mut debugger, "continue");
+        debugger.target.run_headless(100);
+        assert_eq!(eval_text(&mut debugger, "print rPC"), "PC: 0x0105");
+    }
+
+    #[test]
+    fn test_delete_register_live_gameboy() {
+        /* LD A, 0x3C; LD HL, 0x9800; NOP */
+        let mut debugger = Debugger::new(gameboy_with_program(&[0x3E, 0x3C, 0x21, 0x00, 0x98, 0x00]));
+
+        eval(&mut debugger, "break rA 0x3C");
+        eval(&mut debugger, "break rHL 0x9800");
+        eval(&mut debugger, "delete rA 0x3C");
+
+        debugger.target.run_headless(100);
+        assert_eq!(eval_text(&mut debugger, "print rPC"), "PC: 0x0105");
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut debugger = test_debugger();
+
+        eval(&mut debugger, "speed 2");
+        eval(&mut debugger, "speed 0");
+        assert_eq!(debugger.target.inputs, vec![Input::SetSpeed(2.0), Input::SetSpeed(0.0)]);
+
+        assert!(tok(&mut "speed fast".split_whitespace()).is_err());
+        let negative = tok(&mut "speed -1".split_whitespace()).unwrap();
+        assert!(debugger.eval(negative).is_err());
+        assert!(debugger.eval(vec![Token::Speed]).is_err());
+        assert_eq!(debugger.target.inputs.len(), 2);
+    }
+
+    #[test]
+    fn test_print_register() {
+        let mut debugger = test_debugger();
+
+        match eval(&mut debugger, "print rHL") {
+            Output::Text(text) => assert_eq!(text, "HL: 0x014D"),
+            output => panic!("unexpected output: {:?}", output),
+        }
+        assert_eq!(debugger.target.sent, vec![DebugCommand::Registers]);
+    }
+
+    fn eval_text<T: Target>(debugger: &mut Debugger<T>, line: &str) -> String {
+        match eval(debugger, line) {
+            Output::Text(text) => text,
+            output => panic!("unexpected output: {:?}", output),
+        }
+    }
+
+    #[test]
+    fn test_print_registers8() {
+        let mut debugger = test_debugger();
+
+        assert_eq!(eval_text(&mut debugger, "p rA"), "A: 0x01");
+        assert_eq!(eval_text(&mut debugger, "p rC"), "C: 0x13");
+        assert_eq!(eval_text(&mut debugger, "p rE"), "E: 0xD8");
+        assert_eq!(eval_text(&mut debugger, "p rF"), "F: 0xB0");
+        assert_eq!(eval_text(&mut debugger, "p rL"), "L: 0x4D");
+    }
+
+    #[test]
+    fn test_print_registers16() {
+        let mut debugger = test_debugger();
 
-    Ok(tok(&mut input.trim().split_whitespace())?)
+        assert_eq!(eval_text(&mut debugger, "p rAF"), "AF: 0x01B0");
+        assert_eq!(eval_text(&mut debugger, "p rBC"), "BC: 0x0013");
+        assert_eq!(eval_text(&mut debugger, "p rDE"), "DE: 0x00D8");
+        assert_eq!(eval_text(&mut debugger, "p rSP"), "SP: 0xFFFE");
+        assert_eq!(eval_text(&mut debugger, "p rPC"), "PC: 0x0100");
+    }
+
+    #[test]
+    fn test_print_flags() {
+        let mut debugger = test_debugger();
+
+        /* F is 0xB0, 1011 */
+        assert_eq!(eval_text(&mut debugger, "p fZ"), "Z: 1");
+        assert_eq!(eval_text(&mut debugger, "p fN"), "N: 0");
+        assert_eq!(eval_text(&mut debugger, "p fH"), "H: 1");
+        assert_eq!(eval_text(&mut debugger, "p fC"), "C: 1");
+    }
+
+    #[test]
+    fn test_print_all() {
+        let mut debugger = test_debugger();
+
+        assert_eq!(eval_text(&mut debugger, "print"), [
+            "A: 0x01 F: 0xB0 B: 0x00 C: 0x13 D: 0x00 E: 0xD8 H: 0x01 L: 0x4D",
+            "AF: 0x01B0",
+            "BC: 0x0013",
+            "DE: 0x00D8",
+            "HL: 0x014D",
+            "PC: 0x0100",
+            "SP: 0xFFFE",
+            "Z:1 H:1 N:0 C:1",
+            "IME: 0",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_print_address() {
+        let mut debugger = test_debugger();
+
+        assert_eq!(eval_text(&mut debugger, "p 0xFF80"), "0xFF80: 80");
+        assert_eq!(
+            eval_text(&mut debugger, "p 0xC000 18"),
+            "0xC000: 00 01 02 03 04 05 06 07 08 09 0A 0B 0C 0D 0E 0F\n0xC010: 10 11",
+        );
+        assert_eq!(debugger.target.sent[1], DebugCommand::Read(0xC000, 18));
+    }
+
+    #[test]
+    fn test_tok_print_arguments() {
+        assert!(matches!(tok(&mut "print fZ".split_whitespace()).unwrap()[..], [Token::Print, Token::Flag(Flag::Z)]));
+        assert!(matches!(tok(&mut "print 0x8000".split_whitespace()).unwrap()[..], [Token::Print, Token::Address(0x8000)]));
+        assert!(matches!(tok(&mut "print rSP".split_whitespace()).unwrap()[..], [Token::Print, Token::Register(Register::SP)]));
+        assert!(tok(&mut "print fQ".split_whitespace()).is_err());
+    }
+
+    #[test]
+    fn test_tok_examine() {
+        assert!(matches!(tok(&mut "x 0x8000 64".split_whitespace()).unwrap()[..], [Token::Examine, Token::Address(0x8000), Token::Count(64)]));
+        assert!(matches!(tok(&mut "examine 0xFF80".split_whitespace()).unwrap()[..], [Token::Examine, Token::Address(0xFF80)]));
+        assert!(tok(&mut "x 0x8000 lots".split_whitespace()).is_err());
+        assert!(tok(&mut "x 8000".split_whitespace()).is_err());
+    }
+
+    #[test]
+    fn test_examine() {
+        let mut debugger = test_debugger();
+
+        assert_eq!(
+            eval_text(&mut debugger, "x 0xC040 20"),
+            [
+                "0xC040: 40 41 42 43 44 45 46 47 48 49 4A 4B 4C 4D 4E 4F  @ABCDEFGHIJKLMNO",
+                "0xC050: 50 51 52 53                                      PQRS",
+            ].join("\n"),
+        );
+        assert_eq!(debugger.target.sent, vec![DebugCommand::Read(0xC040, 20)]);
+
+        /* 16 bytes by default */
+        eval(&mut debugger, "x 0xC000");
+        assert_eq!(debugger.target.sent[1], DebugCommand::Read(0xC000, 16));
+
+        let no_address = tok(&mut "x rHL".split_whitespace()).unwrap();
+        assert!(debugger.eval(no_address).is_err());
+    }
+
+    #[test]
+    fn test_format_examine() {
+        assert_eq!(
+            format_examine(0x0134, b"TETRIS\x00\x7F ~"),
+            "0x0134: 54 45 54 52 49 53 00 7F 20 7E                    TETRIS.. ~",
+        );
+        assert_eq!(format_examine(0x0000, &[]), "");
+    }
+
+    #[test]
+    fn test_print_live_gameboy() {
+        /* LD A, 0x01; OR A; LD (0xC000), A */
+        let mut debugger = Debugger::new(gameboy_with_program(&[0x3E, 0x01, 0xB7, 0xEA, 0x00, 0xC0]));
+
+        /* Z is set after the boot rom */
+        assert_eq!(eval_text(&mut debugger, "print fZ"), "Z: 1");
+        assert_eq!(eval_text(&mut debugger, "print 0xC000"), "0xC000: 00");
+
+        for _ in 0..3 {
+            eval(&mut debugger, "step");
+        }
+
+        assert_eq!(eval_text(&mut debugger, "print fZ"), "Z: 0");
+        assert_eq!(eval_text(&mut debugger, "print 0xC000"), "0xC000: 01");
+        assert_eq!(eval_text(&mut debugger, "print rPC"), "PC: 0x0106");
+    }
+
+    #[test]
+    fn test_print_invalid_count() {
+        assert!(tok(&mut "p 0xC000 lots".split_whitespace()).is_err());
+    }
+
+    #[test]
+    fn test_step_and_continue() {
+        let mut debugger = test_debugger();
+
+        match eval(&mut debugger, "step") {
+            Output::Registers(snapshot) => assert_eq!(snapshot.pc, 0x0100),
+            output => panic!("unexpected output: {:?}", output),
+        }
+
+        eval(&mut debugger, "continue");
+        assert_eq!(debugger.target.sent, vec![DebugCommand::Step, DebugCommand::Continue]);
+    }
+
+    #[test]
+    fn test_next() {
+        let mut debugger = test_debugger();
+        eval(&mut debugger, "n");
+
+        assert_eq!(debugger.target.sent, vec![DebugCommand::Next]);
+    }
 }
@@ -1,25 +1,71 @@
 use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Read;
+use std::str::FromStr;
 
 use crate::helpers::open_file;
 use crate::device::Device;
 
+/* The boot roms shipped in different models differ slightly, most
+ * importantly in the value they leave in register A which games use
+ * to detect what hardware they're running on.
+ */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum BootRomVariant {
+    // Early original gameboy
+    DMG0,
+    // Original gameboy
+    DMG,
+    // Gameboy pocket
+    MGB,
+}
+
+impl FromStr for BootRomVariant {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<BootRomVariant, Error> {
+        match s.to_lowercase().as_str() {
+            "dmg0" => Ok(BootRomVariant::DMG0),
+            "dmg" => Ok(BootRomVariant::DMG),
+            "mgb" => Ok(BootRomVariant::MGB),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("invalid boot rom variant: {}", s))),
+        }
+    }
+}
+
 pub struct BootRom {
-    storage: [u8;256]
+    storage: [u8;256],
+    pub variant: BootRomVariant,
 }
 
 impl BootRom {
-    pub fn read(p: &str) -> Result<BootRom, Error> {
-        let mut bytes = [0; 256];
+    pub fn read(p: &str, variant: BootRomVariant) -> Result<BootRom, Error> {
+        let mut bytes = Vec::new();
         let mut f = open_file(p)?;
-        f.read(&mut bytes)?;
+        f.read_to_end(&mut bytes)?;
 
-        Ok(BootRom { storage: bytes })
+        BootRom::from_bytes(&bytes, variant)
+    }
+
+    /* All of the DMG family boot roms are exactly 256 bytes */
+    pub fn from_bytes(bytes: &[u8], variant: BootRomVariant) -> Result<BootRom, Error> {
+        if bytes.len() != 256 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid boot rom, expected 256 bytes found {}", bytes.len()),
+            ));
+        }
+
+        let mut storage = [0; 256];
+        storage.copy_from_slice(bytes);
+
+        Ok(BootRom { storage, variant })
     }
 
     pub fn zero() -> BootRom {
         BootRom {
-            storage: [0; 256]
+            storage: [0; 256],
+            variant: BootRomVariant::DMG,
         }
     }
 }
@@ -35,3 +81,21 @@ impl Device for BootRom {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes_length() {
+        assert!(BootRom::from_bytes(&[0; 256], BootRomVariant::DMG).is_ok());
+        assert!(BootRom::from_bytes(&[0; 255], BootRomVariant::DMG).is_err());
+        assert!(BootRom::from_bytes(&[0; 512], BootRomVariant::MGB).is_err());
+    }
+
+    #[test]
+    fn test_parse_variant() {
+        assert_eq!("MGB".parse::<BootRomVariant>().unwrap(), BootRomVariant::MGB);
+        assert_eq!("dmg0".parse::<BootRomVariant>().unwrap(), BootRomVariant::DMG0);
+        assert!("cgb".parse::<BootRomVariant>().is_err());
+    }
+}
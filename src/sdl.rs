@@ -1,27 +1,46 @@
 use sdl2;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::render::Canvas;
+use sdl2::render::{Canvas, Texture};
 use sdl2::video::Window;
-use sdl2::pixels::Color;
-use sdl2::rect::Point;
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::rect::Rect;
 
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::shade::Shade;
-use crate::msg::{Frame, TileMap};
+use crate::msg::{Frame, TileMap, TileMapView, Input, DebugCommand};
+use crate::config::ShadeColors;
+use crate::framebuffer::{self, Buffer, ColorBuffer};
+use crate::color::{self, ColorCorrection};
 
 use anyhow;
 use rate_limiter::RateLimiter;
+use fps::FpsCounter;
+use turbo::{Turbo, KeyEvent, skip_frame};
+use audio::Audio;
+use layout::{Layout, Area};
+
+pub use turbo::TurboMode;
+pub use layout::{MIN_SCALE, MAX_SCALE};
 
 mod rate_limiter;
+mod fps;
+mod turbo;
+mod keymap;
+mod audio;
+mod layout;
 
-const SCALE:u32 = 4;
+/* How long to wait on the emulator for a frame before handling events
+ * anyway, while paused frames only arrive as the emulator waits on input.
+ */
+const FRAME_WAIT: Duration = Duration::from_millis(100);
 
 #[derive(PartialEq, Eq)]
 enum State {
     Running,
+    Paused,
 }
 
 pub struct SDL {
@@ -29,18 +48,40 @@ pub struct SDL {
     canvas: Canvas<Window>,
     sdl_context: sdl2::Sdl,
     frames_channel: Receiver<Box<Frame>>,
+    input_sender: Sender<Input>,
+    rate_limiter: RateLimiter,
+    turbo: Turbo,
+    speed: f64,
+    frames: u64,
+    audio: Option<Audio>,
+    colors: ShadeColors,
+    color_correction: ColorCorrection,
+    tile_map_view: TileMapView,
+
+    /* The last screen drawn, for screenshots, with its CGB colors when the
+     * cart is CGB
+     */
+    screen: Buffer,
+    screen_colors: Option<ColorBuffer>,
+    layout: Layout,
 }
 
 impl SDL {
-    pub fn new(frames_channel: Receiver<Box<Frame>>) -> anyhow::Result<SDL> {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
+    pub fn new(
+        frames_channel: Receiver<Box<Frame>>,
+        input_sender: Sender<Input>,
+        scale: u32,
+        debug_view: bool,
+    ) -> anyhow::Result<SDL> {
+        let sdl_context = sdl2::init().map_err(anyhow::Error::msg)?;
+        let video_subsystem = sdl_context.video().map_err(anyhow::Error::msg)?;
+
+        let mut layout = Layout::new(scale);
+        layout.debug_view = debug_view;
+        let (width, height) = layout.window();
 
         let window = video_subsystem
-            .window("Gameboy",
-                (160 * SCALE) + 256,
-                144 * SCALE
-            )
+            .window("Gameboy", width, height)
             .position_centered()
             .build()?;
 
@@ -51,113 +92,294 @@ impl SDL {
             canvas: canvas,
             sdl_context: sdl_context,
             frames_channel: frames_channel,
+            input_sender,
+            rate_limiter: RateLimiter::new(60),
+            turbo: Turbo::new(TurboMode::Hold),
+            speed: 1.0,
+            frames: 0,
+            audio: None,
+            colors: ShadeColors::greyscale(),
+            color_correction: ColorCorrection::None,
+            tile_map_view: TileMapView::new(),
+            screen: framebuffer::new(),
+            screen_colors: None,
+            layout,
         })
     }
 
-    /* For each pixel in the framebuffer render the palette shade into a point of
-     * a specific color on the canvas.
+    /* Whether the turbo key (tab) needs to be held or toggles */
+    pub fn set_turbo_mode(&mut self, mode: TurboMode) {
+        self.turbo = Turbo::new(mode);
+    }
+
+    /* Multiplies the target frame rate, turbo lifts the limit entirely */
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+        self.rate_limiter.set_speed(speed);
+    }
+
+    fn fast(&self) -> bool {
+        self.turbo.active() || self.speed > 1.0
+    }
+
+    /* Opens the audio device and plays interleaved stereo samples sent by
+     * the emulator. Without this the display is silent.
      */
-    pub fn draw_frame(&mut self, origin_x:i32, origin_y: i32, frame: [[Shade;160];144]) {
-        self.canvas.set_draw_color(Color::RGBA(255, 0, 0, 255));
-        self.canvas.draw_rect(Rect::new(origin_x, origin_y, 162, 144)).unwrap();
+    pub fn enable_audio(&mut self, samples: Receiver<Vec<f32>>) -> anyhow::Result<()> {
+        self.audio = Some(Audio::new(&self.sdl_context, samples)?);
+        Ok(())
+    }
 
-        for y in 0..144 {
-            for x in 0..160 {
-                let shade = frame[y][x];
-                self.set_draw_color(shade);
+    /* The colors drawn for each shade in every panel */
+    pub fn set_colors(&mut self, colors: ShadeColors) {
+        self.colors = colors;
+    }
 
-                let rx = (x as i32 + origin_x) * SCALE as i32;
-                let ry = (y as i32 + origin_y) * SCALE as i32;
+    /* How CGB colors are converted for the screen */
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.color_correction = correction;
+    }
 
-                self.canvas.fill_rect(
-                    Rect::new(rx, ry, SCALE, SCALE)
-                ).unwrap();
-            }
-        }
+    /* Packs shades into RGBA32 bytes for a texture upload */
+    fn rgba(&self, shades: impl Iterator<Item = Shade>) -> Vec<u8> {
+        shades.flat_map(|shade| self.colors.rgba(shade).to_vec()).collect()
     }
 
-    pub fn set_draw_color(&mut self, shade: Shade) {
-        match shade {
-            Shade::White => {
-                self.canvas.set_draw_color(Color::RGBA(255, 255, 255, 255))
-            }
-            Shade::LightGrey => {
-                self.canvas.set_draw_color(Color::RGBA(211, 211, 211, 255))
-            }
-            Shade::DarkGrey => {
-                self.canvas.set_draw_color(Color::RGBA(169, 169, 169, 255))
-            }
-            Shade::Black => {
-                self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 255))
-            }
+    /* Stop throttling to 60fps and draw frames as fast as they arrive */
+    pub fn set_unlimited(&mut self) {
+        self.rate_limiter = RateLimiter::unlimited();
+    }
+
+    /* Input::SetSpeed, 0 switches the rate limiter off and anything else
+     * switches it back on at that speed
+     */
+    fn change_speed(&mut self, speed: f64) {
+        if speed == 0.0 {
+            self.set_unlimited();
+        } else {
+            self.rate_limiter = RateLimiter::new(60);
+            self.set_speed(speed);
         }
     }
 
-    pub fn draw_tile_map(&mut self, origin_x: i32, origin_y: i32, tile_map: TileMap) {
-        for y in 0..256 {
-            for x in 0..256 {
-                let pixel = tile_map.pixels[y][x];
-                let shade = tile_map.palette.map(pixel);
-                self.set_draw_color(shade);
+    /* Uploads the framebuffer to the texture and copies it to the canvas
+     * scaled up. CGB carts are drawn from their colors rather than shades.
+     */
+    pub fn draw_frame(&mut self, texture: &mut Texture, area: Area, frame: [[Shade;160];144], colors: Option<ColorBuffer>) {
+        let pixels = match colors {
+            Some(colors) => colors.iter()
+                .flat_map(|row| row.iter())
+                .flat_map(|c| {
+                    let (r, g, b) = color::rgb555_to_rgb888(*c, self.color_correction);
+                    vec![r, g, b, 255]
+                })
+                .collect(),
+            None => self.rgba(frame.iter().flat_map(|row| row.iter().copied())),
+        };
+        texture.update(None, &pixels, 160 * 4).unwrap();
 
-                self.canvas.draw_point(
-                    Point::new(x as i32 + origin_x, y as i32 + origin_y)
-                ).unwrap();
-            }
+        self.canvas.copy(texture, None, area.rect()).unwrap();
+    }
+
+    pub fn draw_tile_map(&mut self, texture: &mut Texture, area: Area, tile_map: TileMap) {
+        let palette = tile_map.palette;
+        let pixels = self.rgba(tile_map.pixels[0..256].iter().flat_map(|row| row.iter().map(|p| palette.map(*p))));
+        texture.update(None, &pixels, 256 * 4).unwrap();
+
+        self.canvas.copy(texture, None, area.rect()).unwrap();
+
+        if !tile_map.background {
+            return
         }
 
+        /* The viewport goes on top of the copied texture */
         self.canvas.set_draw_color(Color::RGBA(255, 0, 0, 126));
 
         self.canvas.draw_rect(
             Rect::new(
-                (tile_map.scroll_x as i32) + origin_x,
-                tile_map.scroll_y as i32,
+                (tile_map.scroll_x as i32) + area.x,
+                (tile_map.scroll_y as i32) + area.y,
                 160, 144)
         ).unwrap();
     }
 
-    fn draw_tiles(&mut self, origin_x: i32, origin_y: i32, tiles: [[Shade; 256];96]) {
-        for y in 0..96 {
-            for x in 0..256 {
-                let shade = tiles[y][x];
-                self.set_draw_color(shade);
+    fn draw_tiles(&mut self, texture: &mut Texture, area: Area, tiles: [[Shade; 256];96]) {
+        let pixels = self.rgba(tiles.iter().flat_map(|row| row.iter().copied()));
+        texture.update(None, &pixels, 256 * 4).unwrap();
 
-                self.canvas.draw_point(
-                    Point::new(x as i32 + origin_x, y as i32 + origin_y)
-                ).unwrap();
-            }
+        self.canvas.copy(texture, None, area.rect()).unwrap();
+    }
+
+    fn send_button(&self, keycode: Keycode, pressed: bool) {
+        if let Some(button) = keymap::button(keycode) {
+            /* The emulator thread going away is noticed on the frame channel */
+            let _ = self.input_sender.send(Input::Button(button, pressed));
         }
     }
 
+    /* Pausing stops the emulator itself, N steps one instruction while paused */
+    fn toggle_pause(&mut self) {
+        let (state, command) = match self.state {
+            State::Running => (State::Paused, DebugCommand::Pause),
+            State::Paused => (State::Running, DebugCommand::Continue),
+        };
 
-    pub fn start(&mut self) {
-        let mut rate_limiter = RateLimiter::new(60);
+        self.state = state;
+        let _ = self.input_sender.send(Input::Debug(command));
+    }
+
+    /* M switches the tile map view between 0x9800 and 0x9C00, T between the
+     * 0x8000 and 0x8800 tile data.
+     */
+    fn toggle_tile_map_view(&mut self, keycode: Keycode) {
+        match keycode {
+            Keycode::M => self.tile_map_view.map = !self.tile_map_view.map,
+            _ => self.tile_map_view.unsigned_tiles = !self.tile_map_view.unsigned_tiles,
+        }
+
+        let _ = self.input_sender.send(Input::TileMapView(self.tile_map_view));
+    }
+
+    /* D shows or hides the tile map and tile set, the window resizes to fit */
+    fn toggle_debug_view(&mut self) -> anyhow::Result<()> {
+        self.layout.debug_view = !self.layout.debug_view;
+
+        let (width, height) = self.layout.window();
+        self.canvas.window_mut().set_size(width, height)?;
+        Ok(())
+    }
+
+    /* Writes the screen to screenshot-<milliseconds since the epoch>.png in
+     * the working directory
+     */
+    fn screenshot(&self) {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis()).unwrap_or(0);
+        let path = format!("screenshot-{}.png", millis);
+
+        let written = match &self.screen_colors {
+            Some(colors) => framebuffer::write_color_png(&path, colors, self.color_correction),
+            None => framebuffer::write_png(&path, &self.screen, &self.colors),
+        };
+
+        match written {
+            Ok(()) => println!("saved {}", path),
+            Err(e) => println!("failed to save {}: {}", path, e),
+        }
+    }
+
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        let mut fps = FpsCounter::new(60);
+        let mut last_frame = Instant::now();
+        let mut last_title = Instant::now();
+
+        /* One streaming texture per panel, each frame is uploaded once and
+         * copied rather than drawn a pixel at a time.
+         */
+        let texture_creator = self.canvas.texture_creator();
+        let mut main_texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, 160, 144)?;
+        let mut tile_map_texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, 256, 256)?;
+        let mut tiles_texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, 256, 96)?;
 
         'mainloop: loop {
-            match self.state {
-                State::Running => {
-                    let frame = self.frames_channel.recv().unwrap();
+            let frame = match self.frames_channel.recv_timeout(FRAME_WAIT) {
+                Ok(frame) => Some(frame),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break 'mainloop,
+            };
+
+            if let Some(frame) = frame {
+                if let Some(speed) = frame.speed {
+                    self.change_speed(speed);
+                }
 
-                    self.draw_frame(0,0, frame.main);
-                    self.draw_tile_map(160*SCALE as i32, 0, frame.tile_map);
-                    self.draw_tiles(160*SCALE as i32, 256, frame.tiles);
+                let skip = self.state == State::Running && skip_frame(self.fast(), self.frames);
+
+                self.screen = frame.main;
+                self.screen_colors = frame.colors;
+
+                if !skip {
+                    /* Clears whatever the panels don't cover */
+                    self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+                    self.canvas.clear();
+
+                    self.draw_frame(&mut main_texture, self.layout.screen(), frame.main, frame.colors);
+                    if self.layout.debug_view {
+                        self.draw_tile_map(&mut tile_map_texture, self.layout.tile_map(), frame.tile_map);
+                        self.draw_tiles(&mut tiles_texture, self.layout.tiles(), frame.tiles);
+                    }
 
                     self.canvas.present();
                 }
+                self.frames += 1;
             }
 
-            rate_limiter.limit();
+            if let Some(audio) = self.audio.as_ref() {
+                audio.pump();
+            }
 
-            let mut events = self.sdl_context.event_pump().unwrap();
+            /* Turbo runs unthrottled, like RateLimiter::unlimited */
+            if !self.turbo.active() {
+                self.rate_limiter.limit();
+            }
+
+            let now = Instant::now();
+            fps.record(now.duration_since(last_frame));
+            last_frame = now;
+
+            if now.duration_since(last_title) >= Duration::from_secs(1) {
+                let title = match self.state {
+                    State::Running => format!("Gameboy - {:.1} fps ({:.0}%)", fps.fps(), fps.speed()),
+                    State::Paused => String::from("Gameboy - paused"),
+                };
+                self.canvas.window_mut().set_title(&title).unwrap();
+                last_title = now;
+            }
+
+            let mut events = self.sdl_context.event_pump().map_err(anyhow::Error::msg)?;
 
             for event in events.poll_iter() {
                 match event {
                     Event::Quit { .. } | Event::KeyDown { keycode: Option::Some(Keycode::Escape), ..  } => {
                         break 'mainloop
                     },
+                    Event::KeyDown { keycode: Option::Some(Keycode::Tab), repeat: false, .. } => {
+                        self.turbo.handle(KeyEvent::Down);
+                    },
+                    Event::KeyUp { keycode: Option::Some(Keycode::Tab), .. } => {
+                        self.turbo.handle(KeyEvent::Up);
+                    },
+                    Event::KeyDown { keycode: Option::Some(Keycode::P), repeat: false, .. } => {
+                        self.toggle_pause();
+                    },
+                    Event::KeyDown { keycode: Option::Some(Keycode::N), .. } if self.state == State::Paused => {
+                        let _ = self.input_sender.send(Input::Step);
+                    },
+                    Event::KeyDown { keycode: Option::Some(keycode @ (Keycode::M | Keycode::T)), repeat: false, .. } => {
+                        self.toggle_tile_map_view(keycode);
+                    },
+                    Event::KeyDown { keycode: Option::Some(Keycode::D), repeat: false, .. } => {
+                        self.toggle_debug_view()?;
+                    },
+                    Event::KeyDown { keycode: Option::Some(Keycode::F12), repeat: false, .. } => {
+                        self.screenshot();
+                    },
+                    Event::KeyDown { keycode: Option::Some(Keycode::F5), repeat: false, .. } => {
+                        let _ = self.input_sender.send(Input::SaveState);
+                    },
+                    Event::KeyDown { keycode: Option::Some(Keycode::F7), repeat: false, .. } => {
+                        let _ = self.input_sender.send(Input::LoadState);
+                    },
+                    Event::KeyDown { keycode: Option::Some(keycode), repeat: false, .. } => {
+                        self.send_button(keycode, true);
+                    },
+                    Event::KeyUp { keycode: Option::Some(keycode), .. } => {
+                        self.send_button(keycode, false);
+                    },
                     _ => {}
                 }
             }
         }
+
+        Ok(())
     }
 }
@@ -0,0 +1,43 @@
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+use std::sync::mpsc::Receiver;
+
+use crate::device::apu::DEFAULT_SAMPLE_RATE;
+
+/* Keep at most ~100ms of audio queued. When the emulator runs ahead (turbo,
+ * unlimited) batches past this are dropped rather than building up latency,
+ * and when it falls behind the device simply plays silence. Neither blocks
+ * the emulator thread.
+ */
+const MAX_QUEUED_BYTES: u32 = DEFAULT_SAMPLE_RATE / 10 * 2 * 4;
+
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    samples: Receiver<Vec<f32>>,
+}
+
+impl Audio {
+    pub fn new(sdl_context: &sdl2::Sdl, samples: Receiver<Vec<f32>>) -> anyhow::Result<Audio> {
+        let audio_subsystem = sdl_context.audio().map_err(anyhow::Error::msg)?;
+
+        let spec = AudioSpecDesired {
+            freq: Some(DEFAULT_SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: None,
+        };
+
+        let queue = audio_subsystem.open_queue(None, &spec).map_err(anyhow::Error::msg)?;
+        queue.resume();
+
+        Ok(Audio { queue, samples })
+    }
+
+    /* Moves any sample batches from the emulator into the device queue */
+    pub fn pump(&self) {
+        for batch in self.samples.try_iter() {
+            if self.queue.size() < MAX_QUEUED_BYTES {
+                self.queue.queue(&batch);
+            }
+        }
+    }
+}
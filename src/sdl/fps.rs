@@ -0,0 +1,76 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/* The DMG refresh rate, 4194304 Hz / 70224 cycles per frame */
+pub const GAMEBOY_FPS: f64 = 59.73;
+
+/* Rolling average of the most recent frame times
+ */
+pub struct FpsCounter {
+    durations: VecDeque<Duration>,
+    capacity: usize,
+}
+
+impl FpsCounter {
+    pub fn new(capacity: usize) -> FpsCounter {
+        FpsCounter {
+            durations: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        if self.durations.len() == self.capacity {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(duration);
+    }
+
+    pub fn fps(&self) -> f64 {
+        average_fps(self.durations.iter())
+    }
+
+    /* Emulation speed as a percentage of real hardware */
+    pub fn speed(&self) -> f64 {
+        self.fps() / GAMEBOY_FPS * 100.0
+    }
+}
+
+pub fn average_fps<'a>(durations: impl Iterator<Item = &'a Duration>) -> f64 {
+    let (count, total) = durations.fold((0, Duration::from_secs(0)), |(n, t), d| (n + 1, t + *d));
+
+    if count == 0 || total.as_secs_f64() == 0.0 {
+        return 0.0
+    }
+
+    count as f64 / total.as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_average_fps() {
+        let durations = [
+            Duration::from_millis(10),
+            Duration::from_millis(20),
+            Duration::from_millis(30),
+        ];
+
+        /* 3 frames in 60ms */
+        assert!((average_fps(durations.iter()) - 50.0).abs() < 0.001);
+        assert_eq!(average_fps([].iter()), 0.0);
+    }
+
+    #[test]
+    fn test_fps_counter_rolls() {
+        let mut counter = FpsCounter::new(2);
+        counter.record(Duration::from_millis(100));
+        counter.record(Duration::from_millis(20));
+        counter.record(Duration::from_millis(20));
+
+        assert!((counter.fps() - 50.0).abs() < 0.001);
+        assert!((counter.speed() - (50.0 / GAMEBOY_FPS * 100.0)).abs() < 0.001);
+    }
+}
@@ -0,0 +1,36 @@
+use sdl2::keyboard::Keycode;
+
+use crate::msg::Button;
+
+/* Keyboard layout for the gameboy buttons
+ *
+ *   arrows       - d-pad
+ *   z / x        - a / b
+ *   enter        - start
+ *   right shift  - select
+ */
+pub fn button(keycode: Keycode) -> Option<Button> {
+    match keycode {
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Right => Some(Button::Right),
+        Keycode::Z => Some(Button::A),
+        Keycode::X => Some(Button::B),
+        Keycode::Return => Some(Button::Start),
+        Keycode::RShift => Some(Button::Select),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_button() {
+        assert_eq!(button(Keycode::Z), Some(Button::A));
+        assert_eq!(button(Keycode::RShift), Some(Button::Select));
+        assert_eq!(button(Keycode::Tab), None);
+    }
+}
@@ -0,0 +1,130 @@
+use sdl2::rect::Rect;
+
+/* Window layout
+ *
+ * The screen is drawn scaled up in the top left, the debug panels sit to
+ * its right at their natural size, the tile map with the tile set under
+ * it. The window is tall enough for whichever column is taller, without
+ * the debug view it's just the screen.
+ */
+pub const MIN_SCALE: u32 = 1;
+pub const MAX_SCALE: u32 = 8;
+
+const SCREEN: (u32, u32) = (160, 144);
+const TILE_MAP: (u32, u32) = (256, 256);
+const TILES: (u32, u32) = (256, 96);
+
+/* A rectangle in window pixels */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Area {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Area {
+    pub fn rect(&self) -> Rect {
+        Rect::new(self.x, self.y, self.width, self.height)
+    }
+}
+
+pub struct Layout {
+    scale: u32,
+    pub debug_view: bool,
+}
+
+impl Layout {
+    pub fn new(scale: u32) -> Layout {
+        assert!((MIN_SCALE..=MAX_SCALE).contains(&scale), "scale must be between {} and {}", MIN_SCALE, MAX_SCALE);
+        Layout {
+            scale,
+            debug_view: true,
+        }
+    }
+
+    pub fn screen(&self) -> Area {
+        Area {
+            x: 0,
+            y: 0,
+            width: SCREEN.0 * self.scale,
+            height: SCREEN.1 * self.scale,
+        }
+    }
+
+    pub fn tile_map(&self) -> Area {
+        Area {
+            x: self.screen().width as i32,
+            y: 0,
+            width: TILE_MAP.0,
+            height: TILE_MAP.1,
+        }
+    }
+
+    pub fn tiles(&self) -> Area {
+        Area {
+            x: self.screen().width as i32,
+            y: TILE_MAP.1 as i32,
+            width: TILES.0,
+            height: TILES.1,
+        }
+    }
+
+    /* The width and height of the window */
+    pub fn window(&self) -> (u32, u32) {
+        let screen = self.screen();
+
+        if !self.debug_view {
+            return (screen.width, screen.height)
+        }
+
+        (screen.width + TILE_MAP.0, screen.height.max(TILE_MAP.1 + TILES.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let layout = Layout::new(4);
+        assert_eq!(layout.screen(), Area { x: 0, y: 0, width: 640, height: 576 });
+        assert_eq!(layout.tile_map(), Area { x: 640, y: 0, width: 256, height: 256 });
+        assert_eq!(layout.tiles(), Area { x: 640, y: 256, width: 256, height: 96 });
+        assert_eq!(layout.window(), (896, 576));
+    }
+
+    #[test]
+    fn test_small_scale_fits_panels() {
+        let layout = Layout::new(1);
+        assert_eq!(layout.screen(), Area { x: 0, y: 0, width: 160, height: 144 });
+        assert_eq!(layout.tile_map().x, 160);
+
+        /* Too short for the panels at 144, the window grows to fit them */
+        assert_eq!(layout.window(), (416, 352));
+    }
+
+    #[test]
+    fn test_without_debug_view() {
+        let mut layout = Layout::new(4);
+        layout.debug_view = false;
+
+        assert_eq!(layout.window(), (640, 576));
+        assert_eq!(layout.screen(), Layout::new(4).screen());
+
+        layout.debug_view = true;
+        assert_eq!(layout.window(), (896, 576));
+
+        /* Nothing to make room for at scale 1 either */
+        let mut layout = Layout::new(1);
+        layout.debug_view = false;
+        assert_eq!(layout.window(), (160, 144));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_scale_out_of_range() {
+        Layout::new(MAX_SCALE + 1);
+    }
+}
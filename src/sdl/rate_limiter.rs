@@ -3,27 +3,85 @@ use std::time::{Duration, Instant};
 /* Clamp framerate to a specific value.
  */
 pub struct RateLimiter {
-    // seconds per frame in ms
-    available_time: Duration,
+    // seconds per frame in ms, None runs as fast as possible
+    available_time: Option<Duration>,
+    frame_time: Option<Duration>,
     previous: Instant,
 }
 
 impl RateLimiter {
     pub fn new(fps:u64) -> RateLimiter {
+        let frame_time = Some(Duration::from_millis(1000 / fps));
+
+        RateLimiter {
+            available_time: frame_time,
+            frame_time,
+            previous: Instant::now(),
+        }
+    }
+
+    /* Never sleeps, for measuring raw emulation throughput */
+    pub fn unlimited() -> RateLimiter {
         RateLimiter {
-            available_time: Duration::from_millis(1000 / fps),
+            available_time: None,
+            frame_time: None,
             previous: Instant::now(),
         }
     }
 
+    /* Scales the frame rate, 2.0 runs twice as fast */
+    pub fn set_speed(&mut self, speed: f64) {
+        self.available_time = self.frame_time.map(|t| t.div_f64(speed));
+    }
+
     pub fn limit(&mut self) {
         let now = Instant::now();
         let duration = now.duration_since(self.previous);
 
-        if self.available_time > duration {
-            std::thread::sleep(self.available_time - duration);
+        if let Some(sleep) = self.sleep_time(duration) {
+            std::thread::sleep(sleep);
         }
 
         self.previous = Instant::now();
     }
+
+    /* How long to sleep given the time elapsed since the last frame */
+    fn sleep_time(&self, elapsed: Duration) -> Option<Duration> {
+        match self.available_time {
+            Some(available) if available > elapsed => Some(available - elapsed),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sleep_time() {
+        let limiter = RateLimiter::new(50);
+
+        assert_eq!(limiter.sleep_time(Duration::from_millis(5)), Some(Duration::from_millis(15)));
+        assert_eq!(limiter.sleep_time(Duration::from_millis(25)), None);
+    }
+
+    #[test]
+    fn test_set_speed() {
+        let mut limiter = RateLimiter::new(50);
+        limiter.set_speed(2.0);
+        assert_eq!(limiter.sleep_time(Duration::from_millis(5)), Some(Duration::from_millis(5)));
+
+        limiter.set_speed(1.0);
+        assert_eq!(limiter.sleep_time(Duration::from_millis(5)), Some(Duration::from_millis(15)));
+    }
+
+    #[test]
+    fn test_unlimited_never_sleeps() {
+        let limiter = RateLimiter::unlimited();
+
+        assert_eq!(limiter.sleep_time(Duration::from_millis(0)), None);
+        assert_eq!(limiter.sleep_time(Duration::from_millis(5)), None);
+        assert_eq!(limiter.sleep_time(Duration::from_secs(10)), None);
+    }
 }
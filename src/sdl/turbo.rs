@@ -0,0 +1,92 @@
+/* Turbo (fast forward) key handling
+ *
+ * While turbo is on the display stops throttling altogether, the same as
+ * RateLimiter::unlimited. Some people want turbo only while the key is
+ * held, others want one press to switch it on and another to switch it off.
+ */
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TurboMode {
+    Hold,
+    Toggle,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum KeyEvent {
+    Down,
+    Up,
+}
+
+pub struct Turbo {
+    mode: TurboMode,
+    active: bool,
+}
+
+impl Turbo {
+    pub fn new(mode: TurboMode) -> Turbo {
+        Turbo {
+            mode,
+            active: false,
+        }
+    }
+
+    /* Feeds a turbo key event through, key repeats should be filtered out
+     * before they get here. Returns whether turbo is now on.
+     */
+    pub fn handle(&mut self, event: KeyEvent) -> bool {
+        self.active = match (self.mode, event) {
+            (TurboMode::Hold, KeyEvent::Down) => true,
+            (TurboMode::Hold, KeyEvent::Up) => false,
+            (TurboMode::Toggle, KeyEvent::Down) => !self.active,
+            (TurboMode::Toggle, KeyEvent::Up) => self.active,
+        };
+
+        self.active
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+}
+
+/* Running faster than normal only every other frame is drawn, the emulator
+ * still produces all of them so the game itself runs at full speed.
+ */
+pub fn skip_frame(fast: bool, frame: u64) -> bool {
+    fast && frame & 0x01 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(mode: TurboMode, events: &[KeyEvent]) -> Vec<bool> {
+        let mut turbo = Turbo::new(mode);
+        events.iter().map(|e| turbo.handle(*e)).collect()
+    }
+
+    #[test]
+    fn test_hold() {
+        assert_eq!(
+            states(TurboMode::Hold, &[KeyEvent::Down, KeyEvent::Up, KeyEvent::Down, KeyEvent::Up]),
+            vec![true, false, true, false],
+        );
+    }
+
+    #[test]
+    fn test_toggle() {
+        assert_eq!(
+            states(TurboMode::Toggle, &[KeyEvent::Down, KeyEvent::Up, KeyEvent::Down, KeyEvent::Up]),
+            vec![true, true, false, false],
+        );
+    }
+
+    #[test]
+    fn test_skip_frame() {
+        assert!(!skip_frame(false, 0));
+        assert!(!skip_frame(false, 1));
+        assert!(!skip_frame(true, 0));
+        assert!(skip_frame(true, 1));
+        assert!(skip_frame(true, 3));
+    }
+}
@@ -0,0 +1,196 @@
+use std::io::{Error, ErrorKind};
+
+/* Save states
+ *
+ * A save state is the MAGIC bytes and a VERSION followed by every
+ * component's state written in a fixed order, multi byte values are little
+ * endian. Nothing is tagged so adding, removing or reordering a field means
+ * bumping VERSION, loading a state with a different version is refused
+ * rather than misread.
+ *
+ * The cartridge rom and boot rom aren't included, a state can only be
+ * loaded back into the game that produced it.
+ */
+pub const MAGIC: &[u8; 4] = b"GBES";
+pub const VERSION: u8 = 7;
+
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), Error>;
+}
+
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        StateWriter { bytes }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.bytes.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.bytes.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /* Variable length data is prefixed with its length */
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.bytes.extend_from_slice(v);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("invalid save state: {}", message))
+}
+
+impl<'a> StateReader<'a> {
+    /* Checks the header, the reader starts at the first component */
+    pub fn new(bytes: &'a [u8]) -> Result<StateReader<'a>, Error> {
+        if bytes.len() < MAGIC.len() + 1 || &bytes[0..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a save state"));
+        }
+
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(invalid(&format!("version {} is not supported, expected {}", version, VERSION)));
+        }
+
+        Ok(StateReader {
+            bytes,
+            position: MAGIC.len() + 1,
+        })
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.position + n > self.bytes.len() {
+            return Err(invalid("unexpected end of data"));
+        }
+
+        let slice = &self.bytes[self.position..self.position + n];
+        self.position += n;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    /* Reads length prefixed data into a buffer that must be the same size */
+    pub fn bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        let bytes = self.bytes()?;
+
+        if bytes.len() != buffer.len() {
+            return Err(invalid(&format!("expected {} bytes found {}", buffer.len(), bytes.len())));
+        }
+
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(), Error> {
+        if self.position != self.bytes.len() {
+            return Err(invalid("trailing data"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789A_BCDE);
+        w.u64(0x0102_0304_0506_0708);
+        w.bytes(&[1, 2, 3]);
+        let bytes = w.finish();
+
+        let mut r = StateReader::new(&bytes).unwrap();
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0x3456);
+        assert_eq!(r.u32().unwrap(), 0x789A_BCDE);
+        assert_eq!(r.u64().unwrap(), 0x0102_0304_0506_0708);
+
+        let mut buffer = [0; 3];
+        r.bytes_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert!(r.finish().is_ok());
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let mut bytes = StateWriter::new().finish();
+        bytes[MAGIC.len()] = VERSION + 1;
+
+        assert!(StateReader::new(&bytes).is_err());
+        assert!(StateReader::new(b"nope").is_err());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut w = StateWriter::new();
+        w.u8(0x01);
+        let bytes = w.finish();
+
+        let mut r = StateReader::new(&bytes).unwrap();
+        assert!(r.u16().is_err());
+    }
+}
@@ -46,9 +46,58 @@ impl Tile {
         }
     }
 
+    /* A copy of the tile mirrored horizontally and/or vertically, used
+     * for sprites with the flip flags set.
+     */
+    pub fn flipped(&self, x_flip: bool, y_flip: bool) -> Tile {
+        let mut tile = *self;
+
+        if x_flip {
+            for row in tile.data.iter_mut() {
+                row.reverse();
+            }
+        }
+
+        if y_flip {
+            tile.data.reverse();
+        }
+
+        tile
+    }
+
     pub fn zero() -> Tile {
         Tile {
             data: [[Pixel::P0;8];8],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flipped() {
+        /* A single P3 in the top left and a P1 just right of it */
+        let mut tile = Tile::zero();
+        tile.data[0][0] = Pixel::P3;
+        tile.data[0][1] = Pixel::P1;
+
+        let x = tile.flipped(true, false);
+        assert_eq!(x.data[0][7], Pixel::P3);
+        assert_eq!(x.data[0][6], Pixel::P1);
+        assert_eq!(x.data[0][0], Pixel::P0);
+
+        let y = tile.flipped(false, true);
+        assert_eq!(y.data[7][0], Pixel::P3);
+        assert_eq!(y.data[7][1], Pixel::P1);
+        assert_eq!(y.data[0][0], Pixel::P0);
+
+        let xy = tile.flipped(true, true);
+        assert_eq!(xy.data[7][7], Pixel::P3);
+        assert_eq!(xy.data[7][6], Pixel::P1);
+        assert_eq!(xy.data[0][0], Pixel::P0);
+
+        assert_eq!(tile.flipped(false, false).data, tile.data);
+    }
+}
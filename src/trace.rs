@@ -0,0 +1,102 @@
+use crate::cpu::CPU;
+use crate::register::{Registers8, Registers16};
+
+/* Instruction trace hash
+ *
+ * A rolling FNV-1a hash over every executed (pc, opcode) pair. Comparing the
+ * final digest against the same hash computed by a reference emulator tells
+ * us whether the two executed exactly the same instruction stream without
+ * having to store and diff full logs.
+ *
+ * For each instruction the bytes fed into the hash are the pc (low byte
+ * first) followed by the opcode as it appears in memory, so CB prefixed
+ * instructions contribute 0xCB and then their second byte.
+ */
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+pub struct TraceHash {
+    digest: u64,
+}
+
+impl TraceHash {
+    pub fn new() -> TraceHash {
+        TraceHash {
+            digest: FNV_OFFSET,
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, opcode: u16) {
+        self.feed(pc as u8);
+        self.feed((pc >> 8) as u8);
+
+        if opcode & 0x0100 != 0 {
+            self.feed(0xCB);
+        }
+        self.feed(opcode as u8);
+    }
+
+    pub fn digest(&self) -> u64 {
+        self.digest
+    }
+
+    fn feed(&mut self, byte: u8) {
+        self.digest ^= byte as u64;
+        self.digest = self.digest.wrapping_mul(FNV_PRIME);
+    }
+}
+
+/* Gameboy Doctor trace lines
+ *
+ *   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+ *
+ * The registers before an instruction runs followed by the four bytes at
+ * the pc, in exactly the layout of the reference logs so they can be
+ * diffed line for line.
+ */
+pub fn format_state(cpu: &CPU) -> String {
+    let r = &cpu.registers;
+    let pc = r.get16(Registers16::PC);
+
+    format!(
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        r.get8(Registers8::A),
+        r.get8(Registers8::F),
+        r.get8(Registers8::B),
+        r.get8(Registers8::C),
+        r.get8(Registers8::D),
+        r.get8(Registers8::E),
+        r.get8(Registers8::H),
+        r.get8(Registers8::L),
+        r.get16(Registers16::SP),
+        pc,
+        cpu.mmu.peek(pc),
+        cpu.mmu.peek(pc.wrapping_add(1)),
+        cpu.mmu.peek(pc.wrapping_add(2)),
+        cpu.mmu.peek(pc.wrapping_add(3)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{Cartridge, Header};
+    use crate::mmu::MMU;
+    use crate::register::Registers;
+    use crate::rom::BootRomVariant;
+
+    #[test]
+    fn test_format_state() {
+        let mut bytes = vec![0; 0x8000];
+        bytes[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+
+        let cartridge = Cartridge::new(bytes, Header::zero());
+        let cpu = CPU::new(Registers::skip_boot(BootRomVariant::DMG), MMU::skip_boot(cartridge, BootRomVariant::DMG));
+
+        assert_eq!(
+            format_state(&cpu),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+
+use crate::msg::DebugRegister;
+use crate::register::{Registers, Registers8, Registers16};
+
+/* A register holding a value, checked after each instruction */
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum RegisterCondition {
+    R8(Registers8, u8),
+    R16(Registers16, u16),
+}
+
+impl RegisterCondition {
+    pub fn new(register: DebugRegister, value: u16) -> RegisterCondition {
+        use RegisterCondition::{R8, R16};
+
+        match register {
+            DebugRegister::A => R8(Registers8::A, value as u8),
+            DebugRegister::B => R8(Registers8::B, value as u8),
+            DebugRegister::C => R8(Registers8::C, value as u8),
+            DebugRegister::D => R8(Registers8::D, value as u8),
+            DebugRegister::E => R8(Registers8::E, value as u8),
+            DebugRegister::F => R8(Registers8::F, value as u8),
+            DebugRegister::H => R8(Registers8::H, value as u8),
+            DebugRegister::L => R8(Registers8::L, value as u8),
+            DebugRegister::AF => R16(Registers16::AF, value),
+            DebugRegister::BC => R16(Registers16::BC, value),
+            DebugRegister::DE => R16(Registers16::DE, value),
+            DebugRegister::HL => R16(Registers16::HL, value),
+            DebugRegister::PC => R16(Registers16::PC, value),
+            DebugRegister::SP => R16(Registers16::SP, value),
+        }
+    }
+
+    pub fn matches(&self, registers: &Registers) -> bool {
+        match *self {
+            RegisterCondition::R8(r, value) => registers.get8(r) == value,
+            RegisterCondition::R16(r, value) => registers.get16(r) == value,
+        }
+    }
+}
+
+/* Memory watch points
+ *
+ * MMU::get is called through a shared reference from all over the emulator
+ * so rather than threading a mutable watcher through every read we record
+ * the last matching address in a Cell. The CPU drains it after each
+ * instruction and drops into the debug state.
+ *
+ * A watch point only triggers again once the value read changes, so a rom
+ * polling an address doesn't stop the cpu on every pass. Watching the
+ * address again clears that.
+ */
+pub struct MemoryWatcher {
+    reads: HashSet<u16>,
+    hit: Cell<Option<u16>>,
+
+    /* The value each watched address last triggered on */
+    triggered: RefCell<HashMap<u16, u8>>,
+
+    /* Program counter addresses the cpu stops at before executing */
+    break_points: HashSet<u16>,
+
+    /* Register values the cpu stops on, along with whether each held at
+     * the last check. They only trigger when they start to hold, so
+     * continuing doesn't stop again straight away.
+     */
+    register_breaks: Vec<(RegisterCondition, bool)>,
+}
+
+impl MemoryWatcher {
+    pub fn new() -> MemoryWatcher {
+        MemoryWatcher {
+            reads: HashSet::new(),
+            hit: Cell::new(None),
+            triggered: RefCell::new(HashMap::new()),
+            break_points: HashSet::new(),
+            register_breaks: Vec::new(),
+        }
+    }
+
+    /* True when there's anything to watch for */
+    pub fn is_enabled(&self) -> bool {
+        !self.reads.is_empty() || !self.break_points.is_empty() || !self.register_breaks.is_empty()
+    }
+
+    pub fn watch_read(&mut self, address: u16) {
+        self.reads.insert(address);
+        self.triggered.get_mut().remove(&address);
+    }
+
+    pub fn check_read(&self, address: u16, value: u8) {
+        if !self.reads.contains(&address) {
+            return
+        }
+
+        if self.triggered.borrow_mut().insert(address, value) != Some(value) {
+            self.hit.set(Some(address));
+        }
+    }
+
+    pub fn take_hit(&self) -> Option<u16> {
+        self.hit.take()
+    }
+
+    pub fn set_break_point(&mut self, pc: u16) {
+        self.break_points.insert(pc);
+    }
+
+    pub fn clear_break_point(&mut self, pc: u16) {
+        self.break_points.remove(&pc);
+    }
+
+    pub fn is_break_point(&self, pc: u16) -> bool {
+        !self.break_points.is_empty() && self.break_points.contains(&pc)
+    }
+
+    pub fn set_register_break(&mut self, condition: RegisterCondition) {
+        if !self.register_breaks.iter().any(|(c, _)| *c == condition) {
+            self.register_breaks.push((condition, false));
+        }
+    }
+
+    pub fn clear_register_break(&mut self, condition: RegisterCondition) {
+        self.register_breaks.retain(|(c, _)| *c != condition);
+    }
+
+    /* Every condition that's come to hold since the last check */
+    pub fn check_registers(&mut self, registers: &Registers) -> Vec<RegisterCondition> {
+        let mut hits = Vec::new();
+
+        for (condition, held) in self.register_breaks.iter_mut() {
+            let holds = condition.matches(registers);
+            if holds && !*held {
+                hits.push(*condition);
+            }
+            *held = holds;
+        }
+
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_reads_trigger_once() {
+        let mut watcher = MemoryWatcher::new();
+        assert!(!watcher.is_enabled());
+
+        watcher.watch_read(0xC100);
+        assert!(watcher.is_enabled());
+
+        watcher.check_read(0xC100, 0x12);
+        assert_eq!(watcher.take_hit(), Some(0xC100));
+
+        watcher.check_read(0xC100, 0x12);
+        assert_eq!(watcher.take_hit(), None);
+
+        /* A new value triggers again */
+        watcher.check_read(0xC100, 0x34);
+        assert_eq!(watcher.take_hit(), Some(0xC100));
+
+        watcher.check_read(0xC200, 0x34);
+        assert_eq!(watcher.take_hit(), None);
+    }
+
+    #[test]
+    fn test_watch_read_clears_trigger() {
+        let mut watcher = MemoryWatcher::new();
+        watcher.watch_read(0xC100);
+
+        watcher.check_read(0xC100, 0x12);
+        watcher.take_hit();
+
+        watcher.watch_read(0xC100);
+        watcher.check_read(0xC100, 0x12);
+        assert_eq!(watcher.take_hit(), Some(0xC100));
+    }
+
+    #[test]
+    fn test_register_condition_widths() {
+        assert_eq!(RegisterCondition::new(DebugRegister::A, 0x3C), RegisterCondition::R8(Registers8::A, 0x3C));
+        assert_eq!(RegisterCondition::new(DebugRegister::L, 0x1234), RegisterCondition::R8(Registers8::L, 0x34));
+        assert_eq!(RegisterCondition::new(DebugRegister::HL, 0x9800), RegisterCondition::R16(Registers16::HL, 0x9800));
+        assert_eq!(RegisterCondition::new(DebugRegister::SP, 0xFFFE), RegisterCondition::R16(Registers16::SP, 0xFFFE));
+    }
+
+    #[test]
+    fn test_register_break_triggers_on_change() {
+        let mut watcher = MemoryWatcher::new();
+        let mut registers = Registers::new();
+
+        watcher.set_register_break(RegisterCondition::new(DebugRegister::A, 0x3C));
+        watcher.set_register_break(RegisterCondition::new(DebugRegister::HL, 0x9800));
+        assert!(watcher.is_enabled());
+        assert_eq!(watcher.check_registers(&registers), vec![]);
+
+        registers.set8(Registers8::A, 0x3C);
+        assert_eq!(watcher.check_registers(&registers), vec![RegisterCondition::R8(Registers8::A, 0x3C)]);
+        assert_eq!(watcher.check_registers(&registers), vec![]);
+
+        /* H and L make up HL */
+        registers.set8(Registers8::H, 0x98);
+        assert_eq!(watcher.check_registers(&registers), vec![RegisterCondition::R16(Registers16::HL, 0x9800)]);
+
+        /* Holding again after it stopped holding triggers again */
+        registers.set8(Registers8::A, 0x00);
+        assert_eq!(watcher.check_registers(&registers), vec![]);
+        registers.set8(Registers8::A, 0x3C);
+        assert_eq!(watcher.check_registers(&registers), vec![RegisterCondition::R8(Registers8::A, 0x3C)]);
+    }
+
+    #[test]
+    fn test_register_breaks_hit_together() {
+        let mut watcher = MemoryWatcher::new();
+        let mut registers = Registers::new();
+        let a = RegisterCondition::new(DebugRegister::A, 0x3C);
+        let b = RegisterCondition::new(DebugRegister::B, 0x12);
+
+        watcher.set_register_break(a);
+        watcher.set_register_break(b);
+        registers.set8(Registers8::A, 0x3C);
+        registers.set8(Registers8::B, 0x12);
+
+        /* Neither is lost when both start holding on the same instruction */
+        assert_eq!(watcher.check_registers(&registers), vec![a, b]);
+
+        watcher.clear_register_break(a);
+        registers.set8(Registers8::A, 0x00);
+        registers.set8(Registers8::B, 0x00);
+        watcher.check_registers(&registers);
+        registers.set8(Registers8::A, 0x3C);
+        assert_eq!(watcher.check_registers(&registers), vec![]);
+
+        watcher.clear_register_break(b);
+        assert!(!watcher.is_enabled());
+    }
+
+    #[test]
+    fn test_break_points_enable() {
+        let mut watcher = MemoryWatcher::new();
+
+        watcher.set_break_point(0x0150);
+        assert!(watcher.is_enabled());
+        assert!(watcher.is_break_point(0x0150));
+
+        watcher.clear_break_point(0x0150);
+        assert!(!watcher.is_enabled());
+    }
+}
@@ -0,0 +1,80 @@
+use gameboy_emulator_v2::Gameboy;
+use gameboy_emulator_v2::cartridge::{Cartridge, Header};
+use gameboy_emulator_v2::rom::BootRom;
+use gameboy_emulator_v2::shade::Shade;
+use gameboy_emulator_v2::msg::{Input, DebugCommand};
+
+use std::sync::mpsc::{channel, sync_channel};
+
+/* LD A, 0x42; LD (0xC000), A; JR -2 */
+fn test_gameboy() -> Gameboy {
+    let mut bytes = vec![0; 0x8000];
+    bytes[0x100..0x107].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0x18, 0xFE]);
+    let cartridge = Cartridge::new(bytes, Header::zero());
+
+    Gameboy::from_roms(BootRom::zero(), cartridge, true)
+}
+
+#[test]
+fn test_step_instruction() {
+    let mut gameboy = test_gameboy();
+
+    gameboy.step_instruction();
+    assert_eq!(gameboy.peek(0xC000), 0x00);
+
+    gameboy.step_instruction();
+    assert_eq!(gameboy.peek(0xC000), 0x42);
+}
+
+#[test]
+fn test_step_instruction_cycles() {
+    let mut gameboy = test_gameboy();
+    let start = gameboy.total_cycles();
+
+    /* LD A, n8 is 8 cycles, LD (n16), A is 16 and a taken JR is 12 */
+    assert_eq!(gameboy.step_instruction(), 8);
+    assert_eq!(gameboy.step_instruction(), 16);
+    assert_eq!(gameboy.step_instruction(), 12);
+    assert_eq!(gameboy.step_instruction(), 12);
+
+    assert_eq!(gameboy.total_cycles() - start, 48);
+}
+
+#[test]
+fn test_step_frame() {
+    let mut gameboy = test_gameboy();
+
+    gameboy.step_frame();
+    assert_eq!(gameboy.frames(), 1);
+
+    /* An empty tile set with the post boot palette draws all white */
+    let buffer = gameboy.frame_buffer();
+    assert_eq!(buffer.len(), 144);
+    assert!(buffer.iter().all(|row| row.iter().all(|shade| *shade == Shade::White)));
+}
+
+#[test]
+fn test_pause_stops_execution() {
+    let mut gameboy = test_gameboy();
+    let (input_sender, input_receiver) = channel();
+    let (frame_sender, frames) = sync_channel(16);
+    gameboy.set_input(input_receiver);
+    gameboy.set_frame_output(frame_sender);
+
+    input_sender.send(Input::Debug(DebugCommand::Pause)).unwrap();
+    for _ in 0..3 {
+        assert!(gameboy.next_frame());
+    }
+    assert_eq!(gameboy.cpu.instruction_count(), 0);
+
+    /* The display still gets frames while paused */
+    assert_eq!(frames.try_iter().count(), 3);
+
+    input_sender.send(Input::Debug(DebugCommand::Step)).unwrap();
+    assert!(gameboy.next_frame());
+    assert_eq!(gameboy.cpu.instruction_count(), 1);
+
+    input_sender.send(Input::Debug(DebugCommand::Continue)).unwrap();
+    assert!(gameboy.next_frame());
+    assert!(gameboy.cpu.instruction_count() > 1);
+}
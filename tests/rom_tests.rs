@@ -0,0 +1,137 @@
+/* Test roms run headless
+ *
+ * The roms aren't distributed with the emulator, put them under tests/roms
+ * to run these, laid out the way the test suites ship
+ *
+ *   tests/roms/cpu_instrs/individual/01-special.gb
+ *   tests/roms/instr_timing/instr_timing.gb
+ *   tests/roms/mooneye/acceptance/ei_sequence.gb
+ *
+ * A missing rom skips its test rather than failing it.
+ */
+use gameboy_emulator_v2::Gameboy;
+use gameboy_emulator_v2::cartridge::Cartridge;
+use gameboy_emulator_v2::rom::BootRom;
+
+use std::path::PathBuf;
+
+/* The DMG cpu clock */
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+
+fn load(rom: &str) -> Option<Gameboy> {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "roms", rom].iter().collect();
+
+    if !path.exists() {
+        println!("skipping, {} not found", path.display());
+        return None
+    }
+
+    let cartridge = Cartridge::read(path.to_str().unwrap()).unwrap();
+    Some(Gameboy::from_roms(BootRom::zero(), cartridge, true))
+}
+
+/* Blargg's roms print their name then "Passed" or "Failed" over serial */
+fn blargg(rom: &str, seconds: u64) {
+    let mut gameboy = match load(rom) {
+        Some(gameboy) => gameboy,
+        None => return,
+    };
+
+    let mut output = String::new();
+    for _ in 0..seconds {
+        output.push_str(&gameboy.run_headless(CYCLES_PER_SECOND));
+
+        if output.contains("Passed") || output.contains("Failed") {
+            break
+        }
+    }
+
+    assert!(output.contains("Passed"), "{} did not pass:\n{}", rom, output);
+}
+
+/* Mooneye's roms stop on LD B, B with the result in the registers */
+fn mooneye(rom: &str, seconds: u64) {
+    let mut gameboy = match load(rom) {
+        Some(gameboy) => gameboy,
+        None => return,
+    };
+
+    gameboy.run_headless(seconds * CYCLES_PER_SECOND);
+    assert_eq!(gameboy.mooneye_result(), Some(true), "{} did not pass", rom);
+}
+
+#[test]
+fn test_cpu_instrs_special() {
+    blargg("cpu_instrs/individual/01-special.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_interrupts() {
+    blargg("cpu_instrs/individual/02-interrupts.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_op_sp_hl() {
+    blargg("cpu_instrs/individual/03-op sp,hl.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_op_r_imm() {
+    blargg("cpu_instrs/individual/04-op r,imm.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_op_rp() {
+    blargg("cpu_instrs/individual/05-op rp.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_ld_r_r() {
+    blargg("cpu_instrs/individual/06-ld r,r.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_jr_jp_call_ret_rst() {
+    blargg("cpu_instrs/individual/07-jr,jp,call,ret,rst.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_misc_instrs() {
+    blargg("cpu_instrs/individual/08-misc instrs.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_op_r_r() {
+    blargg("cpu_instrs/individual/09-op r,r.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_bit_ops() {
+    blargg("cpu_instrs/individual/10-bit ops.gb", 30);
+}
+
+#[test]
+fn test_cpu_instrs_op_a_hl() {
+    blargg("cpu_instrs/individual/11-op a,(hl).gb", 30);
+}
+
+/* Checks the cycle counts of every instruction against the timer */
+#[test]
+fn test_instr_timing() {
+    blargg("instr_timing/instr_timing.gb", 60);
+}
+
+#[test]
+fn test_mooneye_ei_sequence() {
+    mooneye("mooneye/acceptance/ei_sequence.gb", 10);
+}
+
+#[test]
+fn test_mooneye_rapid_di_ei() {
+    mooneye("mooneye/acceptance/rapid_di_ei.gb", 10);
+}
+
+#[test]
+fn test_mooneye_div_write() {
+    mooneye("mooneye/acceptance/timer/div_write.gb", 10);
+}